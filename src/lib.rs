@@ -0,0 +1,5 @@
+// The graduation server, as a library, so that the integration tests under `tests/` can drive
+// the same routes that `main` serves. The lessons are exercises rather than an API, and stay in
+// the binary.
+
+pub mod server;
@@ -0,0 +1,224 @@
+// GRADUATION PROJECT -- SERVER
+//
+// The graduation server is a small REST API built with Warp. Each resource lives in its own
+// module under `server/`, exposing a function that returns the composed filter for its routes.
+// The functions in this module stitch those filters together into the complete API.
+//
+// What it serves:
+// - `/hello/{name}`, the project's hello world, and a calculator under `/divide` and `/calc`;
+// - detectives under `/detectives` and users under `/users`, to create, read, update and delete,
+//   or to change many at once under `/batch`;
+// - live changes to both as server-sent events from `/events`;
+// - background jobs under `/jobs`, which are queued, retried when they fail, and reported on;
+// - a WebSocket chat room under `/chat/{room}`, and the course itself under `/course`;
+// - an OpenAPI document at `/openapi.json`, browsable at `/docs`;
+// - `/healthz`, `/readyz` and Prometheus `/metrics` for monitoring.
+//
+// How it serves it:
+// - lists are filtered, sorted and paged with query parameters, and sent as JSON, CSV or a text
+//   table depending on `Accept` (see `page` and `negotiate`);
+// - every resource carries an `ETag` for conditional requests (see `conditional`), and a `POST`
+//   with an `Idempotency-Key` can be retried safely (see `idempotency`);
+// - once credentials are configured, each route needs a role (see `auth`);
+// - clients are rate limited (see `rate_limit`), bodies and request times are capped (see
+//   `limits`), and responses are compressed (see `compression`);
+// - every request is logged with a request ID (see `request_log`);
+// - detectives and users can be kept in a data directory, logged before they change (see `wal`);
+// - HTTPS is served given a certificate (see `tls`), and shutdown is graceful (see `shutdown`).
+//
+// Settings come from flags, the environment or a config file (see `config`).
+
+pub mod auth;
+pub mod batch;
+pub mod calc;
+pub mod chat;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod course;
+pub mod detectives;
+pub mod error;
+pub mod events;
+pub mod health;
+pub mod idempotency;
+pub mod jobs;
+pub mod json;
+pub mod limits;
+pub mod metrics;
+pub mod negotiate;
+pub mod openapi;
+pub mod page;
+pub mod rate_limit;
+pub mod request_log;
+pub mod shutdown;
+pub mod tls;
+pub mod users;
+pub mod wal;
+
+use std::convert::Infallible;
+
+use serde::Serialize;
+use serde_json::json;
+use warp::{Filter, Reply};
+
+#[derive(Debug, Serialize)]
+struct Greeting {
+    message: String,
+}
+
+/// The templates of every route, used to label metrics. Keep in step with `routes`.
+pub const ROUTES: &[&str] = &[
+    "/hello/{name}",
+    "/healthz",
+    "/readyz",
+    "/metrics",
+    "/detectives",
+    "/detectives/{id}",
+    "/users",
+    "/users/{id}",
+    "/divide/{numerator}/{denominator}",
+    "/calc",
+    "/chat/{room}",
+    "/events",
+    "/openapi.json",
+    "/docs",
+    "/course",
+    "/course/{lesson}",
+    "/jobs",
+    "/jobs/{id}",
+    "/batch",
+];
+
+/// Everything the routes share, each piece handed to the modules that need it.
+#[derive(Clone)]
+pub struct State {
+    pub detectives: detectives::Store,
+    pub users: users::SharedRepo,
+    pub readiness: health::Readiness,
+    pub metrics: metrics::Shared,
+    pub chat: chat::Shared,
+    pub events: events::Shared,
+    pub limiter: rate_limit::Shared,
+    pub limits: limits::Shared,
+    pub auth: auth::Shared,
+    pub jobs: jobs::Shared,
+    pub idempotency: idempotency::Shared,
+}
+
+/// Builds every route served by the graduation server.
+pub fn routes(state: State) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    let State {
+        detectives,
+        users,
+        readiness,
+        metrics,
+        chat,
+        events,
+        limiter,
+        limits,
+        auth,
+        jobs,
+        idempotency,
+    } = state;
+
+    // GET /hello/warp => 200 OK with body { "message": "Hello, warp!" }
+    let hello = warp::path!("hello" / String).map(|name| {
+        warp::reply::json(&Greeting {
+            message: format!("Hello, {}!", name),
+        })
+    });
+
+    let api = hello
+        .or(detectives::routes(
+            detectives.clone(),
+            events.clone(),
+            auth.clone(),
+            idempotency.clone(),
+        ))
+        .or(users::routes(
+            users.clone(),
+            events.clone(),
+            auth.clone(),
+            idempotency.clone(),
+        ))
+        .or(batch::routes(
+            detectives,
+            users,
+            events.clone(),
+            auth.clone(),
+            idempotency.clone(),
+        ))
+        .or(calc::routes())
+        .or(events::routes(events, auth.clone()))
+        .or(jobs::routes(jobs, auth.clone(), idempotency))
+        .or(openapi::routes(&openapi()))
+        .or(course::routes())
+        // Boxing hides the filter's type, which grows with every route. Without it, every crate
+        // that serves the routes compiles the whole tree again, which takes gigabytes of memory.
+        .map(Reply::into_response)
+        .boxed();
+
+    // The chat's WebSockets outlive any deadline, and cannot be passed through one anyway.
+    let api = chat::routes(chat, auth.clone())
+        .map(Reply::into_response)
+        .or(limits::deadline(limits.clone(), api))
+        .unify();
+
+    // Probes and metrics are left out of rate limiting, so monitoring never gets locked out.
+    let monitoring = health::routes(readiness).or(metrics::routes(metrics.clone()));
+
+    let api = rate_limit::limit(limiter, auth)
+        .and(limits::body_size(limits.max_body_bytes))
+        .and(api);
+
+    compression::compressed(request_log::logged(monitoring.or(api), metrics))
+}
+
+/// Describes every route served by the graduation server.
+pub fn openapi() -> openapi::Document {
+    let mut document = openapi::Document::new();
+
+    document.schema(
+        "Greeting",
+        json!({ "type": "object", "properties": { "message": { "type": "string" } } }),
+    );
+    document.operation(
+        "get",
+        "/hello/{name}",
+        openapi::Operation::new("hello", "Say hello")
+            .path("name", json!({ "type": "string" }), "Who to greet")
+            .json(200, "A greeting", "Greeting"),
+    );
+
+    health::openapi(&mut document);
+    metrics::openapi(&mut document);
+    detectives::openapi(&mut document);
+    users::openapi(&mut document);
+    batch::openapi(&mut document);
+    calc::openapi(&mut document);
+    chat::openapi(&mut document);
+    events::openapi(&mut document);
+    openapi::openapi(&mut document);
+    course::openapi(&mut document);
+    jobs::openapi(&mut document);
+
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_route_is_described() {
+        let document = openapi();
+        let described: Vec<&str> = document.paths().collect();
+
+        for route in ROUTES {
+            assert!(described.contains(route), "{} is not described", route);
+        }
+        for path in described {
+            assert!(ROUTES.contains(&path), "{} is not a route", path);
+        }
+    }
+}
@@ -0,0 +1,962 @@
+// BATCHES
+//
+// `POST /batch` makes many changes to detectives and users in a single request, for import
+// scripts that would otherwise make thousands of calls. Each operation is checked just as the
+// route it stands in for would check it, needs the same role (`Editor` to create and update,
+// `Admin` to delete), and gets its own result: the status code and body that route would have
+// answered with, in the same position as the operation.
+//
+// A best-effort batch makes each operation on its own, just as its route would, carrying on past
+// the ones that fail. An atomic batch makes all of them or none. The users are locked for the
+// whole batch by `UserRepo::transaction`, and the detective registry's write lock is taken
+// inside it; every operation is then tried on copies of both. Only once they all succeed is
+// each store's half of the batch logged, as a single durable record, and the copies swapped in.
+// The detectives' half is logged first and swapped in last, so that if the users' half cannot be
+// stored, it is taken back out of the log (see `Registry::stage`). A failed write therefore leaves
+// neither store with part of the batch.
+//
+// A crash can, though. The two halves are two records in two logs, with nothing tying them
+// together, so if the server crashes after the detectives' half is written and before the users'
+// half is, the restarted server replays the detectives' half on its own.
+
+use std::convert::Infallible;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::{self, Role};
+use super::conditional::Tags;
+use super::detectives::{self, Detective, Registry};
+use super::error::AppError;
+use super::events::{self, Change, EventBus};
+use super::idempotency::{self, Attempt};
+use super::json::{FieldError, Validate, Validator};
+use super::openapi::{schema, Document, Operation};
+use super::users::{self, RepoError, User};
+
+/// The most operations a single batch may hold.
+const MAX_OPERATIONS: usize = 1000;
+
+/// The largest batch accepted, which leaves room for `MAX_OPERATIONS` operations of a typical
+/// size. Other routes accept far less (see `json::MAX_BODY_BYTES`).
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// The body accepted by `POST /batch`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Batch {
+    /// Whether to make all of the operations or none of them, rather than as many as possible.
+    #[serde(default)]
+    pub atomic: bool,
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resource {
+    Detectives,
+    Users,
+}
+
+/// One operation in a batch, standing in for a `POST`, `PUT` or `DELETE` request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchOperation {
+    pub op: Action,
+    pub resource: Resource,
+    /// The resource to update or delete. Users to create carry their identifier in the body.
+    pub id: Option<u64>,
+    /// What the route would take as its body: a `Person` for detectives, and a `User` to create
+    /// or `UserChanges` to update for users.
+    pub body: Option<Value>,
+    /// What the route would take as its `If-Match` header, such as `"\"2\""`.
+    pub if_match: Option<String>,
+}
+
+impl Validate for Batch {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut validator = Validator::default();
+        validator
+            .check(
+                !self.operations.is_empty(),
+                "operations",
+                "must not be empty",
+            )
+            .check(
+                self.operations.len() <= MAX_OPERATIONS,
+                "operations",
+                &format!("must hold at most {} operations", MAX_OPERATIONS),
+            );
+
+        for (index, operation) in self.operations.iter().enumerate() {
+            let at = format!("operations[{}]", index);
+            let needs_id = operation.op != Action::Create;
+            let needs_body = operation.op != Action::Delete;
+            let most = match operation.resource {
+                Resource::Detectives => u64::MAX,
+                Resource::Users => i32::MAX as u64,
+            };
+
+            validator
+                .check(
+                    operation.id.is_some() == needs_id,
+                    &format!("{}.id", at),
+                    if needs_id {
+                        "is required"
+                    } else {
+                        "must not be given when creating"
+                    },
+                )
+                .check(
+                    operation.id.is_none_or(|id| (1..=most).contains(&id)),
+                    &format!("{}.id", at),
+                    &format!("must be between 1 and {}", most),
+                )
+                .check(
+                    operation.body.is_some() == needs_body,
+                    &format!("{}.body", at),
+                    if needs_body {
+                        "is required"
+                    } else {
+                        "must not be given when deleting"
+                    },
+                );
+        }
+        validator.finish()
+    }
+}
+
+/// An operation that has been decoded and is ready to be made.
+#[derive(Debug, Clone)]
+enum Edit {
+    Detective(detectives::Edit),
+    User(users::Edit),
+}
+
+/// What an operation did.
+#[derive(Debug, Clone)]
+enum Made {
+    Detective(Change, Detective),
+    User(Change, User),
+}
+
+/// The result of one operation: the status code and body its route would have answered with.
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub status: u16,
+    /// The resource that was created or updated, or a problem if the operation failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+/// The body answered by `POST /batch`.
+#[derive(Debug, Serialize)]
+pub struct Results {
+    /// One outcome for each operation, in the same order.
+    pub results: Vec<Outcome>,
+}
+
+impl BatchOperation {
+    /// The role needed to make the operation, which is the role its route needs.
+    fn role(&self) -> Role {
+        match self.op {
+            Action::Create | Action::Update => Role::Editor,
+            Action::Delete => Role::Admin,
+        }
+    }
+
+    /// Decodes operation `index` for a client with the `granted` role. The batch has already
+    /// been validated, so the identifier and body are there when they are needed.
+    fn plan(self, index: usize, granted: Role) -> Result<Edit, AppError> {
+        auth::permit(granted, self.role())?;
+
+        let id = self.id.unwrap_or_default();
+        let body = self.body.unwrap_or_default();
+        let if_match = self.if_match.as_deref().map(Tags::parse);
+
+        Ok(match (self.resource, self.op) {
+            (Resource::Detectives, Action::Create) => {
+                Edit::Detective(detectives::Edit::Register(decode(index, body)?))
+            }
+            (Resource::Detectives, Action::Update) => Edit::Detective(detectives::Edit::Replace {
+                id,
+                person: decode(index, body)?,
+                if_match,
+            }),
+            (Resource::Detectives, Action::Delete) => {
+                Edit::Detective(detectives::Edit::Remove { id, if_match })
+            }
+            (Resource::Users, Action::Create) => {
+                Edit::User(users::Edit::Insert(decode(index, body)?))
+            }
+            (Resource::Users, Action::Update) => Edit::User(users::Edit::Update {
+                id: id as i32,
+                changes: decode(index, body)?,
+                if_match,
+            }),
+            (Resource::Users, Action::Delete) => Edit::User(users::Edit::Delete {
+                id: id as i32,
+                if_match,
+            }),
+        })
+    }
+}
+
+/// Decodes and validates the body of operation `index`, naming its fields by their place in
+/// the batch.
+fn decode<T: DeserializeOwned + Validate>(index: usize, body: Value) -> Result<T, AppError> {
+    let at = format!("operations[{}].body", index);
+
+    let value: T = serde_json::from_value(body).map_err(|e| AppError::Validation {
+        detail: format!("{} is malformed: {}", at, e),
+        fields: Vec::new(),
+    })?;
+    value.validate().map_err(|fields| AppError::Validation {
+        detail: format!("{} is invalid", at),
+        fields: fields
+            .into_iter()
+            .map(|field| FieldError {
+                field: format!("{}.{}", at, field.field),
+                message: field.message,
+            })
+            .collect(),
+    })?;
+
+    Ok(value)
+}
+
+impl Edit {
+    fn change(&self) -> Change {
+        match self {
+            Edit::Detective(detectives::Edit::Register(_)) | Edit::User(users::Edit::Insert(_)) => {
+                Change::Created
+            }
+            Edit::Detective(detectives::Edit::Replace { .. })
+            | Edit::User(users::Edit::Update { .. }) => Change::Updated,
+            Edit::Detective(detectives::Edit::Remove { .. })
+            | Edit::User(users::Edit::Delete { .. }) => Change::Deleted,
+        }
+    }
+
+    /// Makes the edit on its own, straight to whichever store it is for.
+    async fn make_alone(
+        self,
+        store: &detectives::Store,
+        repo: &users::SharedRepo,
+        events: &EventBus,
+    ) -> Result<Made, AppError> {
+        let change = self.change();
+
+        match self {
+            Edit::Detective(edit) => {
+                let mut registry = store.write().unwrap();
+                let made = Made::Detective(change, detectives::edit(&mut registry, edit)?);
+                made.publish(events);
+                Ok(made)
+            }
+            Edit::User(edit) => {
+                let publish =
+                    Box::new(|user: &User| Made::User(change, user.clone()).publish(events));
+                Ok(Made::User(change, users::apply(repo, edit, publish).await?))
+            }
+        }
+    }
+
+    /// Makes the edit to whichever of `registry` and `users` it is for.
+    fn make(self, registry: &mut Registry, users: &mut users::Users) -> Result<Made, AppError> {
+        let change = self.change();
+
+        match self {
+            Edit::Detective(edit) => {
+                detectives::edit(registry, edit).map(|detective| Made::Detective(change, detective))
+            }
+            Edit::User(edit) => users::edit(users, edit).map(|user| Made::User(change, user)),
+        }
+    }
+}
+
+impl Made {
+    fn publish(&self, events: &EventBus) {
+        match self {
+            Made::Detective(Change::Deleted, detective) => events.publish(
+                detectives::RESOURCE,
+                Change::Deleted,
+                &json!({ "id": detective.id }),
+            ),
+            Made::Detective(change, detective) => {
+                events.publish(detectives::RESOURCE, *change, detective)
+            }
+            Made::User(Change::Deleted, user) => {
+                events.publish(users::RESOURCE, Change::Deleted, &json!({ "id": user.id }))
+            }
+            Made::User(change, user) => events.publish(users::RESOURCE, *change, user),
+        }
+    }
+}
+
+impl Outcome {
+    fn made(made: &Made) -> Self {
+        let (change, body) = match made {
+            Made::Detective(change, detective) => (change, json!(detective)),
+            Made::User(change, user) => (change, json!(user)),
+        };
+
+        match change {
+            Change::Created => Outcome {
+                status: StatusCode::CREATED.as_u16(),
+                body: Some(body),
+            },
+            Change::Updated => Outcome {
+                status: StatusCode::OK.as_u16(),
+                body: Some(body),
+            },
+            Change::Deleted => Outcome {
+                status: StatusCode::NO_CONTENT.as_u16(),
+                body: None,
+            },
+        }
+    }
+
+    fn failed(error: &AppError) -> Self {
+        if let AppError::Internal(message) = error {
+            tracing::error!("internal error in a batch: {}", message);
+        }
+
+        Outcome {
+            status: error.status().as_u16(),
+            body: serde_json::to_value(error.problem()).ok(),
+        }
+    }
+}
+
+/// `POST /batch` with a `Batch` body, the only batch route.
+pub fn routes(
+    store: detectives::Store,
+    repo: users::SharedRepo,
+    events: events::Shared,
+    auth: auth::Shared,
+    idempotency: idempotency::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("batch")
+        .and(warp::post())
+        .and(auth::authenticate(auth, Role::Editor))
+        .and(idempotency::body_up_to(idempotency, MAX_BODY_BYTES))
+        .and(with_resources(store, repo))
+        .and(events::with_bus(events))
+        .and_then(|role, batch, attempt: Attempt, store, repo, events| {
+            attempt.run(run_batch(role, batch, store, repo, events))
+        })
+}
+
+/// Describes the `/batch` route.
+pub fn openapi(document: &mut Document) {
+    document.schema(
+        "BatchOperation",
+        json!({
+            "type": "object",
+            "required": ["op", "resource"],
+            "properties": {
+                "op": { "type": "string", "enum": ["create", "update", "delete"] },
+                "resource": { "type": "string", "enum": ["detectives", "users"] },
+                "id": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "The resource to update or delete",
+                },
+                "body": {
+                    "description": "A Person for detectives; a User to create, or UserChanges \
+                                    to update, for users",
+                    "oneOf": [schema("Person"), schema("User"), schema("UserChanges")],
+                },
+                "if_match": {
+                    "type": "string",
+                    "description": "Like the If-Match header of the operation's route",
+                },
+            },
+        }),
+    );
+    document.schema(
+        "Batch",
+        json!({
+            "type": "object",
+            "required": ["operations"],
+            "properties": {
+                "atomic": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Make all of the operations or none of them. A server \
+                                    crash part way through storing the batch may keep the \
+                                    detectives' changes without the users'",
+                },
+                "operations": {
+                    "type": "array",
+                    "minItems": 1,
+                    "maxItems": MAX_OPERATIONS,
+                    "items": schema("BatchOperation"),
+                },
+            },
+        }),
+    );
+    document.schema(
+        "BatchResults",
+        json!({
+            "type": "object",
+            "properties": {
+                "results": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["status"],
+                        "properties": {
+                            "status": { "type": "integer" },
+                            "body": {
+                                "description": "What the operation's route would have answered \
+                                                with, which is a problem if it failed",
+                            },
+                        },
+                    },
+                },
+            },
+        }),
+    );
+
+    document.operation(
+        "post",
+        "/batch",
+        idempotency::parameters(Operation::new("batch", "Make many changes at once"))
+            .body("Batch")
+            .json(
+                200,
+                "What each operation did. An atomic batch that fails answers with the status of \
+                 the operation that failed instead, and every other operation gets a 424",
+                "BatchResults",
+            )
+            .role(Role::Editor),
+    );
+}
+
+fn with_resources(
+    store: detectives::Store,
+    repo: users::SharedRepo,
+) -> impl Filter<Extract = (detectives::Store, users::SharedRepo), Error = Infallible> + Clone {
+    warp::any()
+        .map(move || (store.clone(), repo.clone()))
+        .untuple_one()
+}
+
+async fn run_batch(
+    granted: Role,
+    batch: Batch,
+    store: detectives::Store,
+    repo: users::SharedRepo,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let planned: Vec<_> = batch
+        .operations
+        .into_iter()
+        .enumerate()
+        .map(|(index, operation)| operation.plan(index, granted))
+        .collect();
+
+    let (status, results) = if batch.atomic {
+        atomic(planned, &store, &repo, &events).await?
+    } else {
+        best_effort(planned, &store, &repo, &events).await?
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Results { results }),
+        status,
+    ))
+}
+
+/// Makes every operation or none of them. If one fails, the batch answers with its status.
+async fn atomic(
+    planned: Vec<Result<Edit, AppError>>,
+    store: &detectives::Store,
+    repo: &users::SharedRepo,
+    events: &EventBus,
+) -> Result<(StatusCode, Vec<Outcome>), RepoError> {
+    let count = planned.len();
+    let planned: Result<Vec<Edit>, _> = planned
+        .into_iter()
+        .enumerate()
+        .map(|(index, edit)| edit.map_err(|error| (index, error)))
+        .collect();
+    let made = match planned {
+        Ok(edits) => all_or_nothing(edits, store, repo, events).await?,
+        Err(failure) => Err(failure),
+    };
+
+    match made {
+        Ok(made) => Ok((StatusCode::OK, made.iter().map(Outcome::made).collect())),
+        Err((failed, error)) => {
+            let skipped = AppError::FailedDependency(format!(
+                "operation {} failed, so no operation in the batch was made",
+                failed
+            ));
+            let outcomes = (0..count)
+                .map(|index| Outcome::failed(if index == failed { &error } else { &skipped }))
+                .collect();
+            Ok((error.status(), outcomes))
+        }
+    }
+}
+
+/// Makes each operation on its own, whatever happens to the others.
+async fn best_effort(
+    planned: Vec<Result<Edit, AppError>>,
+    store: &detectives::Store,
+    repo: &users::SharedRepo,
+    events: &EventBus,
+) -> Result<(StatusCode, Vec<Outcome>), RepoError> {
+    let mut outcomes = Vec::with_capacity(planned.len());
+
+    for edit in planned {
+        let made = match edit {
+            Ok(edit) => edit.make_alone(store, repo, events).await,
+            Err(error) => Err(error),
+        };
+        outcomes.push(match made {
+            Ok(made) => Outcome::made(&made),
+            Err(error) => Outcome::failed(&error),
+        });
+    }
+
+    Ok((StatusCode::OK, outcomes))
+}
+
+/// Makes every edit, in order, or none of them. Returns what each edit did, or the position of
+/// the first that could not be made and why.
+async fn all_or_nothing(
+    edits: Vec<Edit>,
+    store: &detectives::Store,
+    repo: &users::SharedRepo,
+    events: &EventBus,
+) -> Result<Result<Vec<Made>, (usize, AppError)>, RepoError> {
+    let mut result = Ok(Vec::new());
+    let mut unstored = None;
+
+    repo.transaction(Box::new(|users| {
+        let mut registry = store.write().unwrap();
+
+        // The repository hands over a copy of the users, but the registry has to be copied here.
+        let mut scratch = registry.scratch();
+        let mut made = Vec::with_capacity(edits.len());
+        for (index, edit) in edits.into_iter().enumerate() {
+            match edit.make(&mut scratch, users) {
+                Ok(edit) => made.push(edit),
+                Err(error) => {
+                    result = Err((index, error));
+                    return None;
+                }
+            }
+        }
+
+        let staged = match registry.stage(scratch) {
+            Ok(staged) => staged,
+            Err(error) => {
+                unstored = Some(error);
+                return None;
+            }
+        };
+        let published = made.clone();
+        result = Ok(made);
+        Some(Box::new(move |stored| {
+            registry.finish(staged, stored);
+            if stored {
+                published.iter().for_each(|made| made.publish(events));
+            }
+        }))
+    }))
+    .await?;
+
+    match unstored {
+        Some(error) => Err(error.into()),
+        None => Ok(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::error;
+    use crate::server::idempotency::IdempotencyStore;
+    use crate::server::users::{FileUserRepo, InMemoryUserRepo, Then, Transaction, UserRepo};
+
+    struct Server {
+        store: detectives::Store,
+        repo: users::SharedRepo,
+        events: events::Shared,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            Server {
+                store: detectives::store(Registry::seeded()),
+                repo: Arc::new(InMemoryUserRepo::new(users::seed())),
+                events: EventBus::new(),
+            }
+        }
+
+        fn api(
+            &self,
+            credentials: auth::Credentials,
+        ) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+            routes(
+                self.store.clone(),
+                self.repo.clone(),
+                self.events.clone(),
+                Arc::new(credentials),
+                IdempotencyStore::start(),
+            )
+            .recover(error::recover)
+        }
+
+        async fn send(&self, batch: Value) -> (StatusCode, Value) {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/batch")
+                .json(&batch)
+                .reply(&self.api(auth::Credentials::default()))
+                .await;
+
+            (
+                response.status(),
+                serde_json::from_slice(response.body()).unwrap(),
+            )
+        }
+    }
+
+    /// A repository that cannot store anything, as if its disk were full.
+    struct Unstorable;
+
+    #[async_trait::async_trait]
+    impl UserRepo for Unstorable {
+        async fn find_by_id(&self, _: i32) -> Result<Option<User>, RepoError> {
+            Ok(None)
+        }
+
+        async fn list(&self) -> Result<Vec<User>, RepoError> {
+            Ok(Vec::new())
+        }
+
+        async fn insert(&self, _: User, _: Then<'_>) -> Result<User, RepoError> {
+            Err(full())
+        }
+
+        async fn update(
+            &self,
+            _: User,
+            _: Option<u64>,
+            _: Then<'_>,
+        ) -> Result<Option<User>, RepoError> {
+            Err(full())
+        }
+
+        async fn delete(
+            &self,
+            _: i32,
+            _: Option<u64>,
+            _: Then<'_>,
+        ) -> Result<Option<User>, RepoError> {
+            Err(full())
+        }
+
+        async fn transaction(&self, change: Transaction<'_>) -> Result<bool, RepoError> {
+            let mut users = users::Users::default();
+            if let Some(finish) = change(&mut users) {
+                finish(false);
+            }
+            Err(full())
+        }
+    }
+
+    fn full() -> RepoError {
+        RepoError::Io(std::io::Error::other("no space left on device"))
+    }
+
+    fn statuses(body: &Value) -> Vec<u64> {
+        body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["status"].as_u64().unwrap())
+            .collect()
+    }
+
+    fn moriarty() -> Value {
+        json!({
+            "name": "James Moriarty",
+            "age": 48,
+            "address": { "street": "Unknown", "city": "London" },
+        })
+    }
+
+    #[tokio::test]
+    async fn best_effort_batches_carry_on_past_failures() {
+        let server = Server::new();
+        let (_, mut events) = server.events.subscribe(None);
+
+        let (status, body) = server
+            .send(json!({
+                "operations": [
+                    { "op": "create", "resource": "detectives", "body": moriarty() },
+                    {
+                        "op": "update", "resource": "users", "id": 2,
+                        "body": { "name": "Dr. Watson" },
+                    },
+                    { "op": "delete", "resource": "users", "id": 99 },
+                    { "op": "delete", "resource": "detectives", "id": 1, "if_match": "\"1\"" },
+                ],
+            }))
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(statuses(&body), [201, 200, 404, 204]);
+        assert_eq!(body["results"][0]["body"]["id"], 3);
+        assert_eq!(body["results"][1]["body"]["version"], 2);
+        assert_eq!(body["results"][2]["body"]["type"], "/problems/not-found");
+
+        let registry = server.store.read().unwrap();
+        assert_eq!(registry.get(3).unwrap().person.name, "James Moriarty");
+        assert!(registry.get(1).is_none());
+        let watson = server.repo.find_by_id(2).await.unwrap().unwrap();
+        assert_eq!(watson.name, "Dr. Watson");
+        assert_eq!(events.recv().await.unwrap().kind, "detective.created");
+    }
+
+    #[tokio::test]
+    async fn atomic_batches_make_nothing_if_anything_fails() {
+        let server = Server::new();
+
+        let (status, body) = server
+            .send(json!({
+                "atomic": true,
+                "operations": [
+                    { "op": "create", "resource": "detectives", "body": moriarty() },
+                    {
+                        "op": "create", "resource": "users",
+                        "body": { "id": 4, "name": "Irene Adler" },
+                    },
+                    {
+                        "op": "update", "resource": "users", "id": 1,
+                        "body": { "name": "S. Holmes" }, "if_match": "\"7\"",
+                    },
+                ],
+            }))
+            .await;
+
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(statuses(&body), [424, 424, 412]);
+        assert_eq!(
+            body["results"][0]["body"]["type"],
+            "/problems/failed-dependency"
+        );
+        assert!(server.store.read().unwrap().get(3).is_none());
+        assert_eq!(server.repo.find_by_id(4).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn atomic_batches_see_their_own_changes() {
+        let server = Server::new();
+
+        let (status, body) = server
+            .send(json!({
+                "atomic": true,
+                "operations": [
+                    {
+                        "op": "create", "resource": "users",
+                        "body": { "id": 4, "name": "Irene Adler" },
+                    },
+                    {
+                        "op": "update", "resource": "users", "id": 4,
+                        "body": { "name": "Irene Norton" },
+                    },
+                    { "op": "create", "resource": "detectives", "body": moriarty() },
+                    {
+                        "op": "create", "resource": "users",
+                        "body": { "id": 4, "name": "Irene Adler" },
+                    },
+                ],
+            }))
+            .await;
+
+        // The second Irene clashes with the first, even though neither was stored yet.
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(statuses(&body), [424, 424, 424, 409]);
+
+        let (status, body) = server
+            .send(json!({
+                "atomic": true,
+                "operations": [
+                    {
+                        "op": "create", "resource": "users",
+                        "body": { "id": 4, "name": "Irene Adler" },
+                    },
+                    {
+                        "op": "update", "resource": "users", "id": 4,
+                        "body": { "name": "Irene Norton" },
+                    },
+                    { "op": "create", "resource": "detectives", "body": moriarty() },
+                ],
+            }))
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(statuses(&body), [201, 200, 201]);
+        // User versions are counted across the repository, whose seed holds version 1.
+        let irene = server.repo.find_by_id(4).await.unwrap().unwrap();
+        assert_eq!((irene.name.as_str(), irene.version), ("Irene Norton", 3));
+        assert_eq!(server.store.read().unwrap().get(3).unwrap().version, 1);
+    }
+
+    #[tokio::test]
+    async fn deleting_needs_the_admin_role() {
+        let server = Server::new();
+        let credentials = auth::Credentials {
+            tokens: BTreeMap::from([("s3cr3t".to_string(), Role::Editor)]),
+            ..Default::default()
+        };
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/batch")
+            .header("authorization", "Bearer s3cr3t")
+            .json(&json!({
+                "operations": [
+                    {
+                        "op": "update", "resource": "users", "id": 3,
+                        "body": { "name": "M. Holmes" },
+                    },
+                    { "op": "delete", "resource": "users", "id": 2 },
+                ],
+            }))
+            .reply(&server.api(credentials))
+            .await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(statuses(&body), [200, 403]);
+        assert!(server.repo.find_by_id(2).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn malformed_operations_are_refused_up_front() {
+        let server = Server::new();
+
+        let (status, body) = server
+            .send(json!({
+                "operations": [
+                    { "op": "update", "resource": "users", "body": { "name": "Nobody" } },
+                    { "op": "delete", "resource": "detectives", "id": 1, "body": {} },
+                ],
+            }))
+            .await;
+        let fields: Vec<&str> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["field"].as_str().unwrap())
+            .collect();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(fields, ["operations[0].id", "operations[1].body"]);
+
+        let (status, _) = server.send(json!({ "operations": [] })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn invalid_bodies_fail_only_their_operation() {
+        let server = Server::new();
+
+        let (_, body) = server
+            .send(json!({
+                "operations": [
+                    { "op": "create", "resource": "users", "body": { "id": 5, "name": " " } },
+                    {
+                        "op": "create", "resource": "users",
+                        "body": { "id": 6, "name": "Mrs Hudson" },
+                    },
+                ],
+            }))
+            .await;
+
+        assert_eq!(statuses(&body), [400, 201]);
+        assert_eq!(
+            body["results"][0]["body"]["errors"][0]["field"],
+            "operations[0].body.name"
+        );
+    }
+
+    #[tokio::test]
+    async fn atomic_batches_are_stored_as_one_change_to_each_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server {
+            store: detectives::store(Registry::open(dir.path()).unwrap()),
+            repo: Arc::new(FileUserRepo::open(dir.path(), users::seed()).await.unwrap()),
+            events: EventBus::new(),
+        };
+
+        let (status, _) = server
+            .send(json!({
+                "atomic": true,
+                "operations": [
+                    { "op": "create", "resource": "detectives", "body": moriarty() },
+                    { "op": "create", "resource": "users", "body": { "id": 4, "name": "Irene" } },
+                    { "op": "delete", "resource": "users", "id": 3 },
+                ],
+            }))
+            .await;
+        drop(server);
+        let log = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        let registry = Registry::open(dir.path()).unwrap();
+        let repo = FileUserRepo::open(dir.path(), Vec::new()).await.unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(log("detectives.log").lines().count(), 1);
+        assert_eq!(log("users.log").lines().count(), 1);
+        assert_eq!(registry.get(3).unwrap().person.name, "James Moriarty");
+        assert!(repo.find_by_id(4).await.unwrap().is_some());
+        assert!(repo.find_by_id(3).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn detectives_are_taken_back_if_the_users_cannot_be_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server {
+            store: detectives::store(Registry::open(dir.path()).unwrap()),
+            repo: Arc::new(Unstorable),
+            events: EventBus::new(),
+        };
+
+        let (status, _) = server
+            .send(json!({
+                "atomic": true,
+                "operations": [
+                    { "op": "create", "resource": "detectives", "body": moriarty() },
+                    { "op": "create", "resource": "users", "body": { "id": 4, "name": "Irene" } },
+                ],
+            }))
+            .await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(server.store.read().unwrap().get(3).is_none());
+        drop(server);
+        assert!(Registry::open(dir.path()).unwrap().get(3).is_none());
+    }
+}
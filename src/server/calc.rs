@@ -0,0 +1,223 @@
+// CALCULATOR
+//
+// The `decode` and `divide` functions from `errors::result`, exposed over HTTP. A numerator or
+// denominator that is not a number, and a denominator of zero, each surface as their own
+// problem type, so the `Result` plumbing from the exercises becomes visible to clients.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use warp::{Filter, Rejection, Reply};
+
+use super::error::AppError;
+use super::json::{self, FieldError, Validate};
+use super::openapi::{Document, Operation};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalcError {
+    /// The input could not be decoded as a whole number.
+    InvalidNumber(String),
+    DivisionByZero,
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalcError::InvalidNumber(s) => write!(f, "{:?} is not a valid number", s),
+            CalcError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for CalcError {}
+
+impl From<CalcError> for AppError {
+    fn from(e: CalcError) -> Self {
+        AppError::Calc(e)
+    }
+}
+
+impl From<CalcError> for Rejection {
+    fn from(e: CalcError) -> Self {
+        AppError::from(e).into()
+    }
+}
+
+pub fn decode(s: &str) -> Result<i32, CalcError> {
+    s.parse::<i32>()
+        .map_err(|_| CalcError::InvalidNumber(s.to_string()))
+}
+
+pub fn divide(numerator: f64, denominator: f64) -> Result<f64, CalcError> {
+    if denominator == 0.0 {
+        Err(CalcError::DivisionByZero)
+    } else {
+        Ok(numerator / denominator)
+    }
+}
+
+pub fn decode_and_then_divide(numerator: &str, denominator: &str) -> Result<f64, CalcError> {
+    let numerator = decode(numerator)?;
+    let denominator = decode(denominator)?;
+
+    divide(numerator as f64, denominator as f64)
+}
+
+/// The body accepted by `POST /calc`. The operands are strings, so that clients can send
+/// anything and see how it decodes.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Division {
+    pub numerator: String,
+    pub denominator: String,
+}
+
+impl Validate for Division {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        // Bad operands are reported as calculation errors, not validation errors.
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Quotient {
+    pub numerator: String,
+    pub denominator: String,
+    pub result: f64,
+}
+
+/// All the calculator routes, composed into a single filter.
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // GET /divide/4/2 => 200 OK with body { "numerator": "4", "denominator": "2", "result": 2.0 }
+    let path = warp::path!("divide" / String / String)
+        .and(warp::get())
+        .and_then(|numerator, denominator| {
+            calculate(Division {
+                numerator,
+                denominator,
+            })
+        });
+
+    // POST /calc with body { "numerator": "4", "denominator": "2" } => the same
+    let body = warp::path!("calc")
+        .and(warp::post())
+        .and(json::body())
+        .and_then(calculate);
+
+    path.or(body)
+}
+
+/// Describes the calculator routes.
+pub fn openapi(document: &mut Document) {
+    let operand = json!({ "type": "string", "example": "4" });
+
+    document.schema(
+        "Division",
+        json!({
+            "type": "object",
+            "required": ["numerator", "denominator"],
+            "properties": { "numerator": operand, "denominator": operand },
+        }),
+    );
+    document.schema(
+        "Quotient",
+        json!({
+            "type": "object",
+            "properties": {
+                "numerator": operand,
+                "denominator": operand,
+                "result": { "type": "number" },
+            },
+        }),
+    );
+    document.operation(
+        "get",
+        "/divide/{numerator}/{denominator}",
+        Operation::new("calculator", "Divide two whole numbers")
+            .path("numerator", operand.clone(), "The number to divide")
+            .path("denominator", operand.clone(), "The number to divide by")
+            .json(200, "The quotient", "Quotient")
+            .problem(400, "An operand is not a whole number")
+            .problem(422, "The denominator is zero"),
+    );
+    document.operation(
+        "post",
+        "/calc",
+        Operation::new("calculator", "Divide two whole numbers")
+            .body("Division")
+            .json(200, "The quotient", "Quotient")
+            .problem(422, "The denominator is zero"),
+    );
+}
+
+async fn calculate(division: Division) -> Result<impl Reply, Rejection> {
+    let result = decode_and_then_divide(&division.numerator, &division.denominator)?;
+
+    Ok(warp::reply::json(&Quotient {
+        numerator: division.numerator,
+        denominator: division.denominator,
+        result,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use super::*;
+    use crate::server::error;
+
+    #[test]
+    fn decode_and_then_divide_propagates_errors() {
+        assert_eq!(decode_and_then_divide("4", "2"), Ok(2.0));
+        assert_eq!(
+            decode_and_then_divide("four", "2"),
+            Err(CalcError::InvalidNumber("four".to_string()))
+        );
+        assert_eq!(
+            decode_and_then_divide("4", "0"),
+            Err(CalcError::DivisionByZero)
+        );
+    }
+
+    #[tokio::test]
+    async fn divide_by_path() {
+        let response = warp::test::request()
+            .path("/divide/9/3")
+            .reply(&routes())
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body["result"], 3.0);
+    }
+
+    #[tokio::test]
+    async fn division_by_zero_is_its_own_problem() {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/calc")
+            .json(&serde_json::json!({ "numerator": "4", "denominator": "0" }))
+            .reply(&routes().recover(error::recover))
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["type"], "/problems/division-by-zero");
+    }
+
+    #[tokio::test]
+    async fn invalid_number_is_bad_request() {
+        let response = warp::test::request()
+            .path("/divide/four/2")
+            .reply(&routes().recover(error::recover))
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body["type"], "/problems/invalid-number");
+    }
+}
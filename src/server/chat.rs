@@ -0,0 +1,442 @@
+// CHAT
+//
+// A workshop chat room served over WebSockets, for classrooms that cannot reach the chat linked
+// from `welcome`. Each named room has a `broadcast` channel that every connected client
+// subscribes to, and keeps its most recent messages so that late joiners can catch up. A room is
+// forgotten, history and all, once the last member leaves. Joining needs the `Reader` role.
+//
+// The protocol is JSON text frames, tagged by `type`. A client first sends
+// `{ "type": "join", "name": "..." }`, and is answered with the room's `history`. After that, it
+// sends `{ "type": "say", "text": "..." }` to talk, and `{ "type": "leave" }` (or simply closes
+// the socket) to go. Everyone in the room, the speaker included, receives `joined`, `left` and
+// `message` frames as they happen.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::{self, Role};
+use super::error::AppError;
+use super::json::{FieldError, Validate, Validator};
+use super::openapi::{Document, Operation};
+
+/// How many messages each room remembers for late joiners.
+const HISTORY_LEN: usize = 50;
+
+/// How many events a slow client may fall behind before it starts missing them.
+const CHANNEL_CAPACITY: usize = 64;
+
+const MAX_NAME_LEN: usize = 32;
+const MAX_TEXT_LEN: usize = 1000;
+
+/// A frame sent by a client.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Join { name: String },
+    Say { text: String },
+    Leave,
+}
+
+impl Validate for ClientMessage {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        match self {
+            ClientMessage::Join { name } => Validator::default()
+                .not_blank(name, "name")
+                .check(name.chars().count() <= MAX_NAME_LEN, "name", "is too long")
+                .finish(),
+            ClientMessage::Say { text } => Validator::default()
+                .not_blank(text, "text")
+                .check(text.chars().count() <= MAX_TEXT_LEN, "text", "is too long")
+                .finish(),
+            ClientMessage::Leave => Ok(()),
+        }
+    }
+}
+
+/// A frame sent by the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    History { messages: Vec<ServerMessage> },
+    Joined { name: String },
+    Left { name: String },
+    Message { name: String, text: String },
+    Error { message: String },
+}
+
+struct Room {
+    sender: broadcast::Sender<ServerMessage>,
+    history: VecDeque<ServerMessage>,
+    /// How many clients have joined and not left yet.
+    members: usize,
+}
+
+impl Room {
+    /// Sends `message` to everyone in the room, remembering it if it is something somebody said.
+    fn publish(&mut self, message: ServerMessage) {
+        if let ServerMessage::Message { .. } = message {
+            if self.history.len() == HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(message.clone());
+        }
+        // Sending only fails when nobody is listening, which is fine.
+        let _ = self.sender.send(message);
+    }
+}
+
+/// Every chat room, created on first use.
+#[derive(Default)]
+pub struct Chat {
+    rooms: Mutex<HashMap<String, Room>>,
+}
+
+pub type Shared = Arc<Chat>;
+
+impl Chat {
+    pub fn new() -> Shared {
+        Arc::new(Chat::default())
+    }
+
+    /// Subscribes to `room`, returning the messages it already holds. Both happen under the same
+    /// lock, so nothing is missed or seen twice in between.
+    fn join(&self, room: &str) -> (Vec<ServerMessage>, broadcast::Receiver<ServerMessage>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(room.to_string()).or_insert_with(|| Room {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+            history: VecDeque::new(),
+            members: 0,
+        });
+        room.members += 1;
+
+        (
+            room.history.iter().cloned().collect(),
+            room.sender.subscribe(),
+        )
+    }
+
+    /// Sends `message` to everyone in `room`.
+    fn publish(&self, room: &str, message: ServerMessage) {
+        if let Some(room) = self.rooms.lock().unwrap().get_mut(room) {
+            room.publish(message);
+        }
+    }
+
+    /// Tells everyone left in `room` that `name` has left, and forgets the room if that was the
+    /// last of its members.
+    fn leave(&self, room: &str, name: String) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(found) = rooms.get_mut(room) else {
+            return;
+        };
+
+        found.members -= 1;
+        if found.members == 0 {
+            rooms.remove(room);
+        } else {
+            found.publish(ServerMessage::Left { name });
+        }
+    }
+}
+
+/// GET /chat/{room}, upgraded to a WebSocket
+pub fn routes(
+    chat: Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("chat" / String)
+        .and(warp::get())
+        .and(auth::require(auth, Role::Reader))
+        .and(warp::ws())
+        .and(with_chat(chat))
+        .and_then(upgrade)
+}
+
+/// Describes the chat route.
+pub fn openapi(document: &mut Document) {
+    document.operation(
+        "get",
+        "/chat/{room}",
+        Operation::new("chat", "Join a chat room over a WebSocket")
+            .path(
+                "room",
+                json!({ "type": "string", "pattern": "^[A-Za-z0-9_-]{1,32}$" }),
+                "The room to join",
+            )
+            .response(
+                101,
+                "Switched to the WebSocket protocol described in the `chat` module",
+                None,
+            )
+            .problem(400, "The room name is not valid")
+            .role(Role::Reader),
+    );
+}
+
+fn with_chat(chat: Shared) -> impl Filter<Extract = (Shared,), Error = Infallible> + Clone {
+    warp::any().map(move || chat.clone())
+}
+
+async fn upgrade(room: String, ws: Ws, chat: Shared) -> Result<impl Reply, Rejection> {
+    let valid = !room.is_empty()
+        && room.len() <= MAX_NAME_LEN
+        && room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(AppError::Validation {
+            detail: "the room name is invalid".to_string(),
+            fields: vec![FieldError {
+                field: "room".to_string(),
+                message: format!(
+                    "must be 1 to {} letters, digits, dashes or underscores",
+                    MAX_NAME_LEN
+                ),
+            }],
+        }
+        .into());
+    }
+
+    Ok(ws.on_upgrade(move |socket| connect(socket, chat, room)))
+}
+
+/// Decodes a text frame from a client, or explains what is wrong with it.
+fn decode(text: &str) -> Result<ClientMessage, String> {
+    let message: ClientMessage =
+        serde_json::from_str(text).map_err(|e| format!("the message is malformed: {}", e))?;
+
+    message.validate().map_err(|fields| {
+        let problems: Vec<String> = fields
+            .iter()
+            .map(|f| format!("{} {}", f.field, f.message))
+            .collect();
+        format!("the message is invalid: {}", problems.join(", "))
+    })?;
+
+    Ok(message)
+}
+
+async fn send(socket: &mut (impl SinkExt<Message> + Unpin), message: &ServerMessage) -> bool {
+    let text = serde_json::to_string(message).expect("chat messages serialize");
+
+    socket.send(Message::text(text)).await.is_ok()
+}
+
+async fn connect(socket: WebSocket, chat: Shared, room: String) {
+    let (mut outgoing, mut incoming) = socket.split();
+
+    // Nothing happens until the client says who they are.
+    let name = loop {
+        let text = match incoming.next().await {
+            Some(Ok(frame)) if frame.is_close() => return,
+            Some(Ok(frame)) => match frame.to_str() {
+                Ok(text) => text.to_string(),
+                Err(()) => continue,
+            },
+            _ => return,
+        };
+
+        let error = match decode(&text) {
+            Ok(ClientMessage::Join { name }) => break name,
+            Ok(ClientMessage::Leave) => return,
+            Ok(ClientMessage::Say { .. }) => "join the room before saying anything".to_string(),
+            Err(error) => error,
+        };
+        if !send(&mut outgoing, &ServerMessage::Error { message: error }).await {
+            return;
+        }
+    };
+
+    let (history, mut events) = chat.join(&room);
+    if !send(&mut outgoing, &ServerMessage::History { messages: history }).await {
+        return;
+    }
+    chat.publish(&room, ServerMessage::Joined { name: name.clone() });
+    tracing::debug!(room = %room, name = %name, "joined chat");
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if !send(&mut outgoing, &event).await {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(room = %room, name = %name, missed, "chat client fell behind");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            frame = incoming.next() => {
+                let text = match frame {
+                    Some(Ok(frame)) if frame.is_close() => break,
+                    Some(Ok(frame)) => match frame.to_str() {
+                        Ok(text) => text.to_string(),
+                        Err(()) => continue,
+                    },
+                    _ => break,
+                };
+
+                let error = match decode(&text) {
+                    Ok(ClientMessage::Say { text }) => {
+                        chat.publish(&room, ServerMessage::Message { name: name.clone(), text });
+                        continue;
+                    }
+                    Ok(ClientMessage::Leave) => break,
+                    Ok(ClientMessage::Join { .. }) => "already joined the room".to_string(),
+                    Err(error) => error,
+                };
+                if !send(&mut outgoing, &ServerMessage::Error { message: error }).await {
+                    break;
+                }
+            }
+        }
+    }
+
+    tracing::debug!(room = %room, name = %name, "left chat");
+    chat.leave(&room, name);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::test::WsClient;
+
+    use super::*;
+
+    async fn connect(chat: &Shared, room: &str) -> WsClient {
+        warp::test::ws()
+            .path(&format!("/chat/{}", room))
+            .handshake(routes(chat.clone(), Arc::default()))
+            .await
+            .unwrap()
+    }
+
+    async fn send(client: &mut WsClient, message: Value) {
+        client.send_text(message.to_string()).await;
+    }
+
+    async fn recv(client: &mut WsClient) -> Value {
+        let frame = client.recv().await.unwrap();
+        serde_json::from_str(frame.to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_are_broadcast_to_the_room() {
+        let chat = Chat::new();
+        let mut alice = connect(&chat, "lobby").await;
+        let mut bob = connect(&chat, "lobby").await;
+
+        send(&mut alice, json!({ "type": "join", "name": "Alice" })).await;
+        assert_eq!(recv(&mut alice).await["type"], "history");
+        assert_eq!(recv(&mut alice).await["name"], "Alice");
+
+        send(&mut bob, json!({ "type": "join", "name": "Bob" })).await;
+        assert_eq!(recv(&mut bob).await["type"], "history");
+        assert_eq!(
+            recv(&mut alice).await,
+            json!({ "type": "joined", "name": "Bob" })
+        );
+        recv(&mut bob).await;
+
+        send(&mut alice, json!({ "type": "say", "text": "Hello!" })).await;
+        let expected = json!({ "type": "message", "name": "Alice", "text": "Hello!" });
+        assert_eq!(recv(&mut alice).await, expected);
+        assert_eq!(recv(&mut bob).await, expected);
+
+        send(&mut bob, json!({ "type": "leave" })).await;
+        assert_eq!(
+            recv(&mut alice).await,
+            json!({ "type": "left", "name": "Bob" })
+        );
+    }
+
+    #[tokio::test]
+    async fn late_joiners_receive_history() {
+        let chat = Chat::new();
+        let mut alice = connect(&chat, "lobby").await;
+        send(&mut alice, json!({ "type": "join", "name": "Alice" })).await;
+        recv(&mut alice).await;
+        recv(&mut alice).await;
+        send(
+            &mut alice,
+            json!({ "type": "say", "text": "Is anyone here?" }),
+        )
+        .await;
+        recv(&mut alice).await;
+
+        let mut bob = connect(&chat, "lobby").await;
+        send(&mut bob, json!({ "type": "join", "name": "Bob" })).await;
+
+        assert_eq!(
+            recv(&mut bob).await,
+            json!({
+                "type": "history",
+                "messages": [{ "type": "message", "name": "Alice", "text": "Is anyone here?" }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn saying_before_joining_is_an_error() {
+        let chat = Chat::new();
+        let mut alice = connect(&chat, "lobby").await;
+
+        send(&mut alice, json!({ "type": "say", "text": "Hello?" })).await;
+
+        assert_eq!(recv(&mut alice).await["type"], "error");
+    }
+
+    #[tokio::test]
+    async fn invalid_room_names_are_rejected() {
+        let result = warp::test::ws()
+            .path("/chat/no%20spaces")
+            .handshake(routes(Chat::new(), Arc::default()))
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn joining_needs_the_reader_role() {
+        let credentials = Arc::new(auth::Credentials {
+            tokens: [("reader".to_string(), Role::Reader)].into_iter().collect(),
+            ..Default::default()
+        });
+        let handshake = |token: Option<&str>| {
+            let request = warp::test::ws().path("/chat/lobby");
+            let request = match token {
+                Some(token) => request.header("authorization", format!("Bearer {}", token)),
+                None => request,
+            };
+            request.handshake(routes(Chat::new(), credentials.clone()))
+        };
+
+        assert!(handshake(None).await.is_err());
+        assert!(handshake(Some("reader")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rooms_are_forgotten_once_everyone_has_left() {
+        let chat = Chat::new();
+        let mut alice = connect(&chat, "lobby").await;
+        send(&mut alice, json!({ "type": "join", "name": "Alice" })).await;
+        recv(&mut alice).await;
+        recv(&mut alice).await;
+
+        assert!(chat.rooms.lock().unwrap().contains_key("lobby"));
+
+        send(&mut alice, json!({ "type": "leave" })).await;
+        assert!(alice.recv_closed().await.is_ok());
+        assert!(chat.rooms.lock().unwrap().is_empty());
+    }
+}
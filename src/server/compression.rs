@@ -0,0 +1,238 @@
+// COMPRESSION
+//
+// Responses are compressed when the request's `Accept-Encoding` header allows it: with brotli if
+// the client likes it at least as much as gzip, and with gzip otherwise. Bodies too small to
+// gain anything, bodies that are already encoded, and bodies that are streamed rather than sent
+// in one piece, like server-sent events, are sent as they are.
+//
+// A compressed body is a different representation from the uncompressed one, so a strong `ETag`
+// gets the encoding appended (see `conditional::encoded`), and caches never mix the two up.
+
+use std::convert::Infallible;
+use std::io::{self, Write};
+
+use flate2::write::GzEncoder;
+use warp::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, VARY};
+use warp::hyper::body::{self, Body, HttpBody};
+use warp::reply::Response;
+use warp::Filter;
+
+use super::conditional;
+
+/// Bodies smaller than this are sent as they are, since compressing them gains next to nothing.
+const MIN_BYTES: u64 = 1024;
+
+/// The brotli quality, from 0 to 11. The highest qualities are too slow to pay off for responses
+/// that are made on the fly.
+const BROTLI_QUALITY: u32 = 5;
+
+/// The base-2 logarithm of brotli's window size, as recommended by its authors.
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Every encoding, in the order the server prefers them when a client likes them equally.
+    const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(bytes)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut writer = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                writer.write_all(bytes)?;
+                writer.finish()
+            }
+        }
+    }
+}
+
+/// Wraps `routes` so that their responses are compressed for clients that accept it.
+pub fn compressed<F>(routes: F) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (Response,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    // A header that is not valid text accepts no encoding, just like a missing one.
+    warp::header::optional::<String>("accept-encoding")
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(routes)
+        .then(|accept: Option<String>, response: Response| compress(accept, response))
+}
+
+/// The encoding the client likes best, by the quality it gives each, or `None` if it likes none
+/// of them.
+fn choose(accept: &str) -> Option<Encoding> {
+    let codings: Vec<(&str, f32)> = accept.split(',').filter_map(coding).collect();
+    let quality = |encoding: Encoding| {
+        [encoding.token(), "*"]
+            .iter()
+            .find_map(|wanted| {
+                codings
+                    .iter()
+                    .find(|(coding, _)| coding.eq_ignore_ascii_case(wanted))
+                    .map(|(_, q)| *q)
+            })
+            .unwrap_or(0.0)
+    };
+
+    // `max_by` keeps the last of equals, so go through the encodings from least to most preferred.
+    Encoding::ALL
+        .into_iter()
+        .rev()
+        .map(|encoding| (encoding, quality(encoding)))
+        .filter(|(_, q)| *q > 0.0)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(encoding, _)| encoding)
+}
+
+/// Parses a content coding such as `gzip;q=0.5` into the coding and its quality.
+fn coding(coding: &str) -> Option<(&str, f32)> {
+    let mut parts = coding.split(';').map(str::trim);
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let quality = parts
+        .filter_map(|param| param.strip_prefix("q=").or(param.strip_prefix("Q=")))
+        .find_map(|q| q.parse::<f32>().ok())
+        .unwrap_or(1.0);
+
+    Some((name, quality))
+}
+
+async fn compress(accept: Option<String>, response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+
+    let whole = body.size_hint().exact();
+    if parts.headers.contains_key(CONTENT_ENCODING) || whole.is_none_or(|len| len < MIN_BYTES) {
+        return Response::from_parts(parts, body);
+    }
+    // Whether or not this response is compressed, the same request with another
+    // `Accept-Encoding` could be, so caches have to keep them apart.
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = accept.as_deref().and_then(choose) else {
+        return Response::from_parts(parts, body);
+    };
+
+    // The body is already whole, so reading it cannot fail or take any time.
+    let bytes = match body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("cannot read a response body to compress it: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    match encoding.compress(&bytes) {
+        Ok(compressed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            let tag = parts.headers.get(ETAG).and_then(|tag| tag.to_str().ok());
+            if let Some(tag) = tag.and_then(|tag| conditional::encoded(tag, encoding.token())) {
+                if let Ok(tag) = HeaderValue::from_str(&tag) {
+                    parts.headers.insert(ETAG, tag);
+                }
+            }
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(e) => {
+            tracing::error!(
+                "cannot compress a response with {}: {}",
+                encoding.token(),
+                e
+            );
+            Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn api(body: String) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
+        compressed(warp::any().map(move || conditional::tagged(body.clone(), 3)))
+    }
+
+    fn large() -> String {
+        "Sherlock Holmes, 221B Baker Street, London\n".repeat(100)
+    }
+
+    #[test]
+    fn encodings_are_chosen_by_quality() {
+        assert_eq!(choose("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(choose("gzip, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(choose("br;q=0, *"), Some(Encoding::Gzip));
+        assert_eq!(choose("identity"), None);
+        assert_eq!(choose("gzip;q=0"), None);
+    }
+
+    #[tokio::test]
+    async fn responses_are_gzipped_for_clients_that_accept_it() {
+        let response = warp::test::request()
+            .header("accept-encoding", "gzip")
+            .reply(&api(large()))
+            .await;
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&response.body()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        assert!(response.body().len() < large().len());
+        assert_eq!(decoded, large());
+    }
+
+    #[tokio::test]
+    async fn brotli_is_preferred() {
+        let response = warp::test::request()
+            .header("accept-encoding", "gzip, deflate, br")
+            .reply(&api(large()))
+            .await;
+
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&response.body()[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+
+        assert_eq!(response.headers()["content-encoding"], "br");
+        assert_eq!(response.headers()["etag"], r#""3-br""#);
+        assert_eq!(decoded, large());
+    }
+
+    #[tokio::test]
+    async fn small_and_unwanted_responses_are_sent_as_they_are() {
+        let small = warp::test::request()
+            .header("accept-encoding", "gzip")
+            .reply(&api("elementary".to_string()))
+            .await;
+        let unwanted = warp::test::request().reply(&api(large())).await;
+
+        assert!(!small.headers().contains_key("content-encoding"));
+        assert_eq!(small.body(), "elementary");
+        assert!(!unwanted.headers().contains_key("content-encoding"));
+        assert_eq!(unwanted.headers()["etag"], r#""3""#);
+        assert_eq!(unwanted.body(), large().as_str());
+    }
+}
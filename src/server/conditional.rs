@@ -0,0 +1,294 @@
+// CONDITIONAL REQUESTS
+//
+// Optimistic concurrency for the stored resources. Every detective and user carries a version,
+// bumped on every change and sent back as the response's `ETag`. A client that sends the tag it
+// last saw in `If-Match` only changes the resource if nobody else has changed it since, instead
+// of silently overwriting their edit, and gets `412 Precondition Failed` otherwise. This is the
+// write contention from `concurrency::sharing_data::mutable_share_rw`, but between HTTP clients
+// rather than threads, who cannot hold a lock across requests. A client that sends the tag in
+// `If-None-Match` gets `304 Not Modified` instead of a copy of the resource it already has.
+//
+// The CSV and text renderings of a resource are different representations of the same version,
+// and so is a compressed response, so each carries a tag of its own: `"3"` for JSON, `"3-csv"` for
+// CSV, and `"3-csv-br"` once that is compressed with brotli. `If-None-Match` compares the version
+// and the format, so a cache never answers a request for one format with a copy of another, but
+// ignores the encoding, which caches undo themselves. `If-Match` compares only the version, so a
+// client may send back whichever tag it read.
+
+use std::convert::Infallible;
+
+use serde_json::json;
+use warp::http::header::{ETAG, IF_MATCH, IF_NONE_MATCH};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use super::error::AppError;
+use super::negotiate::Format;
+use super::openapi::Operation;
+
+/// The entity tag of the JSON representation of a resource at `version`.
+pub fn etag(version: u64) -> String {
+    etag_as(version, Format::Json)
+}
+
+/// The entity tag of the representation of a resource at `version` in `format`, such as `"3"`
+/// for JSON and `"3-csv"` for CSV.
+pub fn etag_as(version: u64, format: Format) -> String {
+    match suffix(format) {
+        Some(suffix) => format!("\"{}-{}\"", version, suffix),
+        None => format!("\"{}\"", version),
+    }
+}
+
+/// What a tag has appended to the version for `format`. JSON, the default, has nothing.
+fn suffix(format: Format) -> Option<&'static str> {
+    match format {
+        Format::Json => None,
+        Format::Csv => Some("csv"),
+        Format::Text => Some("text"),
+    }
+}
+
+/// The tag of the representation of `tag` whose body is encoded with `coding`, such as `"3-br"`
+/// for `"3"`, or `None` for weak tags, which already allow for differences in encoding.
+pub fn encoded(tag: &str, coding: &str) -> Option<String> {
+    let opaque = tag.strip_prefix('"')?.strip_suffix('"')?;
+
+    Some(format!("\"{}-{}\"", opaque, coding))
+}
+
+/// The entity tags listed in an `If-Match` or `If-None-Match` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tags {
+    /// `*`, which matches any version of a resource that exists.
+    Any,
+    List(Vec<Tag>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    weak: bool,
+    /// The tag itself, including its quotes.
+    opaque: String,
+}
+
+impl Tags {
+    pub fn parse(header: &str) -> Tags {
+        if header.trim() == "*" {
+            return Tags::Any;
+        }
+
+        let tags = header
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.strip_prefix("W/") {
+                Some(opaque) => Tag {
+                    weak: true,
+                    opaque: opaque.to_string(),
+                },
+                None => Tag {
+                    weak: false,
+                    opaque: tag.to_string(),
+                },
+            })
+            .collect();
+
+        Tags::List(tags)
+    }
+
+    /// Whether a tag matches `version` by the strong comparison `If-Match` uses, under which weak
+    /// tags never match.
+    fn matches_strongly(&self, version: u64) -> bool {
+        match self {
+            Tags::Any => true,
+            Tags::List(tags) => tags.iter().any(|tag| !tag.weak && tag.names(version)),
+        }
+    }
+
+    /// Whether a tag matches `version` in `format` by the weak comparison `If-None-Match` uses.
+    fn matches_weakly(&self, version: u64, format: Format) -> bool {
+        match self {
+            Tags::Any => true,
+            Tags::List(tags) => tags
+                .iter()
+                .any(|tag| tag.names(version) && tag.format() == Some(format)),
+        }
+    }
+}
+
+impl Tag {
+    /// Whether this is the tag of `version`, in any format and encoding.
+    fn names(&self, version: u64) -> bool {
+        self.parts()
+            .and_then(|mut parts| parts.next())
+            .is_some_and(|named| named == version.to_string())
+    }
+
+    /// The format of the representation this is the tag of, or `None` if the tag is malformed.
+    fn format(&self) -> Option<Format> {
+        let after = self.parts()?.nth(1);
+
+        // Anything else after the version is the encoding of a JSON body.
+        Some(
+            [Format::Csv, Format::Text]
+                .into_iter()
+                .find(|&format| suffix(format) == after)
+                .unwrap_or(Format::Json),
+        )
+    }
+
+    /// The version, format suffix and encoding the tag is made of, in that order.
+    fn parts(&self) -> Option<std::str::Split<'_, char>> {
+        let opaque = self.opaque.strip_prefix('"')?.strip_suffix('"')?;
+
+        Some(opaque.split('-'))
+    }
+}
+
+/// Extracts the tags in the `If-Match` header, if the request has one.
+pub fn if_match() -> impl Filter<Extract = (Option<Tags>,), Error = Infallible> + Clone {
+    tags(IF_MATCH.as_str())
+}
+
+/// Extracts the tags in the `If-None-Match` header, if the request has one.
+pub fn if_none_match() -> impl Filter<Extract = (Option<Tags>,), Error = Infallible> + Clone {
+    tags(IF_NONE_MATCH.as_str())
+}
+
+fn tags(
+    header: &'static str,
+) -> impl Filter<Extract = (Option<Tags>,), Error = Infallible> + Clone {
+    // A header that is not valid text cannot match anything, just like a tag from another server.
+    warp::header::optional::<String>(header)
+        .or(warp::any().map(|| Some(String::new())))
+        .unify()
+        .map(|header: Option<String>| header.map(|header| Tags::parse(&header)))
+}
+
+/// Fails with `412 Precondition Failed` unless `if_match` allows changing a resource that is
+/// currently at `version`. Without an `If-Match` header, any version may be changed.
+pub fn check(if_match: Option<&Tags>, version: u64) -> Result<(), AppError> {
+    match if_match {
+        Some(tags) if !tags.matches_strongly(version) => {
+            Err(AppError::PreconditionFailed(format!(
+                "the resource has changed; its current ETag is {}",
+                etag(version)
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Tags `reply`, a JSON body, with the ETag of `version`.
+pub fn tagged(reply: impl Reply, version: u64) -> Response {
+    tagged_as(reply, version, Format::Json)
+}
+
+fn tagged_as(reply: impl Reply, version: u64, format: Format) -> Response {
+    warp::reply::with_header(reply, ETAG, etag_as(version, format)).into_response()
+}
+
+/// Replies `304 Not Modified` if the client already has `version` in `format`, and `reply`, the
+/// resource rendered in `format`, otherwise.
+pub fn respond(
+    if_none_match: Option<&Tags>,
+    version: u64,
+    format: Format,
+    reply: impl Reply,
+) -> Response {
+    match if_none_match {
+        Some(tags) if tags.matches_weakly(version, format) => {
+            tagged_as(StatusCode::NOT_MODIFIED, version, format)
+        }
+        _ => tagged_as(reply, version, format),
+    }
+}
+
+/// Documents the `If-None-Match` header of an operation that reads a resource.
+pub fn read_parameters(operation: Operation) -> Operation {
+    operation
+        .header(
+            "If-None-Match",
+            json!({ "type": "string" }),
+            "Reply 304 Not Modified if the resource still has one of these ETags",
+        )
+        .response(304, "The resource still has the given ETag", None)
+}
+
+/// Documents the `If-Match` header of an operation that changes a resource.
+pub fn write_parameters(operation: Operation) -> Operation {
+    operation
+        .header(
+            "If-Match",
+            json!({ "type": "string" }),
+            "Only change the resource if it still has one of these ETags",
+        )
+        .problem(412, "The resource no longer has the given ETag")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_match_uses_strong_comparison() {
+        let tags = Tags::parse(r#"W/"3", "4""#);
+
+        assert!(!tags.matches_strongly(3));
+        assert!(tags.matches_strongly(4));
+        assert!(check(Some(&tags), 4).is_ok());
+        assert!(check(Some(&tags), 5).is_err());
+        assert!(check(None, 5).is_ok());
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        let tags = Tags::parse(r#"W/"3""#);
+
+        assert!(tags.matches_weakly(3, Format::Json));
+        assert!(!tags.matches_weakly(4, Format::Json));
+        assert!(Tags::parse("*").matches_weakly(4, Format::Csv));
+    }
+
+    #[test]
+    fn encoded_tags_match_their_version() {
+        let tags = Tags::parse(r#""3-br""#);
+
+        assert_eq!(encoded(r#""3""#, "br").as_deref(), Some(r#""3-br""#));
+        assert_eq!(encoded(r#"W/"3""#, "br"), None);
+        assert!(tags.matches_strongly(3));
+        assert!(tags.matches_weakly(3, Format::Json));
+        assert!(!tags.matches_strongly(30));
+    }
+
+    #[test]
+    fn tags_name_their_format() {
+        let csv = Tags::parse(r#""3-csv-br""#);
+        let json = Tags::parse(r#""3-gzip""#);
+
+        assert_eq!(etag_as(3, Format::Csv), r#""3-csv""#);
+        assert_eq!(etag_as(3, Format::Json), r#""3""#);
+        assert!(csv.matches_weakly(3, Format::Csv));
+        assert!(!csv.matches_weakly(3, Format::Json));
+        assert!(!json.matches_weakly(3, Format::Text));
+        assert!(csv.matches_strongly(3));
+    }
+
+    #[test]
+    fn matching_reads_are_not_modified() {
+        let tags = Tags::parse(r#""2""#);
+
+        let fresh = respond(Some(&tags), 2, Format::Json, "body");
+        let stale = respond(Some(&tags), 3, Format::Json, "body");
+        let other = respond(Some(&tags), 2, Format::Csv, "body");
+
+        assert_eq!(fresh.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(fresh.headers()[ETAG], r#""2""#);
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert_eq!(stale.headers()[ETAG], r#""3""#);
+        assert_eq!(other.status(), StatusCode::OK);
+        assert_eq!(other.headers()[ETAG], r#""2-csv""#);
+    }
+}
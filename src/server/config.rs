@@ -0,0 +1,797 @@
+// CONFIGURATION
+//
+// Settings for the graduation server, gathered from up to three places. Command-line flags take
+// precedence over environment variables, which take precedence over the config file, which
+// takes precedence over the defaults. Every value is checked as it is read, and a value that
+// fails to parse is reported together with where it came from. The `gen-cert` command takes its
+// own flags, and only from the command line.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::auth::Credentials;
+use super::limits::Limits;
+use super::rate_limit::{KeyBy, Limit, Policy};
+use super::tls;
+
+pub const USAGE: &str = "\
+Usage: intro-rust [OPTIONS]
+       intro-rust gen-cert [--out <DIR>] [--host <NAME>]...
+
+Options:
+      --config <FILE>         TOML config file to read settings from   [env: INTRO_RUST_CONFIG]
+      --address <IP>          address to listen on (default 127.0.0.1) [env: INTRO_RUST_ADDRESS]
+      --port <PORT>           port to listen on (default 3030)         [env: INTRO_RUST_PORT]
+      --log-level <LEVEL>     error, warn, info, debug or trace        [env: INTRO_RUST_LOG_LEVEL]
+      --data-dir <DIR>        directory to persist data in             [env: INTRO_RUST_DATA_DIR]
+      --shutdown-timeout <S>  seconds to drain requests on shutdown    [env: INTRO_RUST_SHUTDOWN_TIMEOUT]
+      --rate-limit <LIMIT>    requests per client, such as 60/minute   [env: INTRO_RUST_RATE_LIMIT]
+      --rate-limit-by <KEY>   tell clients apart by address or api-key [env: INTRO_RUST_RATE_LIMIT_BY]
+      --tls-cert <FILE>       PEM certificate chain to serve HTTPS     [env: INTRO_RUST_TLS_CERT]
+      --tls-key <FILE>        PEM private key for --tls-cert           [env: INTRO_RUST_TLS_KEY]
+      --job-workers <N>       workers for background jobs (default 4)  [env: INTRO_RUST_JOB_WORKERS]
+      --max-body-bytes <N>    largest request body (default 1048576)   [env: INTRO_RUST_MAX_BODY_BYTES]
+      --request-timeout <S>   seconds to answer a request (default 30) [env: INTRO_RUST_REQUEST_TIMEOUT]
+      --write-overrun <S>     seconds changes may overrun (default 30) [env: INTRO_RUST_WRITE_OVERRUN]
+  -h, --help                  print this message
+
+Flags override environment variables, which override the config file. Limits for individual
+routes can only be set in the config file, in a [route-rate-limits] table such as
+    \"/calc\" = \"10/minute\"
+and so can request timeouts for individual routes, in seconds, in a [route-timeouts] table:
+    \"/course\" = 5
+
+Credentials, too, can only be set in the config file. Without any, every route is open:
+    [auth.tokens]
+    \"some-secret-token\" = \"editor\"          # reader, editor or admin
+    [auth.basic]
+    watson = { password = \"elementary\", role = \"reader\" }
+
+gen-cert writes a self-signed development certificate and its key to cert.pem and key.pem in
+--out (default the current directory), for each --host (default localhost, 127.0.0.1 and ::1).
+Serve them with --tls-cert cert.pem --tls-key key.pem.";
+
+/// What the command line asked the program to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Serve(Box<Config>),
+    /// Write a self-signed certificate for `hosts` into `out`.
+    GenerateCert {
+        out: PathBuf,
+        hosts: Vec<String>,
+    },
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub log_level: LogLevel,
+    /// Where to persist data. Without one, everything is kept in memory.
+    pub data_dir: Option<PathBuf>,
+    /// How long to wait for in-flight requests to finish when shutting down.
+    pub shutdown_timeout: Duration,
+    /// How many requests each client may make. By default, there is no limit.
+    pub rate_limit: Policy,
+    /// Who may use the server. By default, anyone may.
+    pub auth: Credentials,
+    /// The certificate and key to serve HTTPS with. Without them, the server speaks plain HTTP.
+    pub tls: Option<TlsFiles>,
+    /// How many background jobs may run at once.
+    pub job_workers: NonZeroUsize,
+    /// How large request bodies may be, and how long requests may take.
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3030,
+            log_level: LogLevel::Info,
+            data_dir: None,
+            shutdown_timeout: Duration::from_secs(10),
+            rate_limit: Policy::default(),
+            auth: Credentials::default(),
+            tls: None,
+            job_workers: NonZeroUsize::new(4).unwrap(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err("expected one of error, warn, info, debug or trace".to_string()),
+        }
+    }
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+/// Where a setting was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Env(&'static str),
+    Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::Flag(name) => write!(f, "flag {}", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    InvalidValue {
+        value: String,
+        source: Source,
+        reason: String,
+    },
+    UnknownFlag(String),
+    MissingValue(&'static str),
+    /// One of a pair of settings that only make sense together was given without the other.
+    Unpaired {
+        given: &'static str,
+        missing: &'static str,
+    },
+    ReadFile {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    ParseFile {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidValue {
+                value,
+                source,
+                reason,
+            } => write!(f, "invalid value {:?} for {}: {}", value, source, reason),
+            ConfigError::UnknownFlag(flag) => {
+                write!(f, "unknown flag {} (try --help)", flag)
+            }
+            ConfigError::MissingValue(flag) => write!(f, "flag {} requires a value", flag),
+            ConfigError::Unpaired { given, missing } => {
+                write!(f, "{} is set, so {} must be too", given, missing)
+            }
+            ConfigError::ReadFile { path, error } => {
+                write!(f, "cannot read config file {}: {}", path.display(), error)
+            }
+            ConfigError::ParseFile { path, error } => {
+                write!(f, "invalid config file {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The settings that can be given as flags or environment variables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Setting {
+    Config,
+    Address,
+    Port,
+    LogLevel,
+    DataDir,
+    ShutdownTimeout,
+    RateLimit,
+    RateLimitBy,
+    TlsCert,
+    TlsKey,
+    JobWorkers,
+    MaxBodyBytes,
+    RequestTimeout,
+    WriteOverrun,
+}
+
+impl Setting {
+    const ALL: [Setting; 14] = [
+        Setting::Config,
+        Setting::Address,
+        Setting::Port,
+        Setting::LogLevel,
+        Setting::DataDir,
+        Setting::ShutdownTimeout,
+        Setting::RateLimit,
+        Setting::RateLimitBy,
+        Setting::TlsCert,
+        Setting::TlsKey,
+        Setting::JobWorkers,
+        Setting::MaxBodyBytes,
+        Setting::RequestTimeout,
+        Setting::WriteOverrun,
+    ];
+
+    fn flag(self) -> &'static str {
+        match self {
+            Setting::Config => "--config",
+            Setting::Address => "--address",
+            Setting::Port => "--port",
+            Setting::LogLevel => "--log-level",
+            Setting::DataDir => "--data-dir",
+            Setting::ShutdownTimeout => "--shutdown-timeout",
+            Setting::RateLimit => "--rate-limit",
+            Setting::RateLimitBy => "--rate-limit-by",
+            Setting::TlsCert => "--tls-cert",
+            Setting::TlsKey => "--tls-key",
+            Setting::JobWorkers => "--job-workers",
+            Setting::MaxBodyBytes => "--max-body-bytes",
+            Setting::RequestTimeout => "--request-timeout",
+            Setting::WriteOverrun => "--write-overrun",
+        }
+    }
+
+    fn env(self) -> &'static str {
+        match self {
+            Setting::Config => "INTRO_RUST_CONFIG",
+            Setting::Address => "INTRO_RUST_ADDRESS",
+            Setting::Port => "INTRO_RUST_PORT",
+            Setting::LogLevel => "INTRO_RUST_LOG_LEVEL",
+            Setting::DataDir => "INTRO_RUST_DATA_DIR",
+            Setting::ShutdownTimeout => "INTRO_RUST_SHUTDOWN_TIMEOUT",
+            Setting::RateLimit => "INTRO_RUST_RATE_LIMIT",
+            Setting::RateLimitBy => "INTRO_RUST_RATE_LIMIT_BY",
+            Setting::TlsCert => "INTRO_RUST_TLS_CERT",
+            Setting::TlsKey => "INTRO_RUST_TLS_KEY",
+            Setting::JobWorkers => "INTRO_RUST_JOB_WORKERS",
+            Setting::MaxBodyBytes => "INTRO_RUST_MAX_BODY_BYTES",
+            Setting::RequestTimeout => "INTRO_RUST_REQUEST_TIMEOUT",
+            Setting::WriteOverrun => "INTRO_RUST_WRITE_OVERRUN",
+        }
+    }
+}
+
+/// The shape of the config file. Every setting is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct FileConfig {
+    address: Option<IpAddr>,
+    port: Option<u16>,
+    log_level: Option<LogLevel>,
+    data_dir: Option<PathBuf>,
+    /// In seconds.
+    shutdown_timeout: Option<u64>,
+    rate_limit: Option<Limit>,
+    rate_limit_by: Option<KeyBy>,
+    /// Limits for individual routes, by path prefix.
+    #[serde(default)]
+    route_rate_limits: BTreeMap<String, Limit>,
+    auth: Option<Credentials>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    job_workers: Option<NonZeroUsize>,
+    max_body_bytes: Option<u64>,
+    /// In seconds.
+    request_timeout: Option<NonZeroU64>,
+    /// Timeouts for individual routes, by path prefix, in seconds.
+    #[serde(default)]
+    route_timeouts: BTreeMap<String, NonZeroU64>,
+    /// In seconds.
+    write_overrun: Option<u64>,
+}
+
+/// Reads the command to run from the process's arguments and environment.
+pub fn from_env() -> Result<Command, ConfigError> {
+    parse(std::env::args().skip(1), |name| std::env::var(name).ok())
+}
+
+/// Reads the command to run from `args` (not including the program name) and the environment
+/// variables looked up by `env`.
+pub fn parse(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Command, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter().peekable();
+
+    if args.peek().is_some_and(|arg| arg == "gen-cert") {
+        args.next();
+        return parse_gen_cert(args);
+    }
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help);
+        }
+
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let setting = Setting::ALL
+            .into_iter()
+            .find(|s| s.flag() == name)
+            .ok_or(ConfigError::UnknownFlag(name))?;
+        let value = match inline {
+            Some(value) => value,
+            None => args
+                .next()
+                .ok_or(ConfigError::MissingValue(setting.flag()))?,
+        };
+
+        flags.push((setting, value, Source::Flag(setting.flag())));
+    }
+
+    let from_env = Setting::ALL
+        .into_iter()
+        .filter_map(|s| env(s.env()).map(|value| (s, value, Source::Env(s.env()))));
+
+    // Later entries override earlier ones, so environment variables go before flags.
+    let settings: Vec<_> = from_env.chain(flags).collect();
+
+    let mut config = Config::default();
+    let mut tls = Tls::default();
+
+    let file = settings
+        .iter()
+        .rev()
+        .find(|(s, _, _)| *s == Setting::Config)
+        .map(|(_, path, _)| PathBuf::from(path));
+    if let Some(path) = file {
+        apply_file(&mut config, &mut tls, read_file(path)?);
+    }
+
+    for (setting, value, source) in settings {
+        apply(&mut config, &mut tls, setting, value, source)?;
+    }
+
+    config.tls = tls.pair()?;
+
+    Ok(Command::Serve(Box::new(config)))
+}
+
+/// The TLS files as they are read, before checking that either both or neither are given.
+#[derive(Default)]
+struct Tls {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+impl Tls {
+    fn pair(self) -> Result<Option<TlsFiles>, ConfigError> {
+        match (self.cert, self.key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsFiles { cert, key })),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(ConfigError::Unpaired {
+                given: Setting::TlsCert.flag(),
+                missing: Setting::TlsKey.flag(),
+            }),
+            (None, Some(_)) => Err(ConfigError::Unpaired {
+                given: Setting::TlsKey.flag(),
+                missing: Setting::TlsCert.flag(),
+            }),
+        }
+    }
+}
+
+/// Reads the flags of the `gen-cert` command.
+fn parse_gen_cert(mut args: impl Iterator<Item = String>) -> Result<Command, ConfigError> {
+    let mut out = PathBuf::from(".");
+    let mut hosts = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help);
+        }
+
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let flag = match name.as_str() {
+            "--out" => "--out",
+            "--host" => "--host",
+            _ => return Err(ConfigError::UnknownFlag(name)),
+        };
+        let value = match inline {
+            Some(value) => value,
+            None => args.next().ok_or(ConfigError::MissingValue(flag))?,
+        };
+
+        match flag {
+            "--out" => out = PathBuf::from(value),
+            _ => hosts.push(value),
+        }
+    }
+
+    if hosts.is_empty() {
+        hosts = tls::DEFAULT_HOSTS
+            .iter()
+            .map(|host| host.to_string())
+            .collect();
+    }
+
+    Ok(Command::GenerateCert { out, hosts })
+}
+
+fn read_file(path: PathBuf) -> Result<FileConfig, ConfigError> {
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(error) => return Err(ConfigError::ReadFile { path, error }),
+    };
+
+    toml::from_str(&text).map_err(|error| ConfigError::ParseFile { path, error })
+}
+
+fn apply_file(config: &mut Config, tls: &mut Tls, file: FileConfig) {
+    if let Some(address) = file.address {
+        config.address = address;
+    }
+    if let Some(port) = file.port {
+        config.port = port;
+    }
+    if let Some(log_level) = file.log_level {
+        config.log_level = log_level;
+    }
+    if let Some(data_dir) = file.data_dir {
+        config.data_dir = Some(data_dir);
+    }
+    if let Some(seconds) = file.shutdown_timeout {
+        config.shutdown_timeout = Duration::from_secs(seconds);
+    }
+    if let Some(limit) = file.rate_limit {
+        config.rate_limit.default = Some(limit);
+    }
+    if let Some(key_by) = file.rate_limit_by {
+        config.rate_limit.key_by = key_by;
+    }
+    config.rate_limit.routes = file.route_rate_limits;
+    if let Some(auth) = file.auth {
+        config.auth = auth;
+    }
+    if let Some(cert) = file.tls_cert {
+        tls.cert = Some(cert);
+    }
+    if let Some(key) = file.tls_key {
+        tls.key = Some(key);
+    }
+    if let Some(workers) = file.job_workers {
+        config.job_workers = workers;
+    }
+    if let Some(bytes) = file.max_body_bytes {
+        config.limits.max_body_bytes = bytes;
+    }
+    if let Some(seconds) = file.request_timeout {
+        config.limits.timeout = Duration::from_secs(seconds.get());
+    }
+    config.limits.route_timeouts = file
+        .route_timeouts
+        .into_iter()
+        .map(|(route, seconds)| (route, Duration::from_secs(seconds.get())))
+        .collect();
+    if let Some(seconds) = file.write_overrun {
+        config.limits.write_overrun = Duration::from_secs(seconds);
+    }
+}
+
+fn apply(
+    config: &mut Config,
+    tls: &mut Tls,
+    setting: Setting,
+    value: String,
+    source: Source,
+) -> Result<(), ConfigError> {
+    match setting {
+        Setting::Config => {}
+        Setting::Address => config.address = parse_value(value, source)?,
+        Setting::Port => config.port = parse_value(value, source)?,
+        Setting::LogLevel => config.log_level = parse_value(value, source)?,
+        Setting::DataDir => config.data_dir = Some(PathBuf::from(value)),
+        Setting::ShutdownTimeout => {
+            config.shutdown_timeout = Duration::from_secs(parse_value(value, source)?)
+        }
+        Setting::RateLimit => config.rate_limit.default = Some(parse_value(value, source)?),
+        Setting::RateLimitBy => config.rate_limit.key_by = parse_value(value, source)?,
+        Setting::TlsCert => tls.cert = Some(PathBuf::from(value)),
+        Setting::TlsKey => tls.key = Some(PathBuf::from(value)),
+        Setting::JobWorkers => config.job_workers = parse_value(value, source)?,
+        Setting::MaxBodyBytes => config.limits.max_body_bytes = parse_value(value, source)?,
+        Setting::RequestTimeout => {
+            let seconds: NonZeroU64 = parse_value(value, source)?;
+            config.limits.timeout = Duration::from_secs(seconds.get());
+        }
+        Setting::WriteOverrun => {
+            config.limits.write_overrun = Duration::from_secs(parse_value(value, source)?)
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_value<T>(value: String, source: Source) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e: T::Err| ConfigError::InvalidValue {
+            reason: e.to_string(),
+            value,
+            source,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::server::auth::Role;
+
+    fn run(args: &[&str], env: &[(&str, &str)]) -> Result<Command, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        parse(args.iter().map(|a| a.to_string()), |name| {
+            env.get(name).cloned()
+        })
+    }
+
+    fn serve(args: &[&str], env: &[(&str, &str)]) -> Config {
+        match run(args, env).unwrap() {
+            Command::Serve(config) => *config,
+            other => panic!("expected a config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(serve(&[], &[]), Config::default());
+        assert_eq!(
+            Config::default().socket_addr().to_string(),
+            "127.0.0.1:3030"
+        );
+    }
+
+    #[test]
+    fn flags_override_env_which_overrides_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("intro-rust.toml");
+        std::fs::write(
+            &file,
+            "address = \"0.0.0.0\"\nport = 4000\nlog-level = \"debug\"\njob-workers = 8\n",
+        )
+        .unwrap();
+
+        let config = serve(
+            &["--config", file.to_str().unwrap(), "--port=5000"],
+            &[
+                ("INTRO_RUST_PORT", "4500"),
+                ("INTRO_RUST_LOG_LEVEL", "warn"),
+            ],
+        );
+
+        assert_eq!(config.address.to_string(), "0.0.0.0");
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(config.job_workers.get(), 8);
+    }
+
+    #[test]
+    fn invalid_values_name_their_source() {
+        let error = run(&[], &[("INTRO_RUST_PORT", "thirty")]).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "invalid value \"thirty\" for environment variable INTRO_RUST_PORT: \
+             invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_in_file_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("intro-rust.toml");
+        std::fs::write(&file, "prot = 4000\n").unwrap();
+
+        let error = run(&["--config", file.to_str().unwrap()], &[]).unwrap_err();
+
+        assert!(matches!(error, ConfigError::ParseFile { .. }));
+    }
+
+    #[test]
+    fn rate_limits_for_routes_come_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("intro-rust.toml");
+        std::fs::write(
+            &file,
+            "rate-limit = \"100/minute\"\n\n[route-rate-limits]\n\"/calc\" = \"10/minute\"\n",
+        )
+        .unwrap();
+
+        let config = serve(
+            &[
+                "--config",
+                file.to_str().unwrap(),
+                "--rate-limit-by",
+                "api-key",
+            ],
+            &[("INTRO_RUST_RATE_LIMIT", "200/minute")],
+        );
+
+        assert_eq!(
+            config.rate_limit.default,
+            Some("200/minute".parse().unwrap())
+        );
+        assert_eq!(
+            config.rate_limit.routes["/calc"],
+            "10/minute".parse().unwrap()
+        );
+        assert_eq!(config.rate_limit.key_by, KeyBy::ApiKey);
+    }
+
+    #[test]
+    fn limits_for_routes_come_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("intro-rust.toml");
+        std::fs::write(
+            &file,
+            "max-body-bytes = 2048
+request-timeout = 10
+write-overrun = 60
+
+[route-timeouts]
+\"/batch\" = 120
+",
+        )
+        .unwrap();
+
+        let config = serve(
+            &["--config", file.to_str().unwrap()],
+            &[("INTRO_RUST_REQUEST_TIMEOUT", "5")],
+        );
+        let error = run(&["--request-timeout", "0"], &[]).unwrap_err();
+
+        assert_eq!(config.limits.max_body_bytes, 2048);
+        assert_eq!(config.limits.timeout, Duration::from_secs(5));
+        assert_eq!(
+            config.limits.timeout_for("/batch"),
+            Duration::from_secs(120)
+        );
+        assert_eq!(config.limits.write_overrun, Duration::from_secs(60));
+        assert!(matches!(error, ConfigError::InvalidValue { .. }));
+    }
+
+    #[test]
+    fn credentials_come_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("intro-rust.toml");
+        std::fs::write(
+            &file,
+            "[auth.tokens]\n\"s3cr3t\" = \"admin\"\n\n\
+             [auth.basic]\nwatson = { password = \"elementary\", role = \"reader\" }\n",
+        )
+        .unwrap();
+
+        let config = serve(&["--config", file.to_str().unwrap()], &[]);
+
+        assert_eq!(config.auth.tokens["s3cr3t"], Role::Admin);
+        assert_eq!(config.auth.basic["watson"].role, Role::Reader);
+    }
+
+    #[test]
+    fn flag_without_value() {
+        let error = run(&["--address"], &[]).unwrap_err();
+
+        assert_eq!(error.to_string(), "flag --address requires a value");
+    }
+
+    #[test]
+    fn tls_needs_both_a_certificate_and_a_key() {
+        let config = serve(
+            &["--tls-cert", "cert.pem"],
+            &[("INTRO_RUST_TLS_KEY", "key.pem")],
+        );
+        let error = run(&["--tls-key", "key.pem"], &[]).unwrap_err();
+
+        assert_eq!(
+            config.tls,
+            Some(TlsFiles {
+                cert: PathBuf::from("cert.pem"),
+                key: PathBuf::from("key.pem"),
+            })
+        );
+        assert_eq!(
+            error.to_string(),
+            "--tls-key is set, so --tls-cert must be too"
+        );
+    }
+
+    #[test]
+    fn gen_cert_takes_its_own_flags() {
+        let defaults = run(&["gen-cert"], &[("INTRO_RUST_PORT", "4000")]).unwrap();
+        let custom = run(
+            &["gen-cert", "--out=certs", "--host", "baker-street.local"],
+            &[],
+        )
+        .unwrap();
+        let error = run(&["gen-cert", "--port", "4000"], &[]).unwrap_err();
+
+        assert_eq!(
+            defaults,
+            Command::GenerateCert {
+                out: PathBuf::from("."),
+                hosts: vec![
+                    "localhost".to_string(),
+                    "127.0.0.1".to_string(),
+                    "::1".to_string()
+                ],
+            }
+        );
+        assert_eq!(
+            custom,
+            Command::GenerateCert {
+                out: PathBuf::from("certs"),
+                hosts: vec!["baker-street.local".to_string()],
+            }
+        );
+        assert!(matches!(error, ConfigError::UnknownFlag(flag) if flag == "--port"));
+    }
+
+    #[test]
+    fn help() {
+        assert_eq!(run(&["--port", "1", "--help"], &[]).unwrap(), Command::Help);
+    }
+}
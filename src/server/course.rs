@@ -0,0 +1,394 @@
+// COURSE
+//
+// The workshop materials, served as HTML for attendees who would rather read them in a browser.
+// The README and the lesson sources are compiled into the server, and each lesson is split into
+// its introduction, the sections introduced by its `mod` items, and the exercises, which are
+// the tests inside them. Comments are written in Markdown, so that is how they are rendered.
+
+use once_cell::sync::Lazy;
+use pulldown_cmark::{html, Parser};
+use serde_json::json;
+use warp::{Filter, Rejection, Reply};
+
+use super::error::AppError;
+use super::openapi::{Document, Operation};
+
+const README: &str = include_str!("../../README.md");
+
+/// Every lesson, in the order the course teaches them, by the name of its module.
+const SOURCES: &[(&str, &str)] = &[
+    ("welcome", include_str!("../welcome.rs")),
+    ("fundamentals", include_str!("../fundamentals.rs")),
+    ("types", include_str!("../types.rs")),
+    ("memory", include_str!("../memory.rs")),
+    ("traits", include_str!("../traits.rs")),
+    ("errors", include_str!("../errors.rs")),
+    ("iterators", include_str!("../iterators.rs")),
+    ("concurrency", include_str!("../concurrency.rs")),
+    ("async_await", include_str!("../async_await.rs")),
+];
+
+static LESSONS: Lazy<Vec<Lesson>> = Lazy::new(|| {
+    SOURCES
+        .iter()
+        .map(|(module, source)| Lesson::parse(module, source))
+        .collect()
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lesson {
+    /// The name of the lesson's module, such as `fundamentals`.
+    pub module: String,
+    pub title: String,
+    /// The comment at the top of the lesson, in Markdown.
+    pub intro: String,
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// The name of the section's module, such as `variables`.
+    pub module: String,
+    pub title: String,
+    /// The section's doc comment, in Markdown.
+    pub doc: String,
+    pub exercises: Vec<Exercise>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exercise {
+    /// The name of the test, such as `immutable_variable`.
+    pub name: String,
+    /// The test's doc comment, if it has one, in Markdown.
+    pub doc: String,
+}
+
+/// The text of a comment line, if `line` is a comment starting with `marker`.
+fn comment<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    let text = line.trim_start().strip_prefix(marker)?;
+
+    // `///` is not a `//` comment for our purposes, and `//!` is not a doc comment.
+    if text.starts_with('/') || text.starts_with('!') {
+        return None;
+    }
+    Some(text.strip_prefix(' ').unwrap_or(text))
+}
+
+/// Splits a comment into its first line, which the course uses as a title, and the rest.
+fn title_and_body(lines: &[&str]) -> (Option<String>, String) {
+    match lines.split_first() {
+        Some((first, rest)) if !first.trim().is_empty() => (
+            Some(first.trim().to_string()),
+            rest.join("\n").trim().to_string(),
+        ),
+        _ => (None, lines.join("\n").trim().to_string()),
+    }
+}
+
+impl Lesson {
+    fn parse(module: &str, source: &str) -> Lesson {
+        let mut lines = source.lines().peekable();
+
+        // The introduction is the first `//` comment, before any `mod`.
+        let mut intro = Vec::new();
+        while let Some(line) = lines.peek() {
+            if line.starts_with("mod ") || line.starts_with("///") {
+                break;
+            }
+            if let Some(text) = comment(line, "//") {
+                intro.push(text);
+            } else if !intro.is_empty() && line.trim().is_empty() {
+                break;
+            }
+            lines.next();
+        }
+        let (title, intro) = title_and_body(&intro);
+
+        let mut sections: Vec<Section> = Vec::new();
+        let mut doc: Vec<&str> = Vec::new();
+        let mut in_test = false;
+        // Whether the lines just after a section's `mod` are still its leading comment.
+        let mut leading = false;
+
+        for line in lines {
+            let trimmed = line.trim();
+
+            if let Some(name) = line
+                .strip_prefix("mod ")
+                .and_then(|rest| rest.strip_suffix(" {"))
+            {
+                let (title, body) = title_and_body(&doc);
+                sections.push(Section {
+                    module: name.to_string(),
+                    title: title.unwrap_or_else(|| name.replace('_', " ").to_uppercase()),
+                    doc: body,
+                    exercises: Vec::new(),
+                });
+                doc.clear();
+                leading = true;
+                continue;
+            }
+
+            if let Some(text) = comment(line, "///") {
+                doc.push(text);
+                leading = false;
+            } else if let Some(text) = comment(line, "//").filter(|_| leading) {
+                // Sections without a doc comment may open with a plain comment instead.
+                if let Some(section) = sections.last_mut() {
+                    section.doc.push_str(text);
+                    section.doc.push('\n');
+                }
+            } else if trimmed == "#[test]" || trimmed == "#[tokio::test]" {
+                in_test = true;
+                leading = false;
+            } else if trimmed.starts_with("#[") {
+                // Other attributes may sit between a test's doc comment and its `fn`.
+            } else if let (true, Some(rest)) = (in_test, trimmed.split_once("fn ")) {
+                let name: String = rest
+                    .1
+                    .chars()
+                    .take_while(|c| c.is_alphanumeric() || *c == '_')
+                    .collect();
+                if let Some(section) = sections.last_mut() {
+                    section.exercises.push(Exercise {
+                        name,
+                        doc: doc.join("\n").trim().to_string(),
+                    });
+                }
+                doc.clear();
+                in_test = false;
+            } else {
+                doc.clear();
+                if !trimmed.is_empty() {
+                    leading = false;
+                }
+            }
+        }
+
+        Lesson {
+            module: module.to_string(),
+            title: title.unwrap_or_else(|| module.replace('_', " ").to_uppercase()),
+            intro,
+            sections,
+        }
+    }
+}
+
+fn markdown(text: &str) -> String {
+    let mut out = String::new();
+    html::push_html(&mut out, Parser::new(text));
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+  body {{ font-family: sans-serif; line-height: 1.5; max-width: 50em; margin: 2em auto; padding: 0 1em; }}
+  code, pre {{ background: #f6f6f6; }}
+  .exercise {{ border-left: 3px solid #ccc; padding-left: 1em; margin: 1em 0; }}
+</style>
+</head>
+<body>
+<nav><a href="/course">Course</a></nav>
+{body}
+</body>
+</html>
+"#,
+        title = escape(title),
+        body = body,
+    )
+}
+
+fn render_index() -> String {
+    let mut body = markdown(README);
+
+    body.push_str("<h2>Lessons</h2>\n<ol start=\"0\">\n");
+    for lesson in LESSONS.iter() {
+        body.push_str(&format!(
+            "<li><a href=\"/course/{}\">{}</a> ({} exercises)</li>\n",
+            lesson.module,
+            escape(&lesson.title),
+            lesson
+                .sections
+                .iter()
+                .map(|s| s.exercises.len())
+                .sum::<usize>()
+        ));
+    }
+    body.push_str("</ol>\n");
+
+    page("Learn the Rust Programming Language", &body)
+}
+
+fn render_lesson(lesson: &Lesson) -> String {
+    let mut body = format!(
+        "<h1>{}</h1>\n{}",
+        escape(&lesson.title),
+        markdown(&lesson.intro)
+    );
+
+    for section in &lesson.sections {
+        body.push_str(&format!(
+            "<h2 id=\"{module}\">{title}</h2>\n{doc}",
+            module = section.module,
+            title = escape(&section.title),
+            doc = markdown(&section.doc),
+        ));
+
+        if section.exercises.is_empty() {
+            continue;
+        }
+        body.push_str(&format!(
+            "<p>Run these exercises with <code>cargo test {}::{}</code>.</p>\n",
+            lesson.module, section.module
+        ));
+        for exercise in &section.exercises {
+            body.push_str(&format!(
+                "<div class=\"exercise\"><code>{}</code>\n{}</div>\n",
+                exercise.name,
+                markdown(&exercise.doc)
+            ));
+        }
+    }
+
+    page(&lesson.title, &body)
+}
+
+/// All the course routes, composed into a single filter.
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // GET /course
+    let index = warp::path!("course")
+        .and(warp::get())
+        .map(|| warp::reply::html(render_index()));
+
+    // GET /course/fundamentals
+    let lesson =
+        warp::path!("course" / String)
+            .and(warp::get())
+            .and_then(|module: String| async move {
+                let lesson = LESSONS
+                    .iter()
+                    .find(|lesson| lesson.module == module)
+                    .ok_or_else(|| {
+                        AppError::NotFound(format!("there is no lesson {:?}", module))
+                    })?;
+
+                Ok::<_, Rejection>(warp::reply::html(render_lesson(lesson)))
+            });
+
+    index.or(lesson)
+}
+
+/// Describes the course routes.
+pub fn openapi(document: &mut Document) {
+    let html = Some(("text/html", json!({ "type": "string" })));
+    let modules: Vec<&str> = SOURCES.iter().map(|(module, _)| *module).collect();
+
+    document.operation(
+        "get",
+        "/course",
+        Operation::new("course", "The course overview and its lessons").response(
+            200,
+            "An HTML page",
+            html.clone(),
+        ),
+    );
+    document.operation(
+        "get",
+        "/course/{lesson}",
+        Operation::new("course", "A lesson and its exercises")
+            .path(
+                "lesson",
+                json!({ "type": "string", "enum": modules }),
+                "The lesson's module",
+            )
+            .response(200, "An HTML page", html)
+            .problem(404, "There is no such lesson"),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use super::*;
+    use crate::server::error;
+
+    fn lesson(module: &str) -> &'static Lesson {
+        LESSONS.iter().find(|l| l.module == module).unwrap()
+    }
+
+    #[test]
+    fn lessons_are_split_into_sections_and_exercises() {
+        let fundamentals = lesson("fundamentals");
+        let variables = &fundamentals.sections[0];
+
+        assert_eq!(fundamentals.title, "00 - FUNDAMENTALS");
+        assert!(fundamentals.intro.starts_with("In this module"));
+        assert_eq!(variables.module, "variables");
+        assert_eq!(variables.title, "VARIABLES");
+        assert!(variables.doc.starts_with("A variable is a name"));
+        assert_eq!(
+            variables
+                .exercises
+                .iter()
+                .map(|e| e.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["immutable_variable", "mutable_variable"]
+        );
+    }
+
+    #[test]
+    fn exercises_keep_their_doc_comments() {
+        let raii = lesson("memory")
+            .sections
+            .iter()
+            .find(|s| s.module == "raii")
+            .unwrap();
+
+        assert_eq!(raii.title, "RAII");
+        assert!(raii.exercises[0].doc.starts_with("RAII stands for"));
+    }
+
+    #[test]
+    fn plain_comments_open_sections_without_doc_comments() {
+        let welcome = &lesson("welcome").sections[0];
+
+        assert!(welcome.doc.contains("Please join and say hello"));
+    }
+
+    #[tokio::test]
+    async fn lessons_are_served_as_html() {
+        let response = warp::test::request()
+            .path("/course/fundamentals")
+            .reply(&routes())
+            .await;
+        let body = String::from_utf8_lossy(response.body());
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body.contains("<h2 id=\"variables\">VARIABLES</h2>"));
+        assert!(body.contains("cargo test fundamentals::variables"));
+    }
+
+    #[tokio::test]
+    async fn unknown_lessons_are_not_found() {
+        let response = warp::test::request()
+            .path("/course/cobol")
+            .reply(&routes().recover(error::recover))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,1169 @@
+// DETECTIVES
+//
+// A registry of detectives, exposed as a CRUD resource under `/detectives`. The registry is
+// shared between request handlers exactly like the database in
+// `concurrency::sharing_data::mutable_share_rw`: an `Arc<RwLock<..>>` that many readers can
+// lock at once, but only one writer at a time. Every detective has a version, sent as an `ETag`,
+// so that clients can make conditional requests (see `conditional`). Every change is published
+// as an event, for clients following `/events`, before the write lock is released, so that the
+// events come in the same order as the changes. Reading needs the `Reader` role, changing needs
+// `Editor`, and deleting needs `Admin`.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::{self, Role};
+use super::conditional::{self, Tags};
+use super::error::AppError;
+use super::events::{self, Change};
+use super::idempotency::{self, Attempt};
+use super::json::{self, FieldError, Validate, Validator};
+use super::negotiate::{self, Format, Tabular};
+use super::openapi::{schema, Document, Operation};
+use super::page::{self, SortKey};
+use super::wal::{Wal, WalError};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Person {
+    pub name: String,
+    pub age: i32,
+    pub address: Address,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Address {
+    pub street: String,
+    pub city: String,
+}
+
+/// A partial update of a `Person`, as accepted by `PATCH /detectives/{id}`. Fields that are
+/// absent are left untouched.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct PersonPatch {
+    pub name: Option<String>,
+    pub age: Option<i32>,
+    pub address: Option<AddressPatch>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct AddressPatch {
+    pub street: Option<String>,
+    pub city: Option<String>,
+}
+
+impl Validate for Person {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        Validator::default()
+            .not_blank(&self.name, "name")
+            .check(valid_age(self.age), "age", AGE_MESSAGE)
+            .not_blank(&self.address.street, "address.street")
+            .not_blank(&self.address.city, "address.city")
+            .finish()
+    }
+}
+
+impl Validate for PersonPatch {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut validator = Validator::default();
+
+        if let Some(name) = &self.name {
+            validator.not_blank(name, "name");
+        }
+        if let Some(age) = self.age {
+            validator.check(valid_age(age), "age", AGE_MESSAGE);
+        }
+        if let Some(address) = &self.address {
+            if let Some(street) = &address.street {
+                validator.not_blank(street, "address.street");
+            }
+            if let Some(city) = &address.city {
+                validator.not_blank(city, "address.city");
+            }
+        }
+
+        validator.finish()
+    }
+}
+
+const AGE_MESSAGE: &str = "must be between 0 and 150";
+
+fn valid_age(age: i32) -> bool {
+    (0..=150).contains(&age)
+}
+
+impl Person {
+    fn apply(&mut self, patch: PersonPatch) {
+        if let Some(name) = patch.name {
+            self.name = name;
+        }
+        if let Some(age) = patch.age {
+            self.age = age;
+        }
+        if let Some(address) = patch.address {
+            if let Some(street) = address.street {
+                self.address.street = street;
+            }
+            if let Some(city) = address.city {
+                self.address.city = city;
+            }
+        }
+    }
+}
+
+/// The query accepted by `GET /detectives`, such as `?city=London&sort=-age&limit=10`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DetectiveQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    /// One of `id`, `name`, `age` or `city`, prefixed with `-` to sort in descending order.
+    pub sort: Option<String>,
+    /// Only detectives whose name contains this, ignoring case.
+    pub name: Option<String>,
+    /// Only detectives who live in this city, ignoring case.
+    pub city: Option<String>,
+    pub min_age: Option<i32>,
+    pub max_age: Option<i32>,
+}
+
+impl DetectiveQuery {
+    fn matches(&self, person: &Person) -> bool {
+        let name = self
+            .name
+            .as_ref()
+            .is_none_or(|name| person.name.to_lowercase().contains(&name.to_lowercase()));
+        let city = self
+            .city
+            .as_ref()
+            .is_none_or(|city| person.address.city.eq_ignore_ascii_case(city));
+        let age = self.min_age.is_none_or(|min| person.age >= min)
+            && self.max_age.is_none_or(|max| person.age <= max);
+
+        name && city && age
+    }
+
+    fn page(&self) -> page::Request {
+        page::Request {
+            limit: self.limit,
+            cursor: self.cursor.clone(),
+            sort: self.sort.clone(),
+        }
+    }
+}
+
+const SORTABLE: &[&str] = &["id", "name", "age", "city"];
+
+fn sort_key(detective: &Detective, field: &str) -> SortKey {
+    match field {
+        "name" => SortKey::Text(detective.person.name.clone()),
+        "age" => SortKey::Int(detective.person.age.into()),
+        "city" => SortKey::Text(detective.person.address.city.clone()),
+        _ => SortKey::Int(detective.id as i64),
+    }
+}
+
+/// A person stored in the registry, together with the identifier the registry assigned to them
+/// and the version of their details, which starts at 1 and goes up with every change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Detective {
+    pub id: u64,
+    pub version: u64,
+    #[serde(flatten)]
+    pub person: Person,
+}
+
+impl Tabular for Detective {
+    const COLUMNS: &'static [&'static str] = &["id", "version", "name", "age", "street", "city"];
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.version.to_string(),
+            self.person.name.clone(),
+            self.person.age.to_string(),
+            self.person.address.street.clone(),
+            self.person.address.city.clone(),
+        ]
+    }
+}
+
+/// The registry of detectives, keyed by identifier. A registry opened from a data directory
+/// logs every change before making it, so that it can be rebuilt when the server restarts.
+#[derive(Debug, Default)]
+pub struct Registry {
+    next_id: u64,
+    detectives: BTreeMap<u64, Detective>,
+    journal: Option<Wal<Mutation>>,
+}
+
+/// A change to the registry, as written to its log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Mutation {
+    Put {
+        detective: Detective,
+    },
+    Remove {
+        id: u64,
+    },
+    /// Changes made together, which are logged as one record so that they are replayed all
+    /// together or not at all.
+    Batch {
+        mutations: Vec<Mutation>,
+    },
+}
+
+/// The whole registry, as written to its snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    next_id: u64,
+    detectives: Vec<Detective>,
+}
+
+impl Registry {
+    /// A registry holding the detectives used throughout the course exercises.
+    pub fn seeded() -> Self {
+        let mut registry = Registry::default();
+
+        for person in seed() {
+            registry.apply(Mutation::Put {
+                detective: Detective {
+                    id: registry.next_id + 1,
+                    version: 1,
+                    person,
+                },
+            });
+        }
+
+        registry
+    }
+
+    /// Opens the registry stored in `dir`. A directory without one starts out seeded.
+    pub fn open(dir: &Path) -> Result<Self, WalError> {
+        let (journal, replay) = Wal::open(dir, "detectives")?;
+        let fresh = replay.is_empty();
+
+        let mut registry = match replay.snapshot {
+            Some(Snapshot {
+                next_id,
+                detectives,
+            }) => Registry {
+                next_id,
+                detectives: detectives.into_iter().map(|d| (d.id, d)).collect(),
+                journal: None,
+            },
+            None if fresh => Registry::seeded(),
+            None => Registry::default(),
+        };
+        for mutation in replay.entries {
+            registry.apply(mutation);
+        }
+        registry.journal = Some(journal);
+
+        // Fresh registries are compacted straight away, so that the seed is stored too.
+        if fresh || registry.journal.as_ref().is_some_and(Wal::should_compact) {
+            registry.compact()?;
+        }
+
+        Ok(registry)
+    }
+
+    /// The detectives that match the filters in `query`, in order of identifier.
+    pub fn search<'a>(&'a self, query: &'a DetectiveQuery) -> impl Iterator<Item = Detective> + 'a {
+        self.detectives
+            .values()
+            .filter(|detective| query.matches(&detective.person))
+            .cloned()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Detective> {
+        self.detectives.get(&id).cloned()
+    }
+
+    pub fn insert(&mut self, person: Person) -> Result<Detective, WalError> {
+        let detective = Detective {
+            id: self.next_id + 1,
+            version: 1,
+            person,
+        };
+
+        self.commit(Mutation::Put {
+            detective: detective.clone(),
+        })?;
+
+        Ok(detective)
+    }
+
+    pub fn replace(&mut self, id: u64, person: Person) -> Result<Option<Detective>, WalError> {
+        let detective = match self.detectives.get(&id) {
+            Some(existing) => Detective {
+                id,
+                version: existing.version + 1,
+                person,
+            },
+            None => return Ok(None),
+        };
+
+        self.commit(Mutation::Put {
+            detective: detective.clone(),
+        })?;
+
+        Ok(Some(detective))
+    }
+
+    pub fn patch(&mut self, id: u64, patch: PersonPatch) -> Result<Option<Detective>, WalError> {
+        let mut person = match self.detectives.get(&id) {
+            Some(existing) => existing.person.clone(),
+            None => return Ok(None),
+        };
+        person.apply(patch);
+
+        self.replace(id, person)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Result<Option<Detective>, WalError> {
+        let removed = self.detectives.get(&id).cloned();
+
+        if removed.is_some() {
+            self.commit(Mutation::Remove { id })?;
+        }
+        Ok(removed)
+    }
+
+    /// A copy of the registry that is not stored anywhere, for trying changes out on.
+    pub fn scratch(&self) -> Registry {
+        Registry {
+            next_id: self.next_id,
+            detectives: self.detectives.clone(),
+            journal: None,
+        }
+    }
+
+    /// Durably logs every difference between the registry and `scratch`, a scratch copy of it
+    /// that changes were made to, as a single record, but does not make them yet: `finish` does,
+    /// or takes them back out of the log if the rest of a batch could not be stored.
+    pub fn stage(&mut self, scratch: Registry) -> Result<Staged, WalError> {
+        let mut mutations: Vec<Mutation> = self
+            .detectives
+            .keys()
+            .filter(|id| !scratch.detectives.contains_key(id))
+            .map(|&id| Mutation::Remove { id })
+            .collect();
+        mutations.extend(
+            scratch
+                .detectives
+                .values()
+                .filter(|detective| self.detectives.get(&detective.id) != Some(detective))
+                .map(|detective| Mutation::Put {
+                    detective: detective.clone(),
+                }),
+        );
+
+        let logged = match &mut self.journal {
+            Some(journal) if !mutations.is_empty() => {
+                journal.append_durably(&Mutation::Batch { mutations })?;
+                true
+            }
+            _ => false,
+        };
+
+        Ok(Staged { scratch, logged })
+    }
+
+    /// Makes the changes in `staged` if the rest of their batch was `stored`, and takes them back
+    /// out of the log otherwise.
+    pub fn finish(&mut self, staged: Staged, stored: bool) {
+        if !stored {
+            if let Some(journal) = self.journal.as_mut().filter(|_| staged.logged) {
+                journal.retract();
+            }
+            return;
+        }
+
+        self.next_id = staged.scratch.next_id;
+        self.detectives = staged.scratch.detectives;
+        // The changes are stored already, so a compaction that fails can wait for the next one.
+        if self.journal.as_ref().is_some_and(Wal::should_compact) {
+            if let Err(e) = self.compact() {
+                tracing::error!("cannot compact the detectives: {}", e);
+            }
+        }
+    }
+
+    /// Makes sure every change made so far is durably stored.
+    pub fn flush(&self) -> Result<(), WalError> {
+        match &self.journal {
+            Some(journal) => journal.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs `mutation`, if the registry is stored, and then makes it.
+    fn commit(&mut self, mutation: Mutation) -> Result<(), WalError> {
+        if let Some(journal) = &mut self.journal {
+            journal.append(&mutation)?;
+        }
+        self.apply(mutation);
+
+        // The change is stored already, so a compaction that fails can wait for the next one.
+        if self.journal.as_ref().is_some_and(Wal::should_compact) {
+            if let Err(e) = self.compact() {
+                tracing::error!("cannot compact the detectives: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::Put { detective } => {
+                self.next_id = self.next_id.max(detective.id);
+                self.detectives.insert(detective.id, detective);
+            }
+            Mutation::Remove { id } => {
+                self.detectives.remove(&id);
+            }
+            Mutation::Batch { mutations } => {
+                for mutation in mutations {
+                    self.apply(mutation);
+                }
+            }
+        }
+    }
+
+    fn compact(&mut self) -> Result<(), WalError> {
+        let snapshot = Snapshot {
+            next_id: self.next_id,
+            detectives: self.detectives.values().cloned().collect(),
+        };
+
+        match &mut self.journal {
+            Some(journal) => journal.compact(&snapshot),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Changes that are logged but not yet made (see `Registry::stage`).
+#[derive(Debug)]
+pub struct Staged {
+    scratch: Registry,
+    logged: bool,
+}
+
+fn seed() -> Vec<Person> {
+    vec![
+        Person {
+            name: "Sherlock Holmes".to_string(),
+            age: 64,
+            address: Address {
+                street: "221B Baker Street".to_string(),
+                city: "London".to_string(),
+            },
+        },
+        Person {
+            name: "Hercule Poirot".to_string(),
+            age: 54,
+            address: Address {
+                street: "Whitehaven Mansions".to_string(),
+                city: "London".to_string(),
+            },
+        },
+    ]
+}
+
+pub type Store = Arc<RwLock<Registry>>;
+
+/// The resource named in events about detectives.
+pub const RESOURCE: &str = "detective";
+
+pub fn store(registry: Registry) -> Store {
+    Arc::new(RwLock::new(registry))
+}
+
+/// All the `/detectives` routes, composed into a single filter.
+pub fn routes(
+    store: Store,
+    events: events::Shared,
+    auth: auth::Shared,
+    idempotency: idempotency::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    list(store.clone(), auth.clone())
+        .or(get(store.clone(), auth.clone()))
+        .or(create(
+            store.clone(),
+            events.clone(),
+            auth.clone(),
+            idempotency,
+        ))
+        .or(replace(store.clone(), events.clone(), auth.clone()))
+        .or(patch(store.clone(), events.clone(), auth.clone()))
+        .or(delete(store, events, auth))
+}
+
+/// Describes the `/detectives` routes.
+pub fn openapi(document: &mut Document) {
+    let id = json!({ "type": "integer", "minimum": 1 });
+    let text = json!({ "type": "string" });
+    let address = json!({
+        "type": "object",
+        "required": ["street", "city"],
+        "properties": { "street": text, "city": text },
+    });
+    let person = json!({
+        "name": text,
+        "age": { "type": "integer", "minimum": 0, "maximum": 150 },
+        "address": address,
+    });
+
+    document.schema(
+        "Person",
+        json!({ "type": "object", "required": ["name", "age", "address"], "properties": person }),
+    );
+    document.schema(
+        "PersonPatch",
+        json!({
+            "type": "object",
+            "description": "Only the fields given are changed",
+            "properties": {
+                "name": text,
+                "age": person["age"],
+                "address": { "type": "object", "properties": { "street": text, "city": text } },
+            },
+        }),
+    );
+    document.schema(
+        "Detective",
+        json!({
+            "allOf": [
+                schema("Person"),
+                {
+                    "type": "object",
+                    "required": ["id", "version"],
+                    "properties": { "id": id, "version": { "type": "integer", "minimum": 1 } },
+                },
+            ],
+        }),
+    );
+    document.schema(
+        "DetectivePage",
+        json!({
+            "type": "object",
+            "properties": {
+                "items": { "type": "array", "items": schema("Detective") },
+                "next_cursor": text,
+            },
+        }),
+    );
+
+    let list = page::parameters(Operation::new("detectives", "List detectives"), SORTABLE)
+        .query(
+            "name",
+            text.clone(),
+            "Only names containing this, ignoring case",
+        )
+        .query("city", text.clone(), "Only detectives living in this city")
+        .query(
+            "min_age",
+            json!({ "type": "integer" }),
+            "The youngest age to list",
+        )
+        .query(
+            "max_age",
+            json!({ "type": "integer" }),
+            "The oldest age to list",
+        )
+        .json(200, "A page of detectives", "DetectivePage")
+        .role(Role::Reader);
+    document.operation("get", "/detectives", negotiate::formats(list, 200));
+    document.operation(
+        "post",
+        "/detectives",
+        idempotency::parameters(Operation::new("detectives", "Register a detective"))
+            .body("Person")
+            .json(201, "The registered detective", "Detective")
+            .role(Role::Editor),
+    );
+
+    let path = "/detectives/{id}";
+    let by_id = |summary| {
+        Operation::new("detectives", summary).path("id", id.clone(), "The detective's identifier")
+    };
+    let get = conditional::read_parameters(by_id("Get a detective"))
+        .json(200, "The detective", "Detective")
+        .problem(404, "There is no such detective")
+        .role(Role::Reader);
+    document.operation("get", path, negotiate::formats(get, 200));
+    document.operation(
+        "put",
+        path,
+        conditional::write_parameters(by_id("Replace a detective"))
+            .body("Person")
+            .json(200, "The updated detective", "Detective")
+            .problem(404, "There is no such detective")
+            .role(Role::Editor),
+    );
+    document.operation(
+        "patch",
+        path,
+        conditional::write_parameters(by_id("Change some of a detective's details"))
+            .body("PersonPatch")
+            .json(200, "The updated detective", "Detective")
+            .problem(404, "There is no such detective")
+            .role(Role::Editor),
+    );
+    document.operation(
+        "delete",
+        path,
+        conditional::write_parameters(by_id("Remove a detective"))
+            .response(204, "The detective was removed", None)
+            .problem(404, "There is no such detective")
+            .role(Role::Admin),
+    );
+}
+
+/// GET /detectives with an optional `DetectiveQuery`
+fn list(
+    store: Store,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("detectives")
+        .and(warp::get())
+        .and(auth::require(auth, Role::Reader))
+        .and(warp::query())
+        .and(negotiate::format())
+        .and(with_store(store))
+        .and_then(list_detectives)
+}
+
+/// GET /detectives/{id}
+fn get(
+    store: Store,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("detectives" / u64)
+        .and(warp::get())
+        .and(auth::require(auth, Role::Reader))
+        .and(conditional::if_none_match())
+        .and(negotiate::format())
+        .and(with_store(store))
+        .and_then(get_detective)
+}
+
+/// POST /detectives with a `Person` body
+fn create(
+    store: Store,
+    events: events::Shared,
+    auth: auth::Shared,
+    idempotency: idempotency::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("detectives")
+        .and(warp::post())
+        .and(auth::require(auth, Role::Editor))
+        .and(idempotency::body(idempotency))
+        .and(with_store(store))
+        .and(events::with_bus(events))
+        .and_then(|person, attempt: Attempt, store, events| {
+            attempt.run(create_detective(person, store, events))
+        })
+}
+
+/// PUT /detectives/{id} with a `Person` body
+fn replace(
+    store: Store,
+    events: events::Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("detectives" / u64)
+        .and(warp::put())
+        .and(auth::require(auth, Role::Editor))
+        .and(json::body())
+        .and(conditional::if_match())
+        .and(with_store(store))
+        .and(events::with_bus(events))
+        .and_then(replace_detective)
+}
+
+/// PATCH /detectives/{id} with a `PersonPatch` body
+fn patch(
+    store: Store,
+    events: events::Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("detectives" / u64)
+        .and(warp::patch())
+        .and(auth::require(auth, Role::Editor))
+        .and(json::body())
+        .and(conditional::if_match())
+        .and(with_store(store))
+        .and(events::with_bus(events))
+        .and_then(patch_detective)
+}
+
+/// DELETE /detectives/{id}
+fn delete(
+    store: Store,
+    events: events::Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("detectives" / u64)
+        .and(warp::delete())
+        .and(auth::require(auth, Role::Admin))
+        .and(conditional::if_match())
+        .and(with_store(store))
+        .and(events::with_bus(events))
+        .and_then(delete_detective)
+}
+
+fn with_store(store: Store) -> impl Filter<Extract = (Store,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+fn not_found(id: u64) -> AppError {
+    AppError::NotFound(format!("there is no detective with id {}", id))
+}
+
+async fn list_detectives(
+    query: DetectiveQuery,
+    format: Format,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let page = page::paginate(
+        store.read().unwrap().search(&query),
+        &query.page(),
+        SORTABLE,
+        sort_key,
+        |detective| detective.id as i64,
+    )?;
+
+    Ok(negotiate::page(format, &page))
+}
+
+async fn get_detective(
+    id: u64,
+    if_none_match: Option<Tags>,
+    format: Format,
+    store: Store,
+) -> Result<impl Reply, Rejection> {
+    let detective = store.read().unwrap().get(id).ok_or_else(|| not_found(id))?;
+
+    Ok(conditional::respond(
+        if_none_match.as_ref(),
+        detective.version,
+        format,
+        negotiate::one(format, &detective),
+    ))
+}
+
+/// Fails unless detective `id` exists and `if_match` allows changing it.
+fn check_version(registry: &Registry, id: u64, if_match: Option<&Tags>) -> Result<(), AppError> {
+    let current = registry.get(id).ok_or_else(|| not_found(id))?;
+
+    conditional::check(if_match, current.version)
+}
+
+/// A change to a single detective, checked the way the routes check it.
+#[derive(Debug, Clone)]
+pub enum Edit {
+    Register(Person),
+    Replace {
+        id: u64,
+        person: Person,
+        if_match: Option<Tags>,
+    },
+    Remove {
+        id: u64,
+        if_match: Option<Tags>,
+    },
+}
+
+/// Makes `edit` to `registry`, returning the detective it registered or changed, or the one it
+/// removed.
+pub fn edit(registry: &mut Registry, edit: Edit) -> Result<Detective, AppError> {
+    match edit {
+        Edit::Register(person) => Ok(registry.insert(person)?),
+        Edit::Replace {
+            id,
+            person,
+            if_match,
+        } => {
+            check_version(registry, id, if_match.as_ref())?;
+            registry.replace(id, person)?.ok_or_else(|| not_found(id))
+        }
+        Edit::Remove { id, if_match } => {
+            check_version(registry, id, if_match.as_ref())?;
+            registry.remove(id)?.ok_or_else(|| not_found(id))
+        }
+    }
+}
+
+async fn create_detective(
+    person: Person,
+    store: Store,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let mut registry = store.write().unwrap();
+
+    let detective = edit(&mut registry, Edit::Register(person))?;
+    events.publish(RESOURCE, Change::Created, &detective);
+    let location = format!("/detectives/{}", detective.id);
+
+    Ok(conditional::tagged(
+        warp::reply::with_header(
+            warp::reply::with_status(warp::reply::json(&detective), StatusCode::CREATED),
+            "location",
+            location,
+        ),
+        detective.version,
+    ))
+}
+
+async fn replace_detective(
+    id: u64,
+    person: Person,
+    if_match: Option<Tags>,
+    store: Store,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let mut registry = store.write().unwrap();
+
+    let detective = edit(
+        &mut registry,
+        Edit::Replace {
+            id,
+            person,
+            if_match,
+        },
+    )?;
+    events.publish(RESOURCE, Change::Updated, &detective);
+
+    Ok(conditional::tagged(
+        warp::reply::json(&detective),
+        detective.version,
+    ))
+}
+
+async fn patch_detective(
+    id: u64,
+    patch: PersonPatch,
+    if_match: Option<Tags>,
+    store: Store,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let mut registry = store.write().unwrap();
+
+    check_version(&registry, id, if_match.as_ref())?;
+    let detective = registry.patch(id, patch)?.ok_or_else(|| not_found(id))?;
+    events.publish(RESOURCE, Change::Updated, &detective);
+
+    Ok(conditional::tagged(
+        warp::reply::json(&detective),
+        detective.version,
+    ))
+}
+
+async fn delete_detective(
+    id: u64,
+    if_match: Option<Tags>,
+    store: Store,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let mut registry = store.write().unwrap();
+
+    edit(&mut registry, Edit::Remove { id, if_match })?;
+    events.publish(RESOURCE, Change::Deleted, &json!({ "id": id }));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::error;
+    use crate::server::idempotency::IdempotencyStore;
+    use crate::server::wal;
+
+    fn open() -> auth::Shared {
+        Arc::new(auth::Credentials::default())
+    }
+
+    fn watson() -> Person {
+        Person {
+            name: "John Watson".to_string(),
+            age: 58,
+            address: Address {
+                street: "221B Baker Street".to_string(),
+                city: "London".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn insert_assigns_increasing_ids() {
+        let mut registry = Registry::seeded();
+
+        let watson = registry.insert(watson()).unwrap();
+
+        assert_eq!(watson.id, 3);
+        assert_eq!(registry.search(&DetectiveQuery::default()).count(), 3);
+    }
+
+    #[test]
+    fn patch_only_touches_given_fields() {
+        let mut registry = Registry::seeded();
+
+        let patched = registry
+            .patch(
+                1,
+                PersonPatch {
+                    address: Some(AddressPatch {
+                        city: Some("New York".to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(patched.person.name, "Sherlock Holmes");
+        assert_eq!(patched.person.address.street, "221B Baker Street");
+        assert_eq!(patched.person.address.city, "New York");
+    }
+
+    #[test]
+    fn missing_detectives_are_not_updated() {
+        let mut registry = Registry::seeded();
+
+        assert_eq!(registry.replace(42, watson()).unwrap(), None);
+        assert_eq!(registry.patch(42, PersonPatch::default()).unwrap(), None);
+        assert_eq!(registry.remove(42).unwrap(), None);
+    }
+
+    #[test]
+    fn stored_registry_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut registry = Registry::open(dir.path()).unwrap();
+        let watson = registry.insert(watson()).unwrap();
+        registry.remove(1).unwrap();
+        drop(registry);
+
+        let mut reopened = Registry::open(dir.path()).unwrap();
+        let names: Vec<String> = reopened
+            .search(&DetectiveQuery::default())
+            .map(|d| d.person.name)
+            .collect();
+
+        assert_eq!(names, vec!["Hercule Poirot", "John Watson"]);
+        assert_eq!(reopened.get(watson.id).unwrap().person.age, 58);
+        assert_eq!(reopened.insert(watson.person).unwrap().id, 4);
+    }
+
+    #[test]
+    fn changes_are_kept_when_compaction_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = Registry::open(dir.path()).unwrap();
+        // A directory where the new snapshot should be written makes every compaction fail.
+        std::fs::create_dir(dir.path().join("detectives.snapshot.tmp")).unwrap();
+
+        for _ in 0..wal::COMPACT_AFTER + 1 {
+            registry.insert(watson()).unwrap();
+        }
+        drop(registry);
+        std::fs::remove_dir(dir.path().join("detectives.snapshot.tmp")).unwrap();
+
+        let reopened = Registry::open(dir.path()).unwrap();
+        assert_eq!(reopened.detectives.len(), 2 + wal::COMPACT_AFTER + 1);
+    }
+
+    #[tokio::test]
+    async fn create_then_get() {
+        let store = store(Registry::default());
+        let events = events::EventBus::new();
+        let api = routes(store, events.clone(), open(), IdempotencyStore::start());
+        let (_, mut published) = events.subscribe(None);
+
+        let created = warp::test::request()
+            .method("POST")
+            .path("/detectives")
+            .json(&watson())
+            .reply(&api)
+            .await;
+
+        assert_eq!(created.status(), StatusCode::CREATED);
+        assert_eq!(created.headers()["location"], "/detectives/1");
+
+        let fetched = warp::test::request()
+            .path("/detectives/1")
+            .reply(&api)
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(fetched.body()).unwrap();
+
+        assert_eq!(fetched.status(), StatusCode::OK);
+        assert_eq!(body["name"], "John Watson");
+        assert_eq!(body["address"]["city"], "London");
+
+        let published = published.try_recv().unwrap();
+        assert_eq!(published.kind, "detective.created");
+        assert_eq!(published.data["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn list_filters_sorts_and_pages() {
+        let mut registry = Registry::seeded();
+        registry.insert(watson()).unwrap();
+        let api = routes(
+            store(registry),
+            events::EventBus::new(),
+            open(),
+            IdempotencyStore::start(),
+        );
+
+        let first = warp::test::request()
+            .path("/detectives?city=london&sort=-age&limit=2")
+            .reply(&api)
+            .await;
+        let first: serde_json::Value = serde_json::from_slice(first.body()).unwrap();
+
+        assert_eq!(first["items"][0]["name"], "Sherlock Holmes");
+        assert_eq!(first["items"][1]["name"], "John Watson");
+
+        let second = warp::test::request()
+            .path(&format!(
+                "/detectives?city=london&sort=-age&limit=2&cursor={}",
+                first["next_cursor"].as_str().unwrap()
+            ))
+            .reply(&api)
+            .await;
+        let second: serde_json::Value = serde_json::from_slice(second.body()).unwrap();
+
+        assert_eq!(second["items"][0]["name"], "Hercule Poirot");
+        assert_eq!(second.get("next_cursor"), None);
+    }
+
+    #[tokio::test]
+    async fn deleting_requires_admin() {
+        let credentials = auth::Credentials {
+            tokens: BTreeMap::from([
+                ("editor".to_string(), Role::Editor),
+                ("admin".to_string(), Role::Admin),
+            ]),
+            ..Default::default()
+        };
+        let api = routes(
+            store(Registry::seeded()),
+            events::EventBus::new(),
+            Arc::new(credentials),
+            IdempotencyStore::start(),
+        )
+        .recover(error::recover);
+        let delete = |token: &str| {
+            warp::test::request()
+                .method("DELETE")
+                .path("/detectives/1")
+                .header("authorization", format!("Bearer {}", token))
+                .reply(&api)
+        };
+
+        assert_eq!(delete("editor").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(delete("admin").await.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn delete_missing_is_not_found() {
+        let api = routes(
+            store(Registry::seeded()),
+            events::EventBus::new(),
+            open(),
+            IdempotencyStore::start(),
+        )
+        .recover(error::recover);
+
+        let deleted = warp::test::request()
+            .method("DELETE")
+            .path("/detectives/42")
+            .reply(&api)
+            .await;
+
+        assert_eq!(deleted.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn changes_bump_the_version() {
+        let api = routes(
+            store(Registry::seeded()),
+            events::EventBus::new(),
+            open(),
+            IdempotencyStore::start(),
+        )
+        .recover(error::recover);
+        let patch = |etag: &'static str| {
+            warp::test::request()
+                .method("PATCH")
+                .path("/detectives/2")
+                .header("if-match", etag)
+                .json(&json!({ "age": 55 }))
+                .reply(&api)
+        };
+
+        let patched = patch("\"1\"").await;
+        let body: serde_json::Value = serde_json::from_slice(patched.body()).unwrap();
+
+        assert_eq!(patched.status(), StatusCode::OK);
+        assert_eq!(patched.headers()["etag"], "\"2\"");
+        assert_eq!(body["version"], 2);
+        assert_eq!(
+            patch("\"1\"").await.status(),
+            StatusCode::PRECONDITION_FAILED
+        );
+
+        let deleted = warp::test::request()
+            .method("DELETE")
+            .path("/detectives/2")
+            .header("if-match", "\"1\"")
+            .reply(&api)
+            .await;
+
+        assert_eq!(deleted.status(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn detectives_can_be_read_as_text() {
+        let api = routes(
+            store(Registry::seeded()),
+            events::EventBus::new(),
+            open(),
+            IdempotencyStore::start(),
+        )
+        .recover(error::recover);
+
+        let text = warp::test::request()
+            .path("/detectives/1")
+            .header("accept", "text/plain")
+            .reply(&api)
+            .await;
+        let png = warp::test::request()
+            .path("/detectives/1")
+            .header("accept", "image/png")
+            .reply(&api)
+            .await;
+
+        assert_eq!(text.status(), StatusCode::OK);
+        assert_eq!(
+            String::from_utf8_lossy(text.body()),
+            "id  version  name             age  street             city\n\
+             --  -------  ---------------  ---  -----------------  ------\n\
+             1   1        Sherlock Holmes  64   221B Baker Street  London\n"
+        );
+        assert_eq!(png.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+}
@@ -0,0 +1,337 @@
+// ERRORS
+//
+// The server's error model. Handlers return `Result<impl Reply, Rejection>` and use `?` on
+// anything that converts into an `AppError`, just like `errors::result::question_mark`
+// propagates a `Result<f64, &'static str>`. Every rejection, whether raised by a handler or by
+// Warp itself, is then rendered as an RFC 7807 `application/problem+json` body.
+
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+use warp::http::header::{HeaderName, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use super::calc::CalcError;
+use super::json::FieldError;
+use super::rate_limit::Limit;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+#[derive(Debug)]
+pub enum AppError {
+    /// The requested resource does not exist.
+    NotFound(String),
+    /// The request is well-formed, but some of its values are unacceptable.
+    Validation {
+        detail: String,
+        fields: Vec<FieldError>,
+    },
+    /// The request conflicts with the current state of a resource.
+    Conflict(String),
+    /// The resource has changed since the version the request's `If-Match` header names.
+    PreconditionFailed(String),
+    /// The request carries no credentials, or credentials that are not valid.
+    Unauthorized(String),
+    /// The credentials are valid, but do not allow what the request asks for.
+    Forbidden(String),
+    /// A calculation could not be carried out.
+    Calc(CalcError),
+    /// The request body was sent with a content type the server does not understand.
+    UnsupportedMediaType(String),
+    /// The request's `Accept` header allows none of the formats the server can answer in.
+    NotAcceptable(String),
+    /// The request's `Idempotency-Key` was already used for a different request.
+    IdempotencyKeyReused(String),
+    /// Part of the request was not carried out, because another part of it failed.
+    FailedDependency(String),
+    /// The request body is larger than the server accepts.
+    PayloadTooLarge(String),
+    /// The client has used up its rate limit, and must wait before trying again.
+    TooManyRequests { limit: Limit, retry_after: Duration },
+    /// The server is too busy to take on the request right now.
+    Unavailable(String),
+    /// Something went wrong on the server. The message is logged, but never shown to clients.
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Validation { .. } => StatusCode::BAD_REQUEST,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Calc(CalcError::InvalidNumber(_)) => StatusCode::BAD_REQUEST,
+            AppError::Calc(CalcError::DivisionByZero) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::IdempotencyKeyReused(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::FailedDependency(_) => StatusCode::FAILED_DEPENDENCY,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The problem details sent to clients for this error. Internal errors are not logged here,
+    /// so callers that render them without `render` must log them themselves.
+    pub fn problem(&self) -> Problem {
+        let status = self.status();
+
+        match self {
+            AppError::NotFound(detail) => Problem::new(status, "not-found", detail),
+            AppError::Validation { detail, fields } => {
+                let mut problem = Problem::new(status, "validation", detail);
+                problem.errors = fields.clone();
+                problem
+            }
+            AppError::Conflict(detail) => Problem::new(status, "conflict", detail),
+            AppError::PreconditionFailed(detail) => {
+                Problem::new(status, "precondition-failed", detail)
+            }
+            AppError::Unauthorized(detail) => {
+                let mut problem = Problem::new(status, "unauthorized", detail);
+                problem.headers = vec![(
+                    WWW_AUTHENTICATE,
+                    "Bearer, Basic realm=\"intro-rust\"".to_string(),
+                )];
+                problem
+            }
+            AppError::Forbidden(detail) => Problem::new(status, "forbidden", detail),
+            AppError::Calc(e @ CalcError::InvalidNumber(_)) => {
+                Problem::new(status, "invalid-number", &e.to_string())
+            }
+            AppError::Calc(e @ CalcError::DivisionByZero) => {
+                Problem::new(status, "division-by-zero", &e.to_string())
+            }
+            AppError::UnsupportedMediaType(content_type) => Problem::new(
+                status,
+                "unsupported-media-type",
+                &format!("expected an application/json body, not {}", content_type),
+            ),
+            AppError::NotAcceptable(accept) => Problem::new(
+                status,
+                "not-acceptable",
+                &format!(
+                    "expected to accept application/json, text/csv or text/plain, not {}",
+                    accept
+                ),
+            ),
+            AppError::IdempotencyKeyReused(detail) => {
+                Problem::new(status, "idempotency-key-reused", detail)
+            }
+            AppError::FailedDependency(detail) => Problem::new(status, "failed-dependency", detail),
+            AppError::PayloadTooLarge(detail) => Problem::new(status, "payload-too-large", detail),
+            AppError::TooManyRequests { limit, retry_after } => {
+                // Whole seconds, rounded up, so that clients never retry too early.
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                let mut problem = Problem::new(
+                    status,
+                    "too-many-requests",
+                    &format!("the limit is {}; try again in {} seconds", limit, seconds),
+                );
+                problem.headers = vec![
+                    (RETRY_AFTER, seconds.to_string()),
+                    (RATELIMIT_LIMIT, limit.requests.to_string()),
+                    (RATELIMIT_REMAINING, "0".to_string()),
+                    (RATELIMIT_RESET, seconds.to_string()),
+                ];
+                problem
+            }
+            AppError::Unavailable(detail) => Problem::new(status, "unavailable", detail),
+            AppError::Internal(_) => Problem::new(
+                status,
+                "internal",
+                "the server could not complete the request",
+            ),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound(detail)
+            | AppError::Validation { detail, .. }
+            | AppError::Conflict(detail)
+            | AppError::PreconditionFailed(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::Forbidden(detail)
+            | AppError::IdempotencyKeyReused(detail)
+            | AppError::FailedDependency(detail)
+            | AppError::PayloadTooLarge(detail)
+            | AppError::Unavailable(detail)
+            | AppError::Internal(detail) => write!(f, "{}", detail),
+            AppError::Calc(e) => write!(f, "{}", e),
+            AppError::UnsupportedMediaType(content_type) => {
+                write!(f, "unsupported media type {}", content_type)
+            }
+            AppError::NotAcceptable(accept) => write!(f, "cannot answer with any of {}", accept),
+            AppError::TooManyRequests { limit, .. } => {
+                write!(f, "rate limit of {} exceeded", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+// Rejection already implements From for every Reject, which is what lets handlers use `?`.
+impl warp::reject::Reject for AppError {}
+
+/// An RFC 7807 problem details object.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// The individual fields that failed validation, if any.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// The identifier of the request that failed, for matching the response up with the logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Extra headers to send along with the problem.
+    #[serde(skip)]
+    pub headers: Vec<(HeaderName, String)>,
+}
+
+impl Problem {
+    fn new(status: StatusCode, kind: &str, detail: &str) -> Self {
+        Problem {
+            type_uri: format!("/problems/{}", kind),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: detail.to_string(),
+            errors: Vec::new(),
+            request_id: None,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn into_response(self) -> warp::reply::Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        let mut response = warp::reply::with_header(
+            warp::reply::with_status(warp::reply::json(&self), status),
+            CONTENT_TYPE,
+            "application/problem+json",
+        )
+        .into_response();
+
+        for (name, value) in self.headers {
+            if let Ok(value) = value.parse() {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        response
+    }
+}
+
+/// Renders any rejection as a problem+json response. The server itself renders rejections in
+/// `request_log::logged`, so that they carry the request ID; this is for testing routes alone.
+#[cfg(test)]
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, std::convert::Infallible> {
+    Ok(render(&rejection, None))
+}
+
+/// Renders a rejection as a problem+json response for the request with the given identifier.
+pub fn render(rejection: &Rejection, request_id: Option<&str>) -> warp::reply::Response {
+    let mut problem = problem_for(rejection, request_id.unwrap_or("-"));
+
+    problem.request_id = request_id.map(str::to_string);
+    problem.into_response()
+}
+
+fn problem_for(rejection: &Rejection, request_id: &str) -> Problem {
+    if let Some(e) = rejection.find::<AppError>() {
+        if let AppError::Internal(message) = e {
+            tracing::error!(request_id, "internal error: {}", message);
+        }
+        e.problem()
+    } else if rejection.is_not_found() {
+        Problem::new(
+            StatusCode::NOT_FOUND,
+            "not-found",
+            "no route matches the request",
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        Problem::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload-too-large",
+            &e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        Problem::new(StatusCode::BAD_REQUEST, "invalid-query", &e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+        Problem::new(StatusCode::BAD_REQUEST, "invalid-header", &e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MissingHeader>() {
+        Problem::new(StatusCode::BAD_REQUEST, "missing-header", &e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        // Checked after the others, as Warp does: a route that matched the method but rejected
+        // the request for another reason says more than the routes that wanted another method.
+        Problem::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method-not-allowed",
+            &e.to_string(),
+        )
+    } else {
+        tracing::error!(request_id, "unhandled rejection: {:?}", rejection);
+        AppError::Internal(format!("{:?}", rejection)).problem()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::Filter;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn handler_errors_are_problem_json() {
+        let api = warp::path!("detectives" / u64)
+            .and_then(|id| async move {
+                Err::<String, Rejection>(
+                    AppError::NotFound(format!("there is no detective with id {}", id)).into(),
+                )
+            })
+            .recover(recover);
+
+        let response = warp::test::request()
+            .path("/detectives/42")
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(body["type"], "/problems/not-found");
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["detail"], "there is no detective with id 42");
+    }
+
+    #[test]
+    fn internal_errors_hide_their_message() {
+        let problem = problem_for(&AppError::Internal("disk on fire".to_string()).into(), "-");
+
+        assert_eq!(problem.status, 500);
+        assert_eq!(problem.detail, "the server could not complete the request");
+    }
+
+    #[test]
+    fn unmatched_routes_are_not_found() {
+        let problem = problem_for(&warp::reject::not_found(), "-");
+
+        assert_eq!(problem.status, 404);
+        assert_eq!(problem.title, "Not Found");
+    }
+}
@@ -0,0 +1,279 @@
+// EVENTS
+//
+// Live updates for dashboards, streamed as server-sent events from `/events`. Every change to a
+// detective or a user is published to an `EventBus`, which numbers it, keeps the most recent
+// ones in a bounded log, and broadcasts it to every open stream.
+//
+// A client that reconnects with a `Last-Event-ID` header first receives whatever it missed from
+// the log, then carries on with live events. A client that falls too far behind has its stream
+// closed, so that it reconnects and catches up the same way. Event ids start from the time the
+// server started, in microseconds, so they keep increasing across restarts. A client whose last
+// event is no longer in the log, because it was away too long or the server restarted since,
+// cannot catch up that way, so it is sent a `resync` event instead, telling it to reload
+// everything before carrying on.
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::{self, Role};
+use super::openapi::{Document, Operation};
+
+/// How many past events are kept for clients that reconnect.
+const LOG_LEN: usize = 256;
+
+/// How many events a slow stream may fall behind before it is closed.
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl Change {
+    fn as_str(self) -> &'static str {
+        match self {
+            Change::Created => "created",
+            Change::Updated => "updated",
+            Change::Deleted => "deleted",
+        }
+    }
+}
+
+/// A change to a resource, such as a `detective.updated` event carrying the updated detective.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: u64,
+    pub kind: String,
+    pub data: serde_json::Value,
+}
+
+impl Event {
+    fn to_sse(&self) -> warp::sse::Event {
+        warp::sse::Event::default()
+            .id(self.id.to_string())
+            .event(&self.kind)
+            .data(self.data.to_string())
+    }
+}
+
+#[derive(Debug)]
+struct Log {
+    last_id: u64,
+    events: VecDeque<Event>,
+}
+
+#[derive(Debug)]
+pub struct EventBus {
+    log: Mutex<Log>,
+    sender: broadcast::Sender<Event>,
+}
+
+pub type Shared = Arc<EventBus>;
+
+impl EventBus {
+    /// A bus whose events are numbered from the current time, in microseconds, so that they
+    /// come after those of any earlier run that published fewer than a million a second.
+    pub fn new() -> Shared {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64);
+
+        EventBus::starting_after(started)
+    }
+
+    fn starting_after(last_id: u64) -> Shared {
+        Arc::new(EventBus {
+            log: Mutex::new(Log {
+                last_id,
+                events: VecDeque::new(),
+            }),
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        })
+    }
+
+    /// Records that `resource` went through `change`, and sends it to every open stream.
+    pub fn publish(&self, resource: &str, change: Change, data: &impl Serialize) {
+        let mut log = self.log.lock().unwrap();
+
+        log.last_id += 1;
+        let event = Event {
+            id: log.last_id,
+            kind: format!("{}.{}", resource, change.as_str()),
+            data: serde_json::to_value(data).expect("events serialize"),
+        };
+
+        if log.events.len() == LOG_LEN {
+            log.events.pop_front();
+        }
+        log.events.push_back(event.clone());
+
+        // Sending only fails when nobody is listening, which is fine.
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to new events, returning the logged ones that come after `last_id`, or a
+    /// single `resync` event if some of those are no longer logged. Both happen under the same
+    /// lock, so nothing is missed or seen twice in between.
+    pub fn subscribe(&self, last_id: Option<u64>) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        let log = self.log.lock().unwrap();
+
+        let oldest = log.events.front().map_or(log.last_id + 1, |event| event.id);
+        let missed = match last_id {
+            None => Vec::new(),
+            Some(last_id) if last_id < oldest - 1 || last_id > log.last_id => vec![Event {
+                id: log.last_id,
+                kind: "resync".to_string(),
+                data: json!({}),
+            }],
+            Some(last_id) => log
+                .events
+                .iter()
+                .filter(|event| event.id > last_id)
+                .cloned()
+                .collect(),
+        };
+
+        (missed, self.sender.subscribe())
+    }
+}
+
+/// GET /events, optionally with a `Last-Event-ID` header
+pub fn routes(
+    bus: Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("events")
+        .and(warp::get())
+        .and(auth::require(auth, Role::Reader))
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(with_bus(bus))
+        .map(|last_id: Option<u64>, bus: Shared| {
+            let (missed, receiver) = bus.subscribe(last_id);
+
+            let live = BroadcastStream::new(receiver).map_while(Result::ok);
+            let events = tokio_stream::iter(missed)
+                .chain(live)
+                .map(|event| Ok::<_, Infallible>(event.to_sse()));
+
+            warp::sse::reply(warp::sse::keep_alive().stream(events))
+        })
+}
+
+/// Describes the events route.
+pub fn openapi(document: &mut Document) {
+    document.operation(
+        "get",
+        "/events",
+        Operation::new("events", "Follow changes to detectives and users")
+            .header(
+                "Last-Event-ID",
+                json!({ "type": "integer" }),
+                "The last event received, to resume after it",
+            )
+            .response(
+                200,
+                "Server-sent events named like `detective.created`, holding the resource as JSON, \
+                 or `resync` when the events after Last-Event-ID are gone and everything must be \
+                 reloaded",
+                Some(("text/event-stream", json!({ "type": "string" }))),
+            )
+            .role(Role::Reader),
+    );
+}
+
+pub fn with_bus(bus: Shared) -> impl Filter<Extract = (Shared,), Error = Infallible> + Clone {
+    warp::any().map(move || bus.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn reconnecting_replays_missed_events() {
+        let bus = EventBus::starting_after(0);
+
+        bus.publish("user", Change::Created, &json!({ "id": 4 }));
+        bus.publish("user", Change::Updated, &json!({ "id": 4 }));
+        bus.publish("user", Change::Deleted, &json!({ "id": 4 }));
+
+        let (missed, _) = bus.subscribe(Some(1));
+        let kinds: Vec<&str> = missed.iter().map(|e| e.kind.as_str()).collect();
+
+        assert_eq!(kinds, vec!["user.updated", "user.deleted"]);
+        assert_eq!(bus.subscribe(None).0, Vec::new());
+    }
+
+    #[test]
+    fn log_is_bounded() {
+        let bus = EventBus::starting_after(0);
+
+        for id in 0..LOG_LEN + 10 {
+            bus.publish("user", Change::Created, &json!({ "id": id }));
+        }
+
+        let (missed, _) = bus.subscribe(Some(10));
+
+        assert_eq!(missed.len(), LOG_LEN);
+        assert_eq!(missed[0].id, 11);
+    }
+
+    #[test]
+    fn ids_keep_increasing_across_restarts() {
+        let before = EventBus::new();
+        before.publish("user", Change::Created, &json!({ "id": 4 }));
+        let (_, mut receiver) = before.subscribe(None);
+        before.publish("user", Change::Updated, &json!({ "id": 4 }));
+        let last_id = receiver.try_recv().unwrap().id;
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let after = EventBus::new();
+        let (_, mut receiver) = after.subscribe(None);
+        after.publish("user", Change::Deleted, &json!({ "id": 4 }));
+
+        assert!(receiver.try_recv().unwrap().id > last_id);
+    }
+
+    #[test]
+    fn clients_that_cannot_catch_up_are_told_to_resync() {
+        let bus = EventBus::starting_after(0);
+        for id in 0..LOG_LEN + 10 {
+            bus.publish("user", Change::Created, &json!({ "id": id }));
+        }
+        let restarted = EventBus::starting_after(1000);
+
+        let (too_old, _) = bus.subscribe(Some(9));
+        let (from_before, _) = restarted.subscribe(Some(266));
+        let (from_later, _) = bus.subscribe(Some(1000));
+
+        for missed in [too_old, from_before, from_later] {
+            let kinds: Vec<&str> = missed.iter().map(|e| e.kind.as_str()).collect();
+            assert_eq!(kinds, vec!["resync"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_live_events() {
+        let bus = EventBus::new();
+        let (_, mut receiver) = bus.subscribe(None);
+
+        bus.publish("detective", Change::Created, &json!({ "id": 3 }));
+
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.kind, "detective.created");
+        assert_eq!(event.data, json!({ "id": 3 }));
+    }
+}
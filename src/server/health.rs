@@ -0,0 +1,119 @@
+// HEALTH
+//
+// Probes for whatever is supervising the server. `/healthz` answers as long as the process is
+// serving requests at all, while `/readyz` only reports ready once startup has finished, and
+// stops doing so as soon as shutdown begins, so that traffic can be routed elsewhere.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use super::openapi::{Document, Operation};
+
+/// Whether the server is ready to take traffic. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn set_ready(&self, ready: bool) {
+        self.0.store(ready, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    status: &'static str,
+}
+
+/// All the health routes, composed into a single filter.
+pub fn routes(
+    readiness: Readiness,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    healthz().or(readyz(readiness))
+}
+
+/// Describes the health routes.
+pub fn openapi(document: &mut Document) {
+    document.schema(
+        "Status",
+        json!({ "type": "object", "properties": { "status": { "type": "string" } } }),
+    );
+    document.operation(
+        "get",
+        "/healthz",
+        Operation::new("monitoring", "Whether the server is up").json(
+            200,
+            "The server is up",
+            "Status",
+        ),
+    );
+    document.operation(
+        "get",
+        "/readyz",
+        Operation::new("monitoring", "Whether the server is ready to take traffic")
+            .json(200, "The server is ready", "Status")
+            .json(503, "The server is starting up or shutting down", "Status"),
+    );
+}
+
+/// GET /healthz
+fn healthz() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&Status { status: "ok" }))
+}
+
+/// GET /readyz
+fn readyz(readiness: Readiness) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("readyz").and(warp::get()).map(move || {
+        if readiness.is_ready() {
+            warp::reply::with_status(
+                warp::reply::json(&Status { status: "ready" }),
+                StatusCode::OK,
+            )
+        } else {
+            warp::reply::with_status(
+                warp::reply::json(&Status {
+                    status: "not ready",
+                }),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn healthz_is_always_ok() {
+        let response = warp::test::request()
+            .path("/healthz")
+            .reply(&routes(Readiness::default()))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn readyz_follows_readiness() {
+        let readiness = Readiness::default();
+        let api = routes(readiness.clone());
+
+        let before = warp::test::request().path("/readyz").reply(&api).await;
+        readiness.set_ready(true);
+        let after = warp::test::request().path("/readyz").reply(&api).await;
+
+        assert_eq!(before.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(after.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,625 @@
+// IDEMPOTENCY
+//
+// Safe retries for `POST`. A client that sends an `Idempotency-Key` header with a request can
+// send the very same request again, say after a timeout, without creating a second detective,
+// user or job: the first response is stored under the key and replayed, marked with
+// `Idempotent-Replayed: true`, for as long as the key lives. Reusing a key for a different
+// request is a client bug, and gets `422 Unprocessable Entity`. A retry that arrives while the
+// first request is still being handled gets `409 Conflict`, and can simply be retried later.
+//
+// Only successful responses are stored. A request that is rejected, for instance because its
+// body is invalid, leaves its key free to be used again. Keys are scoped by the request's
+// credentials, so that clients can never see each other's responses.
+//
+// At most `MAX_ENTRIES` keys and `MAX_BYTES` of response bodies are kept. Keys live for the same
+// time, so the oldest is always the first to expire, and it is the one forgotten to make room.
+// A timer started along with the store forgets expired keys every `EXPIRE_EVERY`, oldest first,
+// stopping at the first that is still alive, so no request ever waits on a walk over every key.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use warp::http::{HeaderMap, HeaderValue, StatusCode};
+use warp::hyper::body::{self, Bytes};
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use super::error::AppError;
+use super::json::{self, FieldError, Validate};
+use super::openapi::Operation;
+
+/// How long a stored response is replayed for.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_KEY_LEN: usize = 255;
+
+/// The most keys kept at once.
+const MAX_ENTRIES: usize = 10_000;
+
+/// The most bytes of response bodies kept at once.
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// How often expired keys are forgotten.
+const EXPIRE_EVERY: Duration = Duration::from_secs(60);
+
+/// The header marking a response as a replay of the one sent the first time.
+pub const REPLAYED: &str = "idempotent-replayed";
+
+/// A response, kept so that it can be sent again.
+#[derive(Debug, Clone)]
+pub struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Stored {
+    fn replay(self) -> Response {
+        let mut response = Response::new(self.body.into());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+            .headers_mut()
+            .insert(REPLAYED, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+#[derive(Debug)]
+enum Slot {
+    InFlight,
+    Done(Stored),
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// A hash of the request, to tell a retry from a different request under the same key.
+    fingerprint: u64,
+    slot: Slot,
+    expires: Instant,
+    /// Tells this entry from later ones under the same key, in `Entries::oldest`.
+    claim: u64,
+}
+
+impl Entry {
+    fn bytes(&self) -> usize {
+        match &self.slot {
+            Slot::InFlight => 0,
+            Slot::Done(stored) => stored.body.len(),
+        }
+    }
+}
+
+/// Where keys are scoped: a hash of the credentials they were sent with, and the key itself.
+type Scoped = (u64, String);
+
+/// The entries, and the order they were claimed in.
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<Scoped, Entry>,
+    /// Every claim, oldest first. Entries that were released or replaced leave theirs behind,
+    /// and it is skipped when it comes up.
+    oldest: VecDeque<(Scoped, u64)>,
+    claims: u64,
+    /// The size of every stored body.
+    bytes: usize,
+}
+
+impl Entries {
+    /// Finds the entry for `key`, unless it has expired.
+    fn live(&self, key: &Scoped, now: Instant) -> Option<&Entry> {
+        self.by_key.get(key).filter(|entry| entry.expires > now)
+    }
+
+    fn claim(&mut self, key: Scoped, fingerprint: u64, expires: Instant) -> u64 {
+        while self.by_key.len() >= MAX_ENTRIES {
+            self.forget_oldest();
+        }
+        self.claims += 1;
+        self.oldest.push_back((key.clone(), self.claims));
+
+        let entry = Entry {
+            fingerprint,
+            slot: Slot::InFlight,
+            expires,
+            claim: self.claims,
+        };
+        if let Some(replaced) = self.by_key.insert(key, entry) {
+            self.bytes -= replaced.bytes();
+        }
+        self.claims
+    }
+
+    /// Stores the response for the entry claimed as `claim`, unless it was forgotten already.
+    fn store(&mut self, key: &Scoped, claim: u64, stored: Stored) -> bool {
+        let Some(entry) = self
+            .by_key
+            .get_mut(key)
+            .filter(|entry| entry.claim == claim)
+        else {
+            return false;
+        };
+        self.bytes += stored.body.len();
+        entry.slot = Slot::Done(stored);
+
+        while self.bytes > MAX_BYTES {
+            self.forget_oldest();
+        }
+        true
+    }
+
+    /// Releases the entry claimed as `claim`, if it is still in flight.
+    fn release(&mut self, key: &Scoped, claim: u64) {
+        if matches!(
+            self.by_key.get(key),
+            Some(entry) if entry.claim == claim && matches!(entry.slot, Slot::InFlight)
+        ) {
+            self.by_key.remove(key);
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        while let Some((key, claim)) = self.oldest.pop_front() {
+            if self
+                .by_key
+                .get(&key)
+                .is_some_and(|entry| entry.claim == claim)
+            {
+                let entry = self.by_key.remove(&key).unwrap();
+                self.bytes -= entry.bytes();
+                return;
+            }
+        }
+    }
+
+    /// Forgets the entries that have expired by `now`.
+    fn expire(&mut self, now: Instant) {
+        while let Some((key, claim)) = self.oldest.front() {
+            match self.by_key.get(key) {
+                Some(entry) if entry.claim == *claim => {
+                    if entry.expires > now {
+                        return;
+                    }
+                    self.forget_oldest();
+                }
+                _ => {
+                    self.oldest.pop_front();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+pub type Shared = Arc<IdempotencyStore>;
+
+impl IdempotencyStore {
+    /// Makes a store, and starts the timer that expires its keys, which stops along with it.
+    pub fn start() -> Shared {
+        IdempotencyStore::with_ttl(TTL)
+    }
+
+    fn with_ttl(ttl: Duration) -> Shared {
+        let store = Arc::new(IdempotencyStore {
+            ttl,
+            entries: Mutex::new(Entries::default()),
+        });
+
+        tokio::spawn(expire_every(Arc::downgrade(&store), EXPIRE_EVERY));
+        store
+    }
+
+    /// Looks up `key` for a request with `fingerprint`, claiming it if it is new.
+    fn begin(store: &Shared, key: Scoped, fingerprint: u64) -> Result<Attempt, AppError> {
+        let mut entries = store.entries.lock().unwrap();
+        let now = Instant::now();
+
+        match entries.live(&key, now) {
+            Some(entry) if entry.fingerprint != fingerprint => {
+                Err(AppError::IdempotencyKeyReused(format!(
+                    "the idempotency key {:?} was already used for a different request",
+                    key.1
+                )))
+            }
+            Some(Entry {
+                slot: Slot::InFlight,
+                ..
+            }) => Err(AppError::Conflict(format!(
+                "a request with the idempotency key {:?} is still in progress",
+                key.1
+            ))),
+            Some(Entry {
+                slot: Slot::Done(stored),
+                ..
+            }) => Ok(Attempt::Replay(stored.clone())),
+            None => {
+                let claim = entries.claim(key.clone(), fingerprint, now + store.ttl);
+
+                Ok(Attempt::Fresh(Pending {
+                    store: store.clone(),
+                    key,
+                    claim,
+                    done: false,
+                }))
+            }
+        }
+    }
+}
+
+/// Expires the store's keys every `period`, for as long as the store is in use.
+async fn expire_every(store: Weak<IdempotencyStore>, period: Duration) {
+    let mut ticks = tokio::time::interval(period);
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        match store.upgrade() {
+            Some(store) => store.entries.lock().unwrap().expire(Instant::now()),
+            None => return,
+        }
+    }
+}
+
+/// What to do with a request, depending on its `Idempotency-Key`.
+#[derive(Debug)]
+pub enum Attempt {
+    /// The request has no key, so it is simply handled.
+    Unkeyed,
+    /// The key is new: handle the request and store its response.
+    Fresh(Pending),
+    /// The key was seen before with the same request: send the same response again.
+    Replay(Stored),
+}
+
+impl Attempt {
+    /// Runs `handler` unless the request is a replay, storing its response if it is fresh. The
+    /// handler is a future, which does nothing until it is awaited, so a replay never runs it.
+    pub async fn run<R: Reply>(
+        self,
+        handler: impl Future<Output = Result<R, Rejection>>,
+    ) -> Result<Response, Rejection> {
+        match self {
+            Attempt::Unkeyed => Ok(handler.await?.into_response()),
+            Attempt::Replay(stored) => Ok(stored.replay()),
+            Attempt::Fresh(pending) => {
+                let response = handler.await?.into_response();
+                pending.complete(response).await
+            }
+        }
+    }
+}
+
+/// A claimed key whose response is not known yet. If it is dropped before it completes, because
+/// the request was rejected or abandoned, the key is released again.
+#[derive(Debug)]
+pub struct Pending {
+    store: Shared,
+    key: Scoped,
+    claim: u64,
+    done: bool,
+}
+
+impl Pending {
+    async fn complete(mut self, response: Response) -> Result<Response, Rejection> {
+        let (parts, body) = response.into_parts();
+        // On failure, there is nothing to store, so the key is released on drop.
+        let body = body::to_bytes(body)
+            .await
+            .map_err(|e| AppError::Internal(format!("cannot read response body: {}", e)))?;
+        let stored = Stored {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        };
+
+        let mut entries = self.store.entries.lock().unwrap();
+        self.done = entries.store(&self.key, self.claim, stored);
+        drop(entries);
+
+        Ok(Response::from_parts(parts, body.into()))
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.done {
+            let mut entries = self.store.entries.lock().unwrap();
+            entries.release(&self.key, self.claim);
+        }
+    }
+}
+
+/// Extracts a validated `T` from a JSON request body, like `json::body`, together with what to
+/// do about the request's `Idempotency-Key`.
+pub fn body<T>(store: Shared) -> impl Filter<Extract = (T, Attempt), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    body_up_to(store, json::MAX_BODY_BYTES)
+}
+
+/// Like `body`, for routes that accept bodies of up to `max_bytes`.
+pub fn body_up_to<T>(
+    store: Shared,
+    max_bytes: u64,
+) -> impl Filter<Extract = (T, Attempt), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    warp::header::optional::<String>("idempotency-key")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::path::full())
+        .and(json::raw_up_to(max_bytes))
+        .and(with_store(store))
+        .and_then(
+            |key: Option<String>,
+             authorization: Option<String>,
+             path: FullPath,
+             content_type: Option<String>,
+             bytes: Bytes,
+             store: Shared| async move {
+                let value: T = json::decode(content_type.as_deref(), &bytes)?;
+                let Some(key) = key else {
+                    return Ok((value, Attempt::Unkeyed));
+                };
+                check_key(&key)?;
+
+                let scope = hash(&authorization);
+                let fingerprint = hash(&(path.as_str(), content_type, &bytes[..]));
+                let attempt = IdempotencyStore::begin(&store, (scope, key), fingerprint)?;
+
+                Ok::<_, Rejection>((value, attempt))
+            },
+        )
+        .untuple_one()
+}
+
+fn check_key(key: &str) -> Result<(), AppError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(AppError::Validation {
+            detail: "the Idempotency-Key header is invalid".to_string(),
+            fields: vec![FieldError {
+                field: "Idempotency-Key".to_string(),
+                message: format!("must be between 1 and {} characters", MAX_KEY_LEN),
+            }],
+        });
+    }
+    Ok(())
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn with_store(store: Shared) -> impl Filter<Extract = (Shared,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+/// Documents the `Idempotency-Key` header of an operation that creates a resource.
+pub fn parameters(operation: Operation) -> Operation {
+    operation
+        .header(
+            "Idempotency-Key",
+            json!({ "type": "string", "maxLength": MAX_KEY_LEN }),
+            "Send the same key to retry the request safely; the first response is replayed",
+        )
+        .problem(
+            409,
+            "A request with this idempotency key is still in progress",
+        )
+        .problem(
+            422,
+            "The idempotency key was already used for a different request",
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::server::error;
+    use crate::server::json::Validator;
+
+    #[derive(Debug, Deserialize)]
+    struct Order {
+        item: String,
+    }
+
+    impl Validate for Order {
+        fn validate(&self) -> Result<(), Vec<FieldError>> {
+            Validator::default().not_blank(&self.item, "item").finish()
+        }
+    }
+
+    /// A route that numbers the orders it takes, so that duplicates show.
+    fn api(
+        store: Shared,
+        delay: Duration,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+        let taken = Arc::new(Mutex::new(0));
+
+        warp::path!("orders")
+            .and(body::<Order>(store))
+            .and_then(move |order: Order, attempt: Attempt| {
+                let taken = taken.clone();
+
+                attempt.run(async move {
+                    tokio::time::sleep(delay).await;
+                    if order.item == "contraband" {
+                        return Err(AppError::Forbidden("not for sale".to_string()).into());
+                    }
+                    let mut taken = taken.lock().unwrap();
+                    *taken += 1;
+
+                    Ok::<_, Rejection>(warp::reply::with_status(
+                        format!("order {} for {}", taken, order.item),
+                        StatusCode::CREATED,
+                    ))
+                })
+            })
+            .recover(error::recover)
+    }
+
+    fn order(key: &str, item: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path("/orders")
+            .header("idempotency-key", key)
+            .json(&json!({ "item": item }))
+    }
+
+    #[tokio::test]
+    async fn retries_replay_the_first_response() {
+        let api = api(IdempotencyStore::start(), Duration::ZERO);
+
+        let first = order("k1", "pipe").reply(&api).await;
+        let retry = order("k1", "pipe").reply(&api).await;
+        let other = order("k2", "pipe").reply(&api).await;
+
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(first.body(), "order 1 for pipe");
+        assert!(!first.headers().contains_key(REPLAYED));
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.body(), "order 1 for pipe");
+        assert_eq!(retry.headers()[REPLAYED], "true");
+        assert_eq!(other.body(), "order 2 for pipe");
+    }
+
+    #[tokio::test]
+    async fn reusing_a_key_for_another_request_is_refused() {
+        let api = api(IdempotencyStore::start(), Duration::ZERO);
+
+        order("k1", "pipe").reply(&api).await;
+        let reused = order("k1", "violin").reply(&api).await;
+        let body: serde_json::Value = serde_json::from_slice(reused.body()).unwrap();
+
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["type"], "/problems/idempotency-key-reused");
+    }
+
+    #[tokio::test]
+    async fn keys_are_scoped_by_credentials() {
+        let api = api(IdempotencyStore::start(), Duration::ZERO);
+
+        let holmes = order("k1", "pipe")
+            .header("authorization", "Bearer holmes")
+            .reply(&api)
+            .await;
+        let watson = order("k1", "pipe")
+            .header("authorization", "Bearer watson")
+            .reply(&api)
+            .await;
+
+        assert_eq!(holmes.body(), "order 1 for pipe");
+        assert_eq!(watson.body(), "order 2 for pipe");
+    }
+
+    #[tokio::test]
+    async fn concurrent_retries_conflict() {
+        let api = api(IdempotencyStore::start(), Duration::from_millis(100));
+
+        let (first, retry) = tokio::join!(order("k1", "pipe").reply(&api), async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            order("k1", "pipe").reply(&api).await
+        });
+
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_free_their_key() {
+        let store = IdempotencyStore::start();
+        let api = api(store.clone(), Duration::ZERO);
+
+        let invalid = order("k1", " ").reply(&api).await;
+        let refused = order("k2", "contraband").reply(&api).await;
+        let retried = order("k2", "contraband").reply(&api).await;
+
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+        // Handled again rather than replayed, or turned away as still in progress.
+        assert_eq!(retried.status(), StatusCode::FORBIDDEN);
+        assert!(store.entries.lock().unwrap().by_key.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stored_responses_expire() {
+        let api = api(
+            IdempotencyStore::with_ttl(Duration::from_millis(10)),
+            Duration::ZERO,
+        );
+
+        order("k1", "pipe").reply(&api).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let later = order("k1", "violin").reply(&api).await;
+
+        assert_eq!(later.status(), StatusCode::CREATED);
+        assert_eq!(later.body(), "order 2 for violin");
+    }
+
+    fn stored(bytes: usize) -> Stored {
+        Stored {
+            status: StatusCode::CREATED,
+            headers: HeaderMap::new(),
+            body: Bytes::from(vec![b'x'; bytes]),
+        }
+    }
+
+    #[test]
+    fn the_oldest_keys_make_room() {
+        let mut entries = Entries::default();
+        let now = Instant::now();
+        let key = |n: usize| (0, n.to_string());
+
+        for n in 0..MAX_ENTRIES {
+            entries.claim(key(n), 0, now);
+        }
+        entries.claim(key(MAX_ENTRIES), 0, now);
+
+        assert_eq!(entries.by_key.len(), MAX_ENTRIES);
+        assert!(!entries.by_key.contains_key(&key(0)));
+        assert!(entries.by_key.contains_key(&key(MAX_ENTRIES)));
+
+        let big = entries.claim(key(1_000_000), 0, now);
+        assert!(entries.store(&key(1_000_000), big, stored(MAX_BYTES)));
+        let bigger = entries.claim(key(2_000_000), 0, now);
+        assert!(entries.store(&key(2_000_000), bigger, stored(1)));
+
+        assert!(!entries.by_key.contains_key(&key(1_000_000)));
+        assert_eq!(entries.bytes, 1);
+    }
+
+    #[test]
+    fn expiring_stops_at_the_first_live_key() {
+        let mut entries = Entries::default();
+        let now = Instant::now();
+
+        let released = entries.claim((0, "released".to_string()), 0, now);
+        entries.release(&(0, "released".to_string()), released);
+        let old = entries.claim((0, "old".to_string()), 0, now);
+        entries.store(&(0, "old".to_string()), old, stored(10));
+        entries.claim((0, "new".to_string()), 0, now + Duration::from_secs(60));
+        entries.expire(now + Duration::from_secs(1));
+
+        assert_eq!(entries.by_key.len(), 1);
+        assert_eq!(entries.oldest.len(), 1);
+        assert_eq!(entries.bytes, 0);
+    }
+}
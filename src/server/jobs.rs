@@ -0,0 +1,876 @@
+// JOBS
+//
+// Work that takes too long to do while the client waits. `POST /jobs` puts a job on a bounded
+// queue and answers `202 Accepted` straight away, and a fixed number of worker tasks take jobs
+// off the queue and run them, each spawned and awaited just like the futures in
+// `async_await::futures`. Clients follow a job's progress at `GET /jobs/{id}`, from `queued` to
+// `running` and then `succeeded` or `failed`, and can cancel it with `DELETE /jobs/{id}` as long
+// as it has not finished.
+//
+// A job that fails in a way that might not happen again is queued once more after a delay that
+// doubles with every attempt, until it runs out of attempts. Jobs waiting out that delay still
+// count as queued, so when the queue is full, whether with new jobs or retries, new jobs are
+// turned away with `503 Service Unavailable` rather than piling up without bound.
+//
+// Jobs are only kept in memory, so they are forgotten when the server stops. When it is told to
+// stop, the queue takes no more jobs and cancels the ones that have not started, then gives the
+// running ones until the drain timeout to finish before cancelling them too.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::{self, Role};
+use super::calc;
+use super::error::AppError;
+use super::idempotency::{self, Attempt};
+use super::json::{FieldError, Validate, Validator};
+use super::openapi::{schema, Document, Operation};
+
+/// How many jobs may wait in the queue before new ones are turned away.
+const QUEUE_CAPACITY: usize = 100;
+
+/// How many finished jobs are remembered, so that clients have time to collect their results.
+const RETAINED: usize = 1000;
+
+/// How long to wait before the first retry. Each retry after that waits twice as long.
+const BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+const DEFAULT_ATTEMPTS: u32 = 3;
+const MAX_ATTEMPTS: u32 = 10;
+const MAX_SLEEP_MILLIS: u64 = 60_000;
+
+/// What a job does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Work {
+    /// Divides two whole numbers, as `POST /calc` does.
+    Divide {
+        numerator: String,
+        denominator: String,
+    },
+    /// Waits for a while, standing in for slow work such as sending an email.
+    Sleep { millis: u64 },
+    /// Fails its first `failures` attempts, to see retries at work.
+    Flaky { failures: u32 },
+}
+
+/// Why an attempt at a job failed, and whether trying again could help.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Failure {
+    reason: String,
+    retry: bool,
+}
+
+impl Work {
+    async fn run(&self, attempt: u32) -> Result<Value, Failure> {
+        match self {
+            Work::Divide {
+                numerator,
+                denominator,
+            } => {
+                // The same numbers will never divide any better, so there is no point retrying.
+                let result =
+                    calc::decode_and_then_divide(numerator, denominator).map_err(|e| Failure {
+                        reason: e.to_string(),
+                        retry: false,
+                    })?;
+
+                Ok(json!(calc::Quotient {
+                    numerator: numerator.clone(),
+                    denominator: denominator.clone(),
+                    result,
+                }))
+            }
+            Work::Sleep { millis } => {
+                tokio::time::sleep(Duration::from_millis(*millis)).await;
+                Ok(json!({ "slept_millis": millis }))
+            }
+            Work::Flaky { failures } if attempt <= *failures => Err(Failure {
+                reason: format!("attempt {} failed, as asked", attempt),
+                retry: true,
+            }),
+            Work::Flaky { .. } => Ok(json!({ "succeeded_on_attempt": attempt })),
+        }
+    }
+}
+
+/// The body accepted by `POST /jobs`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct NewJob {
+    #[serde(flatten)]
+    pub work: Work,
+    /// How many times to try the job before giving up on it.
+    pub max_attempts: Option<u32>,
+}
+
+impl Validate for NewJob {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut validator = Validator::default();
+
+        if let Some(attempts) = self.max_attempts {
+            validator.check(
+                (1..=MAX_ATTEMPTS).contains(&attempts),
+                "max_attempts",
+                &format!("must be between 1 and {}", MAX_ATTEMPTS),
+            );
+        }
+        if let Work::Sleep { millis } = self.work {
+            validator.check(
+                millis <= MAX_SLEEP_MILLIS,
+                "millis",
+                &format!("must be at most {}", MAX_SLEEP_MILLIS),
+            );
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Succeeded => "succeeded",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_finished(self) -> bool {
+        matches!(self, Status::Succeeded | Status::Failed | Status::Cancelled)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub status: Status,
+    #[serde(flatten)]
+    pub work: Work,
+    /// How many times the job has been started.
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// What the job produced, once it has succeeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    /// Why the last attempt failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    /// Wakes the worker running the job when it is cancelled.
+    cancel: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct Jobs {
+    next_id: u64,
+    entries: BTreeMap<u64, Entry>,
+    /// Finished jobs, oldest first, so the oldest can be forgotten.
+    finished: VecDeque<u64>,
+    /// Jobs waiting for a worker, including those waiting to be retried.
+    queued: usize,
+    running: usize,
+    /// Set once the server is shutting down, after which no more jobs are taken.
+    closed: bool,
+}
+
+impl Jobs {
+    /// Cancels every job with `status`, saying why, and returns how many there were.
+    fn cancel_all(&mut self, status: Status, reason: &str) -> usize {
+        let ids: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.job.status == status)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for &id in &ids {
+            let entry = self.entries.get_mut(&id).expect("the job was just found");
+            entry.job.status = Status::Cancelled;
+            entry.job.error = Some(reason.to_string());
+            entry.cancel.notify_one();
+            self.finish(id);
+        }
+        if status == Status::Queued {
+            self.queued -= ids.len();
+        }
+        ids.len()
+    }
+
+    fn finish(&mut self, id: u64) {
+        self.finished.push_back(id);
+        if self.finished.len() > RETAINED {
+            if let Some(oldest) = self.finished.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JobQueue {
+    jobs: Mutex<Jobs>,
+    sender: mpsc::Sender<u64>,
+    capacity: usize,
+    backoff: Duration,
+    /// Woken whenever the last running job stops.
+    idle: Notify,
+}
+
+pub type Shared = Arc<JobQueue>;
+
+impl JobQueue {
+    /// Creates a queue and starts `workers` tasks to run its jobs.
+    pub fn start(workers: usize) -> Shared {
+        JobQueue::with(workers, QUEUE_CAPACITY, BACKOFF)
+    }
+
+    fn with(workers: usize, capacity: usize, backoff: Duration) -> Shared {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let queue = Arc::new(JobQueue {
+            jobs: Mutex::new(Jobs::default()),
+            sender,
+            capacity,
+            backoff,
+            idle: Notify::new(),
+        });
+
+        // The workers take turns waiting on the one receiver.
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        for _ in 0..workers {
+            let queue = queue.clone();
+            let receiver = receiver.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    let next = receiver.lock().await.recv().await;
+                    match next {
+                        Some(id) => queue.run(id).await,
+                        None => return,
+                    }
+                }
+            });
+        }
+
+        queue
+    }
+
+    /// Queues `work`, to be tried up to `max_attempts` times.
+    pub fn enqueue(&self, work: Work, max_attempts: u32) -> Result<Job, AppError> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        let full = || {
+            AppError::Unavailable(format!(
+                "the job queue is full with {} jobs; try again later",
+                self.capacity
+            ))
+        };
+        if jobs.closed {
+            return Err(AppError::Unavailable(
+                "the server is shutting down".to_string(),
+            ));
+        }
+        if jobs.queued >= self.capacity {
+            return Err(full());
+        }
+
+        let id = jobs.next_id + 1;
+        // Sent under the lock, so that no worker can look for the job before it is recorded.
+        self.sender.try_send(id).map_err(|e| match e {
+            TrySendError::Full(_) => full(),
+            TrySendError::Closed(_) => AppError::Internal("no job workers are running".to_string()),
+        })?;
+
+        let job = Job {
+            id,
+            status: Status::Queued,
+            work,
+            attempts: 0,
+            max_attempts,
+            result: None,
+            error: None,
+        };
+        jobs.next_id = id;
+        jobs.queued += 1;
+        jobs.entries.insert(
+            id,
+            Entry {
+                job: job.clone(),
+                cancel: Arc::new(Notify::new()),
+            },
+        );
+
+        Ok(job)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.entries.get(&id).map(|entry| entry.job.clone())
+    }
+
+    /// Cancels a job that has not finished yet, stopping it if it is running.
+    pub fn cancel(&self, id: u64) -> Result<Job, AppError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let entry = jobs.entries.get_mut(&id).ok_or_else(|| not_found(id))?;
+
+        if entry.job.status.is_finished() {
+            return Err(AppError::Conflict(format!(
+                "job {} has already {}",
+                id,
+                entry.job.status.as_str()
+            )));
+        }
+
+        let was_queued = entry.job.status == Status::Queued;
+        entry.job.status = Status::Cancelled;
+        // Stores a permit if the worker is not waiting yet, so it is woken either way.
+        entry.cancel.notify_one();
+        let job = entry.job.clone();
+        jobs.finish(id);
+        if was_queued {
+            jobs.queued -= 1;
+        }
+
+        Ok(job)
+    }
+
+    /// Takes no more jobs, cancels the ones that have not started, and gives the running ones
+    /// `timeout` to finish before cancelling them too. Returns whether they all finished.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        let cancelled = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.closed = true;
+            jobs.cancel_all(
+                Status::Queued,
+                "the server shut down before the job started",
+            )
+        };
+        if cancelled > 0 {
+            tracing::info!(cancelled, "cancelled jobs that had not started");
+        }
+
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                // Created before checking, so that a job stopping in between still wakes it.
+                let idle = self.idle.notified();
+                if self.jobs.lock().unwrap().running == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok();
+
+        if !drained {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.cancel_all(
+                Status::Running,
+                "the server shut down before the job finished",
+            );
+        }
+        drained
+    }
+
+    /// Makes an attempt at job `id`, unless it was cancelled while it waited in the queue.
+    async fn run(&self, id: u64) {
+        let (work, attempt, cancel) = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let Some(entry) = jobs.entries.get_mut(&id) else {
+                return;
+            };
+            if entry.job.status != Status::Queued {
+                return;
+            }
+
+            entry.job.status = Status::Running;
+            entry.job.attempts += 1;
+            let started = (
+                entry.job.work.clone(),
+                entry.job.attempts,
+                entry.cancel.clone(),
+            );
+            jobs.queued -= 1;
+            jobs.running += 1;
+            started
+        };
+
+        let outcome = tokio::select! {
+            outcome = work.run(attempt) => Some(outcome),
+            _ = cancel.notified() => None,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.running -= 1;
+        if jobs.running == 0 {
+            self.idle.notify_waiters();
+        }
+        let Some(outcome) = outcome else {
+            return;
+        };
+        let Some(entry) = jobs.entries.get_mut(&id) else {
+            return;
+        };
+        // The job may have been cancelled just as it finished.
+        if entry.job.status != Status::Running {
+            return;
+        }
+
+        match outcome {
+            Ok(result) => {
+                entry.job.status = Status::Succeeded;
+                entry.job.result = Some(result);
+                entry.job.error = None;
+            }
+            Err(failure) if failure.retry && attempt < entry.job.max_attempts => {
+                entry.job.status = Status::Queued;
+                entry.job.error = Some(failure.reason);
+                let cancel = entry.cancel.clone();
+                jobs.queued += 1;
+                self.retry(id, backoff(self.backoff, attempt), cancel);
+                return;
+            }
+            Err(failure) => {
+                entry.job.status = Status::Failed;
+                entry.job.error = Some(failure.reason);
+            }
+        }
+        jobs.finish(id);
+    }
+
+    /// Puts job `id` back on the queue after `delay`, unless it is cancelled in the meantime.
+    fn retry(&self, id: u64, delay: Duration, cancel: Arc<Notify>) {
+        let sender = self.sender.clone();
+
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = cancel.notified() => return,
+            }
+            // Waits for room rather than failing, since the job was already accepted.
+            let _ = sender.send(id).await;
+        });
+    }
+}
+
+/// How long to wait before retrying a job whose attempt number `attempt` failed.
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    base.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+fn not_found(id: u64) -> AppError {
+    AppError::NotFound(format!("there is no job with id {}", id))
+}
+
+/// All the job routes, composed into a single filter.
+pub fn routes(
+    queue: Shared,
+    auth: auth::Shared,
+    idempotency: idempotency::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    create(queue.clone(), auth.clone(), idempotency)
+        .or(get(queue.clone(), auth.clone()))
+        .or(cancel(queue, auth))
+}
+
+/// Describes the `/jobs` routes.
+pub fn openapi(document: &mut Document) {
+    let id = json!({ "type": "integer", "minimum": 1 });
+    let operand = json!({ "type": "string", "example": "4" });
+    document.schema(
+        "Work",
+        json!({
+            "oneOf": [
+                {
+                    "type": "object",
+                    "required": ["kind", "numerator", "denominator"],
+                    "properties": {
+                        "kind": { "const": "divide" },
+                        "numerator": operand,
+                        "denominator": operand,
+                    },
+                },
+                {
+                    "type": "object",
+                    "required": ["kind", "millis"],
+                    "properties": {
+                        "kind": { "const": "sleep" },
+                        "millis": { "type": "integer", "minimum": 0, "maximum": MAX_SLEEP_MILLIS },
+                    },
+                },
+                {
+                    "type": "object",
+                    "required": ["kind", "failures"],
+                    "properties": {
+                        "kind": { "const": "flaky" },
+                        "failures": { "type": "integer", "minimum": 0 },
+                    },
+                },
+            ],
+        }),
+    );
+    document.schema(
+        "NewJob",
+        json!({
+            "allOf": [schema("Work"), {
+                "type": "object",
+                "properties": {
+                    "max_attempts": { "type": "integer", "minimum": 1, "maximum": MAX_ATTEMPTS },
+                },
+            }],
+        }),
+    );
+    document.schema(
+        "Job",
+        json!({
+            "allOf": [schema("Work"), {
+                "type": "object",
+                "properties": {
+                    "id": id,
+                    "status": {
+                        "enum": ["queued", "running", "succeeded", "failed", "cancelled"],
+                    },
+                    "attempts": { "type": "integer", "minimum": 0 },
+                    "max_attempts": { "type": "integer", "minimum": 1 },
+                    "result": { "description": "What the job produced, once it has succeeded" },
+                    "error": { "type": "string", "description": "Why the last attempt failed" },
+                },
+            }],
+        }),
+    );
+    document.operation(
+        "post",
+        "/jobs",
+        idempotency::parameters(Operation::new("jobs", "Queue a job"))
+            .body("NewJob")
+            .json(202, "The queued job", "Job")
+            .problem(503, "The queue is full")
+            .role(Role::Editor),
+    );
+
+    let path = "/jobs/{id}";
+    let by_id =
+        |summary| Operation::new("jobs", summary).path("id", id.clone(), "The job's identifier");
+    document.operation(
+        "get",
+        path,
+        by_id("Follow a job")
+            .json(200, "The job", "Job")
+            .problem(404, "There is no such job")
+            .role(Role::Reader),
+    );
+    document.operation(
+        "delete",
+        path,
+        by_id("Cancel a job")
+            .json(200, "The cancelled job", "Job")
+            .problem(404, "There is no such job")
+            .problem(409, "The job has already finished")
+            .role(Role::Editor),
+    );
+}
+
+/// POST /jobs with a `NewJob` body
+fn create(
+    queue: Shared,
+    auth: auth::Shared,
+    idempotency: idempotency::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("jobs")
+        .and(warp::post())
+        .and(auth::require(auth, Role::Editor))
+        .and(idempotency::body(idempotency))
+        .and(with_queue(queue))
+        .and_then(|new, attempt: Attempt, queue| attempt.run(create_job(new, queue)))
+}
+
+/// GET /jobs/{id}
+fn get(
+    queue: Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("jobs" / u64)
+        .and(warp::get())
+        .and(auth::require(auth, Role::Reader))
+        .and(with_queue(queue))
+        .and_then(get_job)
+}
+
+/// DELETE /jobs/{id}
+fn cancel(
+    queue: Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("jobs" / u64)
+        .and(warp::delete())
+        .and(auth::require(auth, Role::Editor))
+        .and(with_queue(queue))
+        .and_then(cancel_job)
+}
+
+fn with_queue(queue: Shared) -> impl Filter<Extract = (Shared,), Error = Infallible> + Clone {
+    warp::any().map(move || queue.clone())
+}
+
+async fn create_job(new: NewJob, queue: Shared) -> Result<impl Reply, Rejection> {
+    let job = queue.enqueue(new.work, new.max_attempts.unwrap_or(DEFAULT_ATTEMPTS))?;
+    let location = format!("/jobs/{}", job.id);
+
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(warp::reply::json(&job), StatusCode::ACCEPTED),
+        "location",
+        location,
+    ))
+}
+
+async fn get_job(id: u64, queue: Shared) -> Result<impl Reply, Rejection> {
+    let job = queue.get(id).ok_or_else(|| not_found(id))?;
+
+    Ok(warp::reply::json(&job))
+}
+
+async fn cancel_job(id: u64, queue: Shared) -> Result<impl Reply, Rejection> {
+    let job = queue.cancel(id)?;
+
+    Ok(warp::reply::json(&job))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::error;
+    use crate::server::idempotency::IdempotencyStore;
+
+    /// Waits for job `id` to finish, and returns it.
+    async fn finished(queue: &Shared, id: u64) -> Job {
+        loop {
+            let job = queue.get(id).unwrap();
+            if job.status.is_finished() {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    fn sleep(millis: u64) -> Work {
+        Work::Sleep { millis }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_limit() {
+        let base = Duration::from_millis(100);
+
+        assert_eq!(backoff(base, 1), Duration::from_millis(100));
+        assert_eq!(backoff(base, 3), Duration::from_millis(400));
+        assert_eq!(backoff(base, 40), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn jobs_run_to_completion() {
+        let queue = JobQueue::with(2, 10, Duration::ZERO);
+
+        let job = queue
+            .enqueue(
+                Work::Divide {
+                    numerator: "9".to_string(),
+                    denominator: "3".to_string(),
+                },
+                1,
+            )
+            .unwrap();
+        let job = finished(&queue, job.id).await;
+
+        assert_eq!(job.status, Status::Succeeded);
+        assert_eq!(job.result.unwrap()["result"], 3.0);
+    }
+
+    #[tokio::test]
+    async fn failing_jobs_are_retried_until_they_run_out_of_attempts() {
+        let queue = JobQueue::with(1, 10, Duration::from_millis(1));
+
+        let recovers = queue.enqueue(Work::Flaky { failures: 2 }, 3).unwrap();
+        let gives_up = queue.enqueue(Work::Flaky { failures: 5 }, 3).unwrap();
+        let recovers = finished(&queue, recovers.id).await;
+        let gives_up = finished(&queue, gives_up.id).await;
+
+        assert_eq!(recovers.status, Status::Succeeded);
+        assert_eq!(recovers.attempts, 3);
+        assert_eq!(recovers.error, None);
+        assert_eq!(gives_up.status, Status::Failed);
+        assert_eq!(gives_up.attempts, 3);
+        assert_eq!(
+            gives_up.error.as_deref(),
+            Some("attempt 3 failed, as asked")
+        );
+    }
+
+    #[tokio::test]
+    async fn hopeless_jobs_are_not_retried() {
+        let queue = JobQueue::with(1, 10, Duration::ZERO);
+
+        let job = queue
+            .enqueue(
+                Work::Divide {
+                    numerator: "1".to_string(),
+                    denominator: "0".to_string(),
+                },
+                3,
+            )
+            .unwrap();
+        let job = finished(&queue, job.id).await;
+
+        assert_eq!(job.status, Status::Failed);
+        assert_eq!(job.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn running_and_queued_jobs_can_be_cancelled() {
+        let queue = JobQueue::with(1, 10, Duration::ZERO);
+
+        let running = queue.enqueue(sleep(10_000), 1).unwrap();
+        let queued = queue.enqueue(sleep(10_000), 1).unwrap();
+        let after = queue.enqueue(sleep(0), 1).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(queue.get(running.id).unwrap().status, Status::Running);
+        assert_eq!(queue.cancel(running.id).unwrap().status, Status::Cancelled);
+        assert_eq!(queue.cancel(queued.id).unwrap().status, Status::Cancelled);
+
+        // The worker moves on to the next job, skipping the cancelled one.
+        assert_eq!(finished(&queue, after.id).await.status, Status::Succeeded);
+        assert_eq!(queue.get(queued.id).unwrap().attempts, 0);
+        assert!(matches!(queue.cancel(after.id), Err(AppError::Conflict(_))));
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_turns_jobs_away() {
+        let queue = JobQueue::with(1, 1, Duration::ZERO);
+
+        queue.enqueue(sleep(10_000), 1).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        queue.enqueue(sleep(0), 1).unwrap();
+        let error = queue.enqueue(sleep(0), 1).unwrap_err();
+
+        assert!(matches!(error, AppError::Unavailable(_)));
+    }
+
+    #[tokio::test]
+    async fn jobs_waiting_to_be_retried_take_up_room() {
+        let queue = JobQueue::with(1, 1, Duration::from_secs(60));
+
+        let flaky = queue.enqueue(Work::Flaky { failures: 1 }, 2).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(queue.get(flaky.id).unwrap().status, Status::Queued);
+        assert!(matches!(
+            queue.enqueue(sleep(0), 1),
+            Err(AppError::Unavailable(_))
+        ));
+
+        queue.cancel(flaky.id).unwrap();
+        assert!(queue.enqueue(sleep(0), 1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shutting_down_cancels_unfinished_jobs() {
+        let queue = JobQueue::with(1, 10, Duration::ZERO);
+
+        let running = queue.enqueue(sleep(10_000), 1).unwrap();
+        let queued = queue.enqueue(sleep(0), 1).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert!(!queue.shutdown(Duration::from_millis(20)).await);
+
+        for id in [running.id, queued.id] {
+            let job = queue.get(id).unwrap();
+            assert_eq!(job.status, Status::Cancelled);
+            assert!(job.error.unwrap().starts_with("the server shut down"));
+        }
+        assert!(matches!(
+            queue.enqueue(sleep(0), 1),
+            Err(AppError::Unavailable(_))
+        ));
+        // The worker stops the cancelled job the next time it runs.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(queue.jobs.lock().unwrap().running, 0);
+    }
+
+    #[tokio::test]
+    async fn shutting_down_waits_for_running_jobs() {
+        let queue = JobQueue::with(1, 10, Duration::ZERO);
+
+        let running = queue.enqueue(sleep(20), 1).unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert!(queue.shutdown(Duration::from_secs(10)).await);
+        assert_eq!(queue.get(running.id).unwrap().status, Status::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn jobs_are_queued_and_followed_over_http() {
+        let queue = JobQueue::with(1, 10, Duration::ZERO);
+        let routes = routes(queue.clone(), Arc::default(), IdempotencyStore::start())
+            .recover(error::recover);
+
+        let created = warp::test::request()
+            .method("POST")
+            .path("/jobs")
+            .json(&json!({ "kind": "flaky", "failures": 0 }))
+            .reply(&routes)
+            .await;
+        let location = created.headers()["location"].to_str().unwrap().to_string();
+        finished(&queue, 1).await;
+        let fetched = warp::test::request().path(&location).reply(&routes).await;
+        let body: Value = serde_json::from_slice(fetched.body()).unwrap();
+
+        assert_eq!(created.status(), StatusCode::ACCEPTED);
+        assert_eq!(location, "/jobs/1");
+        assert_eq!(body["status"], "succeeded");
+        assert_eq!(body["kind"], "flaky");
+        assert_eq!(body["result"], json!({ "succeeded_on_attempt": 1 }));
+    }
+
+    #[tokio::test]
+    async fn invalid_jobs_are_refused() {
+        let routes = routes(
+            JobQueue::with(1, 1, Duration::ZERO),
+            Arc::default(),
+            IdempotencyStore::start(),
+        )
+        .recover(error::recover);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/jobs")
+            .json(&json!({ "kind": "sleep", "millis": 3_600_000, "max_attempts": 0 }))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
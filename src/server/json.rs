@@ -0,0 +1,244 @@
+// JSON
+//
+// Typed JSON request bodies. The `body` filter deserializes a request body into any type that
+// implements `Validate`, and rejects requests whose bodies are malformed or invalid with an
+// `AppError::Validation`, which `error::recover` renders as a structured `400 Bad Request`.
+
+use futures_util::{Stream, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use warp::hyper::body::{Buf, Bytes};
+use warp::{Filter, Rejection};
+
+use super::error::AppError;
+
+/// The largest request body accepted by any route that does not set its own limit.
+pub const MAX_BODY_BYTES: u64 = 16 * 1024;
+
+/// A problem with a single field of a request body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Checks that a deserialized request body makes sense, beyond being well-formed JSON.
+pub trait Validate {
+    fn validate(&self) -> Result<(), Vec<FieldError>>;
+}
+
+/// Accumulates field errors while validating a request body.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, ok: bool, field: &str, message: &str) -> &mut Self {
+        if !ok {
+            self.errors.push(FieldError {
+                field: field.to_string(),
+                message: message.to_string(),
+            });
+        }
+        self
+    }
+
+    pub fn not_blank(&mut self, value: &str, field: &str) -> &mut Self {
+        self.check(!value.trim().is_empty(), field, "must not be blank")
+    }
+
+    pub fn finish(&mut self) -> Result<(), Vec<FieldError>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+}
+
+/// Extracts a validated `T` from a JSON request body.
+pub fn body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    raw().and_then(|content_type: Option<String>, bytes: Bytes| async move {
+        decode(content_type.as_deref(), &bytes).map_err(warp::reject::custom)
+    })
+}
+
+/// Extracts the content type and bytes of a request body, for filters that need to look at the
+/// body before it is passed to `decode`.
+pub fn raw() -> impl Filter<Extract = (Option<String>, Bytes), Error = Rejection> + Clone {
+    raw_up_to(MAX_BODY_BYTES)
+}
+
+/// Like `raw`, for routes that accept bodies of up to `max_bytes` instead.
+pub fn raw_up_to(
+    max_bytes: u64,
+) -> impl Filter<Extract = (Option<String>, Bytes), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::body::stream())
+        .and_then(move |content_type: Option<String>, body| async move {
+            let bytes = read_up_to(body, max_bytes)
+                .await
+                .map_err(warp::reject::custom)?;
+            Ok::<_, Rejection>((content_type, bytes))
+        })
+        .untuple_one()
+}
+
+/// Reads `body` whole, failing with `413 Payload Too Large` as soon as it passes `max_bytes`.
+/// Bodies sent in chunks do not say how large they are, so the bytes are counted as they come.
+async fn read_up_to<S, B>(body: S, max_bytes: u64) -> Result<Bytes, AppError>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    tokio::pin!(body);
+
+    let mut bytes = Vec::new();
+    while let Some(mut chunk) = body.try_next().await.map_err(|e| AppError::Validation {
+        detail: format!("the request body cannot be read: {}", e),
+        fields: Vec::new(),
+    })? {
+        if (bytes.len() + chunk.remaining()) as u64 > max_bytes {
+            return Err(AppError::PayloadTooLarge(format!(
+                "the request body is larger than the {} bytes accepted",
+                max_bytes
+            )));
+        }
+        bytes.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    Ok(Bytes::from(bytes))
+}
+
+/// Decodes and validates a JSON request body sent with `content_type`.
+pub fn decode<T>(content_type: Option<&str>, bytes: &[u8]) -> Result<T, AppError>
+where
+    T: DeserializeOwned + Validate,
+{
+    if let Some(content_type) = content_type {
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+
+        if essence != "application/json" && !essence.ends_with("+json") {
+            return Err(AppError::UnsupportedMediaType(content_type.to_string()));
+        }
+    }
+
+    let value: T = serde_json::from_slice(bytes).map_err(|e| AppError::Validation {
+        detail: format!("the request body is malformed: {}", e),
+        fields: Vec::new(),
+    })?;
+
+    value.validate().map_err(|fields| AppError::Validation {
+        detail: "the request body is invalid".to_string(),
+        fields,
+    })?;
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::io;
+
+    use futures_util::stream;
+    use serde::Deserialize;
+    use warp::http::{Request, StatusCode};
+    use warp::hyper::service::Service;
+    use warp::hyper::Body;
+    use warp::Reply;
+
+    use super::*;
+    use crate::server::error;
+
+    #[derive(Debug, Deserialize)]
+    struct Greeting {
+        name: String,
+    }
+
+    impl Validate for Greeting {
+        fn validate(&self) -> Result<(), Vec<FieldError>> {
+            Validator::default().not_blank(&self.name, "name").finish()
+        }
+    }
+
+    fn api() -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+        body::<Greeting>()
+            .map(|g: Greeting| format!("Hello, {}!", g.name))
+            .recover(error::recover)
+    }
+
+    #[tokio::test]
+    async fn valid_body() {
+        let response = warp::test::request()
+            .body(r#"{ "name": "warp" }"#)
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "Hello, warp!");
+    }
+
+    #[tokio::test]
+    async fn malformed_body() {
+        let response = warp::test::request()
+            .body(r#"{ "name": "#)
+            .reply(&api())
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body["type"], "/problems/validation");
+    }
+
+    #[tokio::test]
+    async fn invalid_body_lists_fields() {
+        let response = warp::test::request()
+            .body(r#"{ "name": "  " }"#)
+            .reply(&api())
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body["detail"], "the request body is invalid");
+        assert_eq!(body["errors"][0]["field"], "name");
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_limited_too() {
+        let api = raw_up_to(8)
+            .map(|_: Option<String>, bytes: Bytes| bytes.len().to_string())
+            .recover(error::recover);
+        // A streamed body goes without a `Content-Length`, as a chunked one does.
+        let send = |body: &'static str| {
+            let chunks = body.as_bytes().chunks(4);
+            let body = Body::wrap_stream(stream::iter(
+                chunks.map(|chunk| Ok::<_, io::Error>(chunk.to_vec())),
+            ));
+            warp::service(api.clone()).call(Request::post("/").body(body).unwrap())
+        };
+
+        let small = send("tiny").await.unwrap();
+        let large = send("far too large").await.unwrap();
+
+        assert_eq!(small.status(), StatusCode::OK);
+        assert_eq!(large.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn wrong_content_type() {
+        let response = warp::test::request()
+            .header("content-type", "text/plain")
+            .body(r#"{ "name": "warp" }"#)
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
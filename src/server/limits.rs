@@ -0,0 +1,356 @@
+// LIMITS
+//
+// Baseline protections for a server that faces a room full of students. A request that says its
+// body is larger than the configured maximum is refused with `413 Payload Too Large` before the
+// body is read; routes may accept less than the maximum, but never more. And every request has
+// a deadline, by the longest path prefix it falls under, just like rate limits. A read that is
+// still running when its deadline passes is abandoned and answered with `503 Service
+// Unavailable`. A change is never abandoned, since it may already have been made, and telling
+// the client otherwise would be worse than being late: it is logged as slow and given a while
+// longer, the configured overrun. A change still running after that is left to finish, but the
+// client is answered with `503` all the same, saying that the change may or may not be made.
+//
+// A Warp filter cannot put a deadline on other filters directly, so `deadline` runs the routes
+// it wraps as a service of their own, on a task of their own, and hands their rejections back
+// out so that they are still logged and rendered like any other.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{Stream, TryStreamExt};
+use tokio::task::JoinHandle;
+use warp::http::{HeaderMap, Method, Request};
+use warp::hyper::body::{Body, Buf};
+use warp::hyper::service::Service;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+use super::error::AppError;
+
+/// How large request bodies may be, and how long requests may take.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The largest request body accepted by any route. Routes may accept less.
+    pub max_body_bytes: u64,
+    /// How long a request may take, unless its route has a timeout of its own.
+    pub timeout: Duration,
+    /// Timeouts for the routes under each path prefix, such as `/batch`.
+    pub route_timeouts: BTreeMap<String, Duration>,
+    /// How much longer than its timeout a change is waited for before its client is answered.
+    pub write_overrun: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_bytes: 1024 * 1024,
+            timeout: Duration::from_secs(30),
+            route_timeouts: BTreeMap::new(),
+            write_overrun: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Limits {
+    /// How long a request for `path` may take.
+    pub fn timeout_for(&self, path: &str) -> Duration {
+        self.route_timeouts
+            .iter()
+            .filter(|(prefix, _)| {
+                let prefix = prefix.trim_end_matches('/');
+                path == prefix || path.starts_with(&format!("{}/", prefix))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.timeout, |(_, timeout)| *timeout)
+    }
+}
+
+pub type Shared = Arc<Limits>;
+
+/// Refuses requests that say their body is larger than `max_bytes`. Routes that read a body
+/// count its bytes as they come, so bodies that do not say how large they are cannot slip past.
+pub fn body_size(max_bytes: u64) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and_then(move |length: Option<u64>| async move {
+            match length {
+                Some(length) if length > max_bytes => {
+                    Err(warp::reject::custom(AppError::PayloadTooLarge(format!(
+                        "the request body is {} bytes, but at most {} are accepted",
+                        length, max_bytes
+                    ))))
+                }
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+/// A rejection on its way out of the service that `deadline` runs the routes as.
+struct Rejected(Rejection);
+
+/// Holds every request that `routes` answer to the deadline for its path. Upgraded connections,
+/// such as WebSockets, cannot be passed through, so routes that upgrade must not be wrapped.
+pub fn deadline<F>(
+    limits: Shared,
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (Response,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    let service = warp::service(
+        routes
+            .recover(|rejection: Rejection| async move {
+                let mut response = Response::default();
+                response.extensions_mut().insert(Rejected(rejection));
+                Ok::<_, Infallible>(response)
+            })
+            .unify(),
+    );
+
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::stream())
+        .and_then(
+            move |method: Method, path: FullPath, query: String, headers: HeaderMap, body| {
+                let mut service = service.clone();
+                let limits = limits.clone();
+
+                async move {
+                    let uri = match query.as_str() {
+                        "" => path.as_str().to_string(),
+                        query => format!("{}?{}", path.as_str(), query),
+                    };
+                    let mut request = Request::builder()
+                        .method(method.clone())
+                        .uri(uri)
+                        .body(into_body(body))
+                        .map_err(|e| warp::reject::custom(AppError::Internal(e.to_string())))?;
+                    *request.headers_mut() = headers;
+
+                    // Warp cannot run routes from inside another route, so they get a task.
+                    let task = Task {
+                        handle: tokio::spawn(async move {
+                            match service.call(request).await {
+                                Ok(response) => response,
+                                Err(never) => match never {},
+                            }
+                        }),
+                        abortable: method.is_safe(),
+                    };
+                    let response = task.finish();
+                    let mut response = within(&limits, &method, path.as_str(), response).await;
+
+                    match response.extensions_mut().remove::<Rejected>() {
+                        Some(Rejected(rejection)) => Err(rejection),
+                        None => Ok(response),
+                    }
+                }
+            },
+        )
+}
+
+/// The task answering a request. A read is aborted if it is abandoned, whether at its deadline
+/// or because the client went away; a change is always left to finish.
+struct Task {
+    handle: JoinHandle<Response>,
+    abortable: bool,
+}
+
+impl Task {
+    async fn finish(mut self) -> Response {
+        match (&mut self.handle).await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::error!("a request's task failed: {}", e);
+                AppError::Internal(e.to_string()).problem().into_response()
+            }
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if self.abortable {
+            self.handle.abort();
+        }
+    }
+}
+
+/// Turns a body taken from a request back into one that can be sent with another.
+fn into_body<S, B>(body: S) -> Body
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf + Send + 'static,
+{
+    Body::wrap_stream(body.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining())))
+}
+
+/// Waits for `response`, the answer to a request for `path`, for as long as the route's timeout
+/// allows. A read that takes longer is abandoned and answered with a problem instead; a change
+/// is waited for until its overrun is up too, and then answered with a problem but left running.
+pub async fn within(
+    limits: &Limits,
+    method: &Method,
+    path: &str,
+    response: impl Future<Output = Response>,
+) -> Response {
+    let timeout = limits.timeout_for(path);
+    tokio::pin!(response);
+
+    match tokio::time::timeout(timeout, &mut response).await {
+        Ok(response) => response,
+        Err(_) if method.is_safe() => {
+            tracing::warn!(method = %method, path, "request abandoned after {:?}", timeout);
+            AppError::Unavailable(format!("the request was not answered within {:?}", timeout))
+                .problem()
+                .into_response()
+        }
+        Err(_) => {
+            tracing::warn!(method = %method, path, "change still running after {:?}", timeout);
+
+            let waited = timeout + limits.write_overrun;
+            match tokio::time::timeout(limits.write_overrun, response).await {
+                Ok(response) => response,
+                Err(_) => {
+                    tracing::error!(method = %method, path, "change unanswered after {:?}", waited);
+                    AppError::Unavailable(format!(
+                        "the change was not finished within {:?}, and may or may not be made",
+                        waited
+                    ))
+                    .problem()
+                    .into_response()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+    use warp::Reply;
+
+    use super::*;
+    use crate::server::error;
+
+    #[test]
+    fn the_longest_prefix_sets_the_timeout() {
+        let limits = Limits {
+            route_timeouts: BTreeMap::from([
+                ("/jobs".to_string(), Duration::from_secs(5)),
+                ("/jobs/".to_string(), Duration::from_secs(5)),
+                ("/batch".to_string(), Duration::from_secs(120)),
+            ]),
+            ..Limits::default()
+        };
+
+        assert_eq!(limits.timeout_for("/batch"), Duration::from_secs(120));
+        assert_eq!(limits.timeout_for("/jobs/7"), Duration::from_secs(5));
+        assert_eq!(limits.timeout_for("/batches"), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_refused() {
+        let api = body_size(8).map(|| "accepted").recover(error::recover);
+
+        let small = warp::test::request().body("tiny").reply(&api).await;
+        let large = warp::test::request()
+            .body("far too large")
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(large.body()).unwrap();
+
+        assert_eq!(small.status(), StatusCode::OK);
+        assert_eq!(large.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["type"], "/problems/payload-too-large");
+    }
+
+    #[tokio::test]
+    async fn slow_requests_are_abandoned() {
+        let limits = Limits {
+            timeout: Duration::from_millis(10),
+            ..Limits::default()
+        };
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "done".into_response()
+        };
+
+        let response = within(&limits, &Method::GET, "/slow", slow).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn slow_changes_are_answered_once_they_are_done() {
+        let limits = Limits {
+            timeout: Duration::from_millis(10),
+            ..Limits::default()
+        };
+        let slow = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            "saved".into_response()
+        };
+
+        let response = within(&limits, &Method::POST, "/slow", slow).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn changes_past_their_overrun_are_answered_without_waiting() {
+        let limits = Limits {
+            timeout: Duration::from_millis(10),
+            write_overrun: Duration::from_millis(20),
+            ..Limits::default()
+        };
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "saved".into_response()
+        };
+
+        let started = tokio::time::Instant::now();
+        let response = within(&limits, &Method::POST, "/slow", slow).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn routes_under_a_deadline_keep_their_bodies_and_rejections() {
+        let limits = Arc::new(Limits {
+            timeout: Duration::from_millis(50),
+            ..Limits::default()
+        });
+        let echo = warp::path!("echo")
+            .and(warp::query::raw())
+            .and(warp::body::bytes())
+            .map(|query: String, body: warp::hyper::body::Bytes| {
+                format!("{} {}", query, String::from_utf8_lossy(&body)).into_response()
+            });
+        let slow = warp::path!("slow").then(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "done".into_response()
+        });
+        let api = deadline(limits, echo.or(slow).unify()).recover(error::recover);
+
+        let echoed = warp::test::request()
+            .method("POST")
+            .path("/echo?case=hound")
+            .body("Baskerville")
+            .reply(&api)
+            .await;
+        let missing = warp::test::request().path("/missing").reply(&api).await;
+        let slow = warp::test::request().path("/slow").reply(&api).await;
+
+        assert_eq!(echoed.body(), "case=hound Baskerville");
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(slow.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
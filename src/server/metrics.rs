@@ -0,0 +1,235 @@
+// METRICS
+//
+// Request counters and latency histograms, kept per route and served at `/metrics` in the
+// Prometheus text exposition format. Paths are recorded against the route templates they match,
+// such as `/detectives/{id}`, so that every detective does not get a time series of its own.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::json;
+use warp::http::header::CONTENT_TYPE;
+use warp::{Filter, Rejection, Reply};
+
+use super::openapi::{Document, Operation};
+
+/// The upper bounds, in seconds, of the latency histogram buckets.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The route label for paths that match none of the known routes.
+const UNMATCHED: &str = "unmatched";
+
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+pub type Shared = Arc<Metrics>;
+
+/// The metrics collected for one method and route.
+#[derive(Debug, Default)]
+struct Series {
+    statuses: BTreeMap<u16, u64>,
+    /// Cumulative counts, one for each of `BUCKETS`.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    routes: &'static [&'static str],
+    series: Mutex<BTreeMap<(String, &'static str), Series>>,
+}
+
+impl Metrics {
+    /// Creates an empty set of metrics for the given route templates, in which `{...}` segments
+    /// match any single path segment.
+    pub fn new(routes: &'static [&'static str]) -> Shared {
+        Arc::new(Metrics {
+            routes,
+            series: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Records that a request for `path` was answered with `status` after `latency`.
+    pub fn record(&self, method: &str, path: &str, status: u16, latency: Duration) {
+        let route = self.route_for(path);
+        let seconds = latency.as_secs_f64();
+
+        let mut series = self.series.lock().unwrap();
+        let series = series.entry((method.to_string(), route)).or_default();
+
+        *series.statuses.entry(status).or_default() += 1;
+        for (count, bound) in series.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        series.sum += seconds;
+        series.count += 1;
+    }
+
+    fn route_for(&self, path: &str) -> &'static str {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        self.routes
+            .iter()
+            .copied()
+            .find(|route| {
+                let template: Vec<&str> = route.split('/').filter(|s| !s.is_empty()).collect();
+
+                template.len() == segments.len()
+                    && template
+                        .iter()
+                        .zip(&segments)
+                        .all(|(t, s)| (t.starts_with('{') && t.ends_with('}')) || t == s)
+            })
+            .unwrap_or(UNMATCHED)
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP http_requests_total Requests answered, by method, route and status.\n",
+        );
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((method, route), series) in series.iter() {
+            for (status, count) in &series.statuses {
+                let _ = writeln!(
+                    out,
+                    "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    method, route, status, count
+                );
+            }
+        }
+
+        out.push_str(concat!(
+            "# HELP http_request_duration_seconds ",
+            "Time taken to answer requests, by method and route.\n",
+        ));
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for ((method, route), series) in series.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, route);
+
+            for (bound, count) in BUCKETS.iter().zip(series.buckets) {
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, series.count
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{}}} {}",
+                labels, series.sum
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{{}}} {}",
+                labels, series.count
+            );
+        }
+
+        out
+    }
+}
+
+/// GET /metrics
+pub fn routes(metrics: Shared) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).map(move || {
+        warp::reply::with_header(metrics.render(), CONTENT_TYPE, CONTENT_TYPE_PROMETHEUS)
+    })
+}
+
+/// Describes the metrics route.
+pub fn openapi(document: &mut Document) {
+    document.operation(
+        "get",
+        "/metrics",
+        Operation::new("monitoring", "Request counts and latencies").response(
+            200,
+            "Metrics in the Prometheus text exposition format",
+            Some(("text/plain", json!({ "type": "string" }))),
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTES: &[&str] = &["/detectives", "/detectives/{id}"];
+
+    #[test]
+    fn paths_are_recorded_against_their_route() {
+        let metrics = Metrics::new(ROUTES);
+
+        metrics.record("GET", "/detectives/1", 200, Duration::from_millis(3));
+        metrics.record("GET", "/detectives/2", 404, Duration::from_millis(30));
+        metrics.record("GET", "/nowhere", 404, Duration::from_millis(1));
+
+        let text = metrics.render();
+
+        assert!(text.contains(
+            "http_requests_total{method=\"GET\",route=\"/detectives/{id}\",status=\"200\"} 1\n"
+        ));
+        assert!(text.contains(
+            "http_requests_total{method=\"GET\",route=\"/detectives/{id}\",status=\"404\"} 1\n"
+        ));
+        assert!(text.contains(
+            "http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::new(ROUTES);
+
+        metrics.record("POST", "/detectives", 201, Duration::from_millis(3));
+        metrics.record("POST", "/detectives", 201, Duration::from_millis(30));
+
+        let text = metrics.render();
+        let labels = "method=\"POST\",route=\"/detectives\"";
+
+        assert!(text.contains(&format!(
+            "http_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "http_request_duration_seconds_bucket{{{},le=\"0.05\"}} 2\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "http_request_duration_seconds_count{{{}}} 2\n",
+            labels
+        )));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_as_prometheus_text() {
+        let metrics = Metrics::new(ROUTES);
+        metrics.record("GET", "/detectives", 200, Duration::from_millis(3));
+
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&routes(metrics))
+            .await;
+
+        assert_eq!(response.headers()[CONTENT_TYPE], CONTENT_TYPE_PROMETHEUS);
+        assert!(String::from_utf8_lossy(response.body()).contains("http_requests_total"));
+    }
+}
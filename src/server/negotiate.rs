@@ -0,0 +1,293 @@
+// CONTENT NEGOTIATION
+//
+// The detective and user routes can answer in more than one format. The `Accept` header picks
+// JSON, CSV for pulling lists straight into a spreadsheet, or a plain text table for reading in
+// a terminal, and a request that accepts none of them gets `406 Not Acceptable`. Without an
+// `Accept` header, the answer is JSON. Anything that can be rendered as a row of a table says so
+// by implementing `Tabular`.
+
+use serde::Serialize;
+use serde_json::json;
+use warp::http::header::{CONTENT_TYPE, VARY};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use super::error::AppError;
+use super::openapi::Operation;
+use super::page::Page;
+
+/// The header that carries the cursor of the next page in CSV and text lists.
+pub const NEXT_CURSOR: &str = "x-next-cursor";
+
+/// A format a response can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    Text,
+}
+
+impl Format {
+    /// Every format, in the order the server prefers them when a client likes them equally.
+    const ALL: [Format; 3] = [Format::Json, Format::Csv, Format::Text];
+
+    fn media_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::Text => "text/plain",
+        }
+    }
+}
+
+/// Something that can be rendered as a row of a CSV file or text table.
+pub trait Tabular {
+    /// The name of each column.
+    const COLUMNS: &'static [&'static str];
+
+    /// The values of each column, in the same order as `COLUMNS`.
+    fn row(&self) -> Vec<String>;
+}
+
+/// Picks the format for the response from the request's `Accept` header.
+pub fn format() -> impl Filter<Extract = (Format,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept").and_then(|accept: Option<String>| async move {
+        match accept {
+            None => Ok(Format::Json),
+            Some(accept) => {
+                choose(&accept).ok_or_else(|| warp::reject::custom(AppError::NotAcceptable(accept)))
+            }
+        }
+    })
+}
+
+/// The format the client likes best, by the quality it gives the most specific media range
+/// matching each format, or `None` if it likes none of them.
+fn choose(accept: &str) -> Option<Format> {
+    let ranges: Vec<(&str, f32)> = accept.split(',').filter_map(media_range).collect();
+    let quality = |format: Format| {
+        let (kind, _) = format.media_type().split_once('/').unwrap();
+
+        [format.media_type(), &format!("{}/*", kind), "*/*"]
+            .iter()
+            .find_map(|wanted| {
+                ranges
+                    .iter()
+                    .find(|(range, _)| range.eq_ignore_ascii_case(wanted))
+                    .map(|(_, q)| *q)
+            })
+            .unwrap_or(0.0)
+    };
+
+    // `max_by` keeps the last of equals, so go through the formats from least to most preferred.
+    Format::ALL
+        .into_iter()
+        .rev()
+        .map(|format| (format, quality(format)))
+        .filter(|(_, q)| *q > 0.0)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(format, _)| format)
+}
+
+/// Parses a media range such as `text/csv;q=0.5` into the range and its quality.
+fn media_range(range: &str) -> Option<(&str, f32)> {
+    let mut parts = range.split(';').map(str::trim);
+    let media = parts.next().filter(|media| media.contains('/'))?;
+    let quality = parts
+        .filter_map(|param| param.strip_prefix("q=").or(param.strip_prefix("Q=")))
+        .find_map(|q| q.parse::<f32>().ok())
+        .unwrap_or(1.0);
+
+    Some((media, quality))
+}
+
+/// Renders a single item in `format`.
+pub fn one<T: Serialize + Tabular>(format: Format, item: &T) -> Response {
+    match format {
+        Format::Json => negotiated(warp::reply::json(item)),
+        Format::Csv | Format::Text => table(format, std::slice::from_ref(item)),
+    }
+}
+
+/// Renders a page of items in `format`. CSV and text have no room for the next page's cursor,
+/// so it goes in the `X-Next-Cursor` header instead.
+pub fn page<T: Serialize + Tabular>(format: Format, page: &Page<T>) -> Response {
+    if format == Format::Json {
+        return negotiated(warp::reply::json(page));
+    }
+
+    let mut response = table(format, &page.items);
+    if let Some(value) = page.next_cursor.as_ref().and_then(|c| c.parse().ok()) {
+        response.headers_mut().insert(NEXT_CURSOR, value);
+    }
+    response
+}
+
+fn table<T: Tabular>(format: Format, items: &[T]) -> Response {
+    let rows: Vec<Vec<String>> = items.iter().map(Tabular::row).collect();
+    let body = match format {
+        Format::Csv => csv(T::COLUMNS, &rows),
+        _ => text(T::COLUMNS, &rows),
+    };
+    let content_type = format!("{}; charset=utf-8", format.media_type());
+
+    negotiated(warp::reply::with_header(body, CONTENT_TYPE, content_type))
+}
+
+/// Marks a response as depending on the `Accept` header, so caches keep each format apart.
+fn negotiated(reply: impl Reply) -> Response {
+    warp::reply::with_header(reply, VARY, "accept").into_response()
+}
+
+fn csv(columns: &[&str], rows: &[Vec<String>]) -> String {
+    let mut out = csv_line(columns.iter().copied());
+    for row in rows {
+        out.push_str(&csv_line(row.iter().map(String::as_str)));
+    }
+    out
+}
+
+fn csv_line<'a>(values: impl Iterator<Item = &'a str>) -> String {
+    values.map(csv_field).collect::<Vec<_>>().join(",") + "\r\n"
+}
+
+/// Quotes a CSV field if it holds anything that would otherwise end it early, as RFC 4180 asks.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn text(columns: &[&str], rows: &[Vec<String>]) -> String {
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain([column.len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let line = |values: Vec<&str>| {
+        let cells: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{:width$}", value, width = width))
+            .collect();
+        cells.join("  ").trim_end().to_string() + "\n"
+    };
+
+    let dashes: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+
+    let mut out = line(columns.to_vec());
+    out.push_str(&line(dashes.iter().map(String::as_str).collect()));
+    for row in rows {
+        out.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+    out
+}
+
+/// Documents the CSV and text versions of an operation's response, and the `406` it gives when
+/// it cannot offer any format the client accepts.
+pub fn formats(operation: Operation, status: u16) -> Operation {
+    let text = json!({ "type": "string" });
+
+    operation
+        .media(status, "text/csv", text.clone())
+        .media(status, "text/plain", text)
+        .problem(406, "None of JSON, CSV or text is acceptable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Suspect {
+        name: &'static str,
+        alias: &'static str,
+    }
+
+    impl Tabular for Suspect {
+        const COLUMNS: &'static [&'static str] = &["name", "alias"];
+
+        fn row(&self) -> Vec<String> {
+            vec![self.name.to_string(), self.alias.to_string()]
+        }
+    }
+
+    fn suspects() -> Vec<Vec<String>> {
+        [
+            Suspect {
+                name: "James Moriarty",
+                alias: "the \"Napoleon of crime\"",
+            },
+            Suspect {
+                name: "Sebastian Moran",
+                alias: "Colonel, retired",
+            },
+        ]
+        .iter()
+        .map(Tabular::row)
+        .collect()
+    }
+
+    #[test]
+    fn the_best_liked_format_is_chosen() {
+        assert_eq!(choose("application/json"), Some(Format::Json));
+        assert_eq!(
+            choose("text/csv, application/json;q=0.9"),
+            Some(Format::Csv)
+        );
+        assert_eq!(choose("text/*;q=0.5, text/plain"), Some(Format::Text));
+        assert_eq!(choose("text/*"), Some(Format::Csv));
+        assert_eq!(choose("*/*"), Some(Format::Json));
+        assert_eq!(choose("*/*, application/json;q=0"), Some(Format::Csv));
+        assert_eq!(choose("image/png"), None);
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        let csv = csv(Suspect::COLUMNS, &suspects());
+
+        assert_eq!(
+            csv,
+            "name,alias\r\n\
+             James Moriarty,\"the \"\"Napoleon of crime\"\"\"\r\n\
+             Sebastian Moran,\"Colonel, retired\"\r\n"
+        );
+    }
+
+    #[test]
+    fn text_tables_line_up() {
+        let text = text(Suspect::COLUMNS, &suspects());
+
+        assert_eq!(
+            text,
+            "name             alias\n\
+             ---------------  -----------------------\n\
+             James Moriarty   the \"Napoleon of crime\"\n\
+             Sebastian Moran  Colonel, retired\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn unacceptable_requests_are_refused() {
+        let filter = format();
+
+        let csv = warp::test::request()
+            .header("accept", "text/csv")
+            .filter(&filter)
+            .await;
+        let png = warp::test::request()
+            .header("accept", "image/png")
+            .filter(&filter)
+            .await;
+
+        assert_eq!(csv.unwrap(), Format::Csv);
+        assert!(png.is_err());
+    }
+}
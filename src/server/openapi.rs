@@ -0,0 +1,336 @@
+// OPENAPI
+//
+// An OpenAPI 3 description of the server, served at `/openapi.json` along with a small viewer
+// at `/docs`. Warp cannot describe its own filters, so each module describes its routes in an
+// `openapi` function kept right next to them, and `server::openapi` gathers them all up. A test
+// checks that every route the server serves is described.
+
+use std::collections::BTreeMap;
+
+use serde_json::{json, Map, Value};
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::Role;
+
+/// A reference to the schema registered under `name`.
+pub fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// One method on one path.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    value: Map<String, Value>,
+}
+
+impl Operation {
+    pub fn new(tag: &str, summary: &str) -> Self {
+        let mut value = Map::new();
+        value.insert("tags".to_string(), json!([tag]));
+        value.insert("summary".to_string(), json!(summary));
+        value.insert("parameters".to_string(), json!([]));
+        value.insert("responses".to_string(), json!({}));
+
+        Operation { value }
+    }
+
+    fn parameter(mut self, location: &str, name: &str, schema: Value, description: &str) -> Self {
+        let parameter = json!({
+            "name": name,
+            "in": location,
+            "required": location == "path",
+            "description": description,
+            "schema": schema,
+        });
+        self.value["parameters"]
+            .as_array_mut()
+            .unwrap()
+            .push(parameter);
+        self
+    }
+
+    pub fn path(self, name: &str, schema: Value, description: &str) -> Self {
+        self.parameter("path", name, schema, description)
+    }
+
+    pub fn query(self, name: &str, schema: Value, description: &str) -> Self {
+        self.parameter("query", name, schema, description)
+    }
+
+    pub fn header(self, name: &str, schema: Value, description: &str) -> Self {
+        self.parameter("header", name, schema, description)
+    }
+
+    /// A required JSON request body, holding the schema registered under `name`.
+    pub fn body(mut self, name: &str) -> Self {
+        self.value.insert(
+            "requestBody".to_string(),
+            json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema(name) } },
+            }),
+        );
+        self.value["responses"]["400"] = problem("The body is malformed or invalid");
+        self.value["responses"]["413"] = problem("The body is too large");
+        self.value["responses"]["415"] = problem("The body is not JSON");
+        self
+    }
+
+    /// A response with the given content, or with no content when `media` is `None`.
+    pub fn response(
+        mut self,
+        status: u16,
+        description: &str,
+        media: Option<(&str, Value)>,
+    ) -> Self {
+        let mut response = json!({ "description": description });
+        if let Some((media, schema)) = media {
+            let mut content = Map::new();
+            content.insert(media.to_string(), json!({ "schema": schema }));
+            response["content"] = Value::Object(content);
+        }
+        self.value["responses"][status.to_string()] = response;
+        self
+    }
+
+    /// Another media type for a response that is already described.
+    pub fn media(mut self, status: u16, media: &str, schema: Value) -> Self {
+        self.value["responses"][status.to_string()]["content"][media] = json!({ "schema": schema });
+        self
+    }
+
+    /// A JSON response holding the schema registered under `name`.
+    pub fn json(self, status: u16, description: &str, name: &str) -> Self {
+        self.response(
+            status,
+            description,
+            Some(("application/json", schema(name))),
+        )
+    }
+
+    /// An error response with a problem+json body.
+    pub fn problem(mut self, status: u16, description: &str) -> Self {
+        self.value["responses"][status.to_string()] = problem(description);
+        self
+    }
+
+    /// Marks the operation as needing credentials with at least `role`, once any are configured.
+    pub fn role(mut self, role: Role) -> Self {
+        let summary = format!(
+            "{} (needs {})",
+            self.value["summary"].as_str().unwrap(),
+            role
+        );
+        self.value["summary"] = json!(summary);
+        self.value.insert(
+            "security".to_string(),
+            json!([{ "bearer": [] }, { "basic": [] }, {}]),
+        );
+        self.value["responses"]["401"] = problem("Credentials are missing or not valid");
+        self.value["responses"]["403"] = problem(&format!("The credentials do not grant {}", role));
+        self
+    }
+}
+
+fn problem(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/problem+json": { "schema": schema("Problem") } },
+    })
+}
+
+/// An OpenAPI document, assembled from the operations and schemas each module adds to it.
+#[derive(Debug, Default)]
+pub struct Document {
+    paths: BTreeMap<String, Map<String, Value>>,
+    schemas: BTreeMap<String, Value>,
+}
+
+impl Document {
+    pub fn new() -> Self {
+        let mut document = Document::default();
+
+        document.schema(
+            "Problem",
+            json!({
+                "type": "object",
+                "description": "An RFC 7807 problem details object",
+                "required": ["type", "title", "status", "detail"],
+                "properties": {
+                    "type": { "type": "string", "example": "/problems/not-found" },
+                    "title": { "type": "string" },
+                    "status": { "type": "integer" },
+                    "detail": { "type": "string" },
+                    "errors": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "field": { "type": "string" },
+                                "message": { "type": "string" },
+                            },
+                        },
+                    },
+                    "request_id": { "type": "string" },
+                },
+            }),
+        );
+        document
+    }
+
+    /// Describes `method` on `path`, a path template such as `/detectives/{id}`.
+    pub fn operation(&mut self, method: &str, path: &str, operation: Operation) {
+        self.paths
+            .entry(path.to_string())
+            .or_default()
+            .insert(method.to_lowercase(), Value::Object(operation.value));
+    }
+
+    pub fn schema(&mut self, name: &str, schema: Value) {
+        self.schemas.insert(name.to_string(), schema);
+    }
+
+    #[cfg(test)]
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.paths.keys().map(String::as_str)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "intro-rust graduation server",
+                "version": env!("CARGO_PKG_VERSION"),
+                "description": "Every error is an application/problem+json body. Routes that \
+                                need a role only ask for credentials once the server is \
+                                configured with some.",
+            },
+            "paths": self.paths,
+            "components": {
+                "schemas": self.schemas,
+                "securitySchemes": {
+                    "bearer": { "type": "http", "scheme": "bearer" },
+                    "basic": { "type": "http", "scheme": "basic" },
+                },
+            },
+        })
+    }
+}
+
+/// A page that lists the operations in `/openapi.json`, without needing anything from the
+/// internet, so that it works in offline classrooms too.
+const VIEWER: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>intro-rust API</title>
+<style>
+  body { font-family: sans-serif; max-width: 60em; margin: 2em auto; padding: 0 1em; }
+  details { border: 1px solid #ccc; border-radius: 4px; margin: 0.5em 0; padding: 0.5em; }
+  summary { cursor: pointer; }
+  .method { display: inline-block; width: 5em; font-weight: bold; text-transform: uppercase; }
+  pre { background: #f6f6f6; padding: 0.5em; overflow-x: auto; }
+</style>
+</head>
+<body>
+<h1 id="title">intro-rust API</h1>
+<p id="description"></p>
+<p>The raw document is at <a href="/openapi.json">/openapi.json</a>.</p>
+<div id="operations"></div>
+<script>
+fetch("/openapi.json").then(r => r.json()).then(doc => {
+  document.getElementById("title").textContent = doc.info.title + " " + doc.info.version;
+  document.getElementById("description").textContent = doc.info.description;
+  const operations = document.getElementById("operations");
+  for (const [path, methods] of Object.entries(doc.paths)) {
+    for (const [method, op] of Object.entries(methods)) {
+      const details = document.createElement("details");
+      const summary = document.createElement("summary");
+      const verb = document.createElement("span");
+      verb.className = "method";
+      verb.textContent = method;
+      summary.append(verb, path + " — " + op.summary);
+      const body = document.createElement("pre");
+      body.textContent = JSON.stringify(op, null, 2);
+      details.append(summary, body);
+      operations.append(details);
+    }
+  }
+});
+</script>
+</body>
+</html>
+"#;
+
+/// All the documentation routes, composed into a single filter.
+pub fn routes(
+    document: &Document,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let json = document.to_json();
+
+    // GET /openapi.json
+    let spec = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(move || warp::reply::json(&json));
+
+    // GET /docs
+    let viewer = warp::path!("docs")
+        .and(warp::get())
+        .map(|| warp::reply::html(VIEWER));
+
+    spec.or(viewer)
+}
+
+/// Describes the documentation routes themselves.
+pub fn openapi(document: &mut Document) {
+    document.operation(
+        "get",
+        "/openapi.json",
+        Operation::new("docs", "This document").response(
+            200,
+            "An OpenAPI 3 document",
+            Some(("application/json", json!({ "type": "object" }))),
+        ),
+    );
+    document.operation(
+        "get",
+        "/docs",
+        Operation::new("docs", "A page for browsing this document").response(
+            200,
+            "An HTML page",
+            Some(("text/html", json!({ "type": "string" }))),
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_add_security_and_problems() {
+        let mut document = Document::new();
+        document.operation(
+            "DELETE",
+            "/detectives/{id}",
+            Operation::new("detectives", "Delete a detective")
+                .path(
+                    "id",
+                    json!({ "type": "integer" }),
+                    "The detective's identifier",
+                )
+                .response(204, "Deleted", None)
+                .role(Role::Admin),
+        );
+
+        let json = document.to_json();
+        let operation = &json["paths"]["/detectives/{id}"]["delete"];
+
+        assert_eq!(operation["summary"], "Delete a detective (needs admin)");
+        assert_eq!(operation["parameters"][0]["required"], true);
+        assert_eq!(
+            operation["responses"]["403"]["content"]["application/problem+json"]["schema"],
+            schema("Problem")
+        );
+    }
+}
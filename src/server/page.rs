@@ -0,0 +1,264 @@
+// PAGINATION
+//
+// Listing routes return one page at a time. A page holds up to `limit` items in a stable order,
+// given by the `sort` field with ties broken by identifier, and ends with an opaque cursor that
+// fetches the page after it. The cursor records where the page ended, rather than how many
+// items came before it, so pages neither skip nor repeat items when others are added or removed
+// in the meantime.
+
+use std::cmp::Ordering;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::error::AppError;
+use super::json::FieldError;
+use super::openapi::Operation;
+
+/// How many items a page holds when the client does not say.
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// The most items a client may ask for in one page.
+pub const MAX_LIMIT: usize = 100;
+
+/// One page of a listing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Fetches the next page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// The value of an item's sort field.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortKey {
+    Int(i64),
+    Text(String),
+}
+
+/// Where the previous page ended, in the order it was sorted by.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    key: SortKey,
+    id: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).expect("cursors serialize"))
+    }
+
+    fn decode(s: &str) -> Option<Cursor> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(s).ok()?).ok()
+    }
+}
+
+/// What a client asked for with `?limit=&cursor=&sort=`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Request {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    /// A field name, prefixed with `-` to sort in descending order.
+    pub sort: Option<String>,
+}
+
+/// Adds the `limit`, `cursor` and `sort` query parameters to a listing's description.
+pub fn parameters(operation: Operation, sortable: &[&str]) -> Operation {
+    let sorts: Vec<String> = sortable
+        .iter()
+        .flat_map(|field| [field.to_string(), format!("-{}", field)])
+        .collect();
+
+    operation
+        .query(
+            "limit",
+            json!({ "type": "integer", "minimum": 1, "maximum": MAX_LIMIT, "default": DEFAULT_LIMIT }),
+            "How many items to return",
+        )
+        .query(
+            "cursor",
+            json!({ "type": "string" }),
+            "The `next_cursor` of the previous page",
+        )
+        .query(
+            "sort",
+            json!({ "type": "string", "enum": sorts, "default": "id" }),
+            "The field to sort by, prefixed with - for descending order",
+        )
+        .problem(400, "The query is not valid")
+}
+
+fn invalid(field: &str, message: &str) -> AppError {
+    AppError::Validation {
+        detail: "the query is invalid".to_string(),
+        fields: vec![FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }],
+    }
+}
+
+/// Sorts `items` and cuts out the page that `request` asks for. `sortable` lists the fields
+/// that may be sorted by, and `key` finds the value of one of them for an item.
+pub fn paginate<T>(
+    items: impl IntoIterator<Item = T>,
+    request: &Request,
+    sortable: &[&str],
+    key: impl Fn(&T, &str) -> SortKey,
+    id: impl Fn(&T) -> i64,
+) -> Result<Page<T>, AppError> {
+    let limit = request.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(invalid(
+            "limit",
+            &format!("must be between 1 and {}", MAX_LIMIT),
+        ));
+    }
+
+    let sort = request.sort.as_deref().unwrap_or("id");
+    let (field, descending) = match sort.strip_prefix('-') {
+        Some(field) => (field, true),
+        None => (sort, false),
+    };
+    if !sortable.contains(&field) {
+        return Err(invalid(
+            "sort",
+            &format!("must be one of {}", sortable.join(", ")),
+        ));
+    }
+
+    let after = match &request.cursor {
+        Some(cursor) => match Cursor::decode(cursor) {
+            Some(cursor) if cursor.sort == sort => Some(cursor),
+            _ => return Err(invalid("cursor", "is not a cursor for this sort order")),
+        },
+        None => None,
+    };
+
+    // Ties are broken by identifier, which is unique, so every item has exactly one place.
+    let order = |a: &(SortKey, i64), b: &(SortKey, i64)| -> Ordering {
+        let by_key = if descending {
+            b.0.cmp(&a.0)
+        } else {
+            a.0.cmp(&b.0)
+        };
+        by_key.then(a.1.cmp(&b.1))
+    };
+
+    let mut keyed: Vec<((SortKey, i64), T)> = items
+        .into_iter()
+        .map(|item| ((key(&item, field), id(&item)), item))
+        .collect();
+    keyed.sort_by(|a, b| order(&a.0, &b.0));
+
+    let mut rest = keyed.into_iter().filter(|(position, _)| match &after {
+        Some(after) => order(position, &(after.key.clone(), after.id)) == Ordering::Greater,
+        None => true,
+    });
+
+    let mut items = Vec::new();
+    let mut last = None;
+    for (position, item) in rest.by_ref().take(limit) {
+        last = Some(position);
+        items.push(item);
+    }
+
+    let next_cursor = match (last, rest.next()) {
+        (Some((key, id)), Some(_)) => Some(
+            Cursor {
+                sort: sort.to_string(),
+                key,
+                id,
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+
+    Ok(Page { items, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pairs of identifier and age.
+    fn people() -> Vec<(i64, i64)> {
+        vec![(1, 64), (2, 54), (3, 54), (4, 30), (5, 71)]
+    }
+
+    fn page(request: &Request) -> Result<Page<(i64, i64)>, AppError> {
+        paginate(
+            people(),
+            request,
+            &["id", "age"],
+            |p, field| match field {
+                "age" => SortKey::Int(p.1),
+                _ => SortKey::Int(p.0),
+            },
+            |p| p.0,
+        )
+    }
+
+    fn ids(page: &Page<(i64, i64)>) -> Vec<i64> {
+        page.items.iter().map(|p| p.0).collect()
+    }
+
+    #[test]
+    fn cursors_walk_through_every_item_once() {
+        let mut request = Request {
+            limit: Some(2),
+            sort: Some("-age".to_string()),
+            ..Default::default()
+        };
+        let mut seen = Vec::new();
+
+        loop {
+            let page = page(&request).unwrap();
+            seen.extend(ids(&page));
+            match page.next_cursor {
+                Some(cursor) => request.cursor = Some(cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(seen, vec![5, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let page = page(&Request {
+            limit: Some(5),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(ids(&page), vec![1, 2, 3, 4, 5]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn bad_requests_are_rejected() {
+        let sort = Request {
+            sort: Some("height".to_string()),
+            ..Default::default()
+        };
+        let limit = Request {
+            limit: Some(MAX_LIMIT + 1),
+            ..Default::default()
+        };
+        let cursor = Request {
+            cursor: Some("not-a-cursor".to_string()),
+            ..Default::default()
+        };
+
+        assert!(page(&sort).is_err());
+        assert!(page(&limit).is_err());
+        assert!(page(&cursor).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+// RATE LIMITING
+//
+// A token bucket per client and route. Each bucket holds up to `requests` tokens and refills
+// at `requests` per `per`; every request takes a token, and a request that finds the bucket
+// empty is turned away with `429 Too Many Requests` and told when to retry.
+//
+// Clients are told apart by their remote address or, when configured, by the API key they send,
+// and routes by the longest configured path prefix they fall under. Only keys that are among the
+// configured bearer tokens count: anyone could make up a new key for every request, so unknown
+// keys fall back to the address. `limit` is an ordinary filter, so it can be put in front of any
+// set of routes.
+//
+// Buckets are kept for at most `MAX_BUCKETS` clients and routes, and the one used least recently
+// is forgotten to make room for a new one. A forgotten bucket starts full again, which only ever
+// lets a client in, never locks one out. Buckets that have refilled completely hold nothing worth
+// keeping, and a timer started along with the limiter forgets them every `PRUNE_EVERY`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+use super::auth;
+use super::error::AppError;
+
+/// The header clients identify themselves with when limits are kept per API key.
+pub const API_KEY: &str = "x-api-key";
+
+/// The most buckets kept at once.
+const MAX_BUCKETS: usize = 10_000;
+
+/// How often buckets that have refilled completely are forgotten.
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+/// A number of requests allowed in a period of time, written like `60/minute`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Limit {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected a limit like 60/minute, not {:?}", s);
+
+        let (requests, unit) = s.split_once('/').ok_or_else(expected)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| expected())?;
+        let per = match unit.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(60 * 60),
+            _ => return Err(expected()),
+        };
+
+        if requests == 0 {
+            return Err("a limit must allow at least one request".to_string());
+        }
+        Ok(Limit { requests, per })
+    }
+}
+
+impl TryFrom<String> for Limit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} requests per {:?}", self.requests, self.per)
+    }
+}
+
+/// What clients are told apart by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyBy {
+    #[default]
+    Address,
+    /// The `X-Api-Key` header, when it holds one of the configured bearer tokens, and the
+    /// address for clients that do not send one.
+    ApiKey,
+}
+
+impl FromStr for KeyBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "address" => Ok(KeyBy::Address),
+            "api-key" => Ok(KeyBy::ApiKey),
+            _ => Err("expected address or api-key".to_string()),
+        }
+    }
+}
+
+/// Which limits apply to which routes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// The limit for routes without one of their own. Without one, they are unlimited.
+    pub default: Option<Limit>,
+    /// Limits for the routes under each path prefix, such as `/calc`.
+    pub routes: BTreeMap<String, Limit>,
+    pub key_by: KeyBy,
+}
+
+impl Policy {
+    /// Finds the limit for `path`, along with the prefix that it is kept under.
+    fn limit_for<'a>(&'a self, path: &str) -> Option<(&'a str, Limit)> {
+        let route = self
+            .routes
+            .iter()
+            .filter(|(prefix, _)| {
+                let prefix = prefix.trim_end_matches('/');
+                path == prefix || path.starts_with(&format!("{}/", prefix))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, limit)| (prefix.as_str(), *limit));
+
+        route.or_else(|| self.default.map(|limit| ("", limit)))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket was last used, as a position in `Buckets::recent`.
+    used: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let rate = limit.requests as f64 / limit.per.as_secs_f64();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate).min(limit.requests as f64);
+        self.updated = now;
+    }
+
+    /// Takes a token, or says how long it will be until there is one.
+    fn take(&mut self, limit: Limit) -> Result<(), Duration> {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let rate = limit.requests as f64 / limit.per.as_secs_f64();
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+/// A client, and the route prefix its bucket is kept under.
+type Key = (String, String);
+
+/// The buckets, and the order they were used in.
+#[derive(Debug, Default)]
+struct Buckets {
+    by_key: HashMap<Key, Bucket>,
+    /// Every use of a bucket, oldest first. A bucket used again is not moved, but pushed again,
+    /// so entries whose `used` no longer matches their bucket's are stale, and skipped.
+    recent: VecDeque<(Key, u64)>,
+    uses: u64,
+}
+
+impl Buckets {
+    /// Finds the bucket for `key`, making room for it if it is new.
+    fn get(&mut self, key: Key, limit: Limit, now: Instant) -> &mut Bucket {
+        if !self.by_key.contains_key(&key) {
+            while self.by_key.len() >= MAX_BUCKETS {
+                self.evict_least_recent();
+            }
+        }
+
+        self.uses += 1;
+        self.recent.push_back((key.clone(), self.uses));
+        if self.recent.len() > 2 * MAX_BUCKETS {
+            self.forget_stale_uses();
+        }
+
+        let bucket = self.by_key.entry(key).or_insert(Bucket {
+            tokens: limit.requests as f64,
+            updated: now,
+            used: 0,
+        });
+        bucket.used = self.uses;
+        bucket
+    }
+
+    fn evict_least_recent(&mut self) {
+        while let Some((key, used)) = self.recent.pop_front() {
+            if self
+                .by_key
+                .get(&key)
+                .is_some_and(|bucket| bucket.used == used)
+            {
+                self.by_key.remove(&key);
+                return;
+            }
+        }
+    }
+
+    fn forget_stale_uses(&mut self) {
+        let by_key = &self.by_key;
+        self.recent
+            .retain(|(key, used)| by_key.get(key).is_some_and(|bucket| bucket.used == *used));
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    policy: Policy,
+    buckets: Mutex<Buckets>,
+}
+
+pub type Shared = Arc<RateLimiter>;
+
+impl RateLimiter {
+    /// Makes a limiter, and starts the timer that prunes its buckets, which stops along with it.
+    pub fn start(policy: Policy) -> Shared {
+        let limiter = RateLimiter::new(policy);
+        let weak = Arc::downgrade(&limiter);
+
+        tokio::spawn(prune_every(weak, PRUNE_EVERY));
+        limiter
+    }
+
+    fn new(policy: Policy) -> Shared {
+        Arc::new(RateLimiter {
+            policy,
+            buckets: Mutex::new(Buckets::default()),
+        })
+    }
+
+    /// Forgets the buckets that have refilled completely.
+    fn prune(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        buckets
+            .by_key
+            .retain(|(_, route), bucket| match self.policy.limit_for(route) {
+                Some((_, limit)) => {
+                    bucket.refill(limit, now);
+                    bucket.tokens < limit.requests as f64
+                }
+                None => false,
+            });
+        buckets.forget_stale_uses();
+    }
+
+    /// Takes a token for a request from `client` to `path`.
+    fn check(&self, client: String, path: &str, now: Instant) -> Result<(), AppError> {
+        let (route, limit) = match self.policy.limit_for(path) {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get((client, route.to_string()), limit, now);
+
+        bucket.refill(limit, now);
+        bucket
+            .take(limit)
+            .map_err(|retry_after| AppError::TooManyRequests { limit, retry_after })
+    }
+}
+
+/// Prunes the limiter's buckets every `period`, for as long as the limiter is in use.
+async fn prune_every(limiter: Weak<RateLimiter>, period: Duration) {
+    let mut ticks = tokio::time::interval(period);
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        match limiter.upgrade() {
+            Some(limiter) => limiter.prune(Instant::now()),
+            None => return,
+        }
+    }
+}
+
+/// Rejects requests from clients that have used up their limit for the route they ask for.
+/// Clients are only told apart by their API key when it is one of `credentials`' tokens.
+pub fn limit(
+    limiter: Shared,
+    credentials: auth::Shared,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>(API_KEY))
+        .and(warp::path::full())
+        .and(with_limiter(limiter))
+        .and_then(
+            move |addr: Option<SocketAddr>,
+                  api_key: Option<String>,
+                  path: FullPath,
+                  limiter: Shared| {
+                let credentials = credentials.clone();
+                async move {
+                    let client = match (limiter.policy.key_by, api_key) {
+                        (KeyBy::ApiKey, Some(key)) if credentials.token_role(&key).is_some() => {
+                            format!("key:{}", key)
+                        }
+                        _ => match addr {
+                            Some(addr) => format!("addr:{}", addr.ip()),
+                            None => "addr:unknown".to_string(),
+                        },
+                    };
+
+                    limiter
+                        .check(client, path.as_str(), Instant::now())
+                        .map_err(warp::reject::custom)
+                }
+            },
+        )
+        .untuple_one()
+}
+
+fn with_limiter(limiter: Shared) -> impl Filter<Extract = (Shared,), Error = Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use super::*;
+    use crate::server::error;
+
+    fn policy(default: &str, routes: &[(&str, &str)]) -> Policy {
+        Policy {
+            default: Some(default.parse().unwrap()),
+            routes: routes
+                .iter()
+                .map(|(prefix, limit)| (prefix.to_string(), limit.parse().unwrap()))
+                .collect(),
+            key_by: KeyBy::ApiKey,
+        }
+    }
+
+    #[test]
+    fn limits_parse() {
+        assert_eq!(
+            "10/minute".parse(),
+            Ok(Limit {
+                requests: 10,
+                per: Duration::from_secs(60)
+            })
+        );
+        assert!("10".parse::<Limit>().is_err());
+        assert!("0/s".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let policy = policy("100/s", &[("/calc", "1/s"), ("/detectives/", "5/s")]);
+
+        assert_eq!(policy.limit_for("/calc").unwrap().0, "/calc");
+        assert_eq!(policy.limit_for("/detectives/1").unwrap().0, "/detectives/");
+        assert_eq!(policy.limit_for("/calculator").unwrap().0, "");
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new(policy("2/s", &[]));
+        let start = Instant::now();
+        let check =
+            |millis| limiter.check("a".to_string(), "/", start + Duration::from_millis(millis));
+
+        assert!(check(0).is_ok());
+        assert!(check(0).is_ok());
+        assert!(matches!(
+            check(100),
+            Err(AppError::TooManyRequests { retry_after, .. })
+                if retry_after == Duration::from_millis(400)
+        ));
+        assert!(check(500).is_ok());
+    }
+
+    #[test]
+    fn the_least_recently_used_bucket_makes_room() {
+        let limiter = RateLimiter::new(policy("1/s", &[]));
+        let now = Instant::now();
+        let check = |client: &str| limiter.check(client.to_string(), "/", now);
+
+        for client in 0..MAX_BUCKETS {
+            assert!(check(&client.to_string()).is_ok());
+        }
+        assert!(check("0").is_err());
+        assert!(check("newcomer").is_ok());
+
+        assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), MAX_BUCKETS);
+        assert!(
+            check("0").is_err(),
+            "client 0 was used just now, so it was kept"
+        );
+        assert!(
+            check("1").is_ok(),
+            "client 1 was used least recently, so it was forgotten"
+        );
+    }
+
+    #[test]
+    fn pruning_forgets_full_buckets() {
+        let limiter = RateLimiter::new(policy("2/s", &[]));
+        let start = Instant::now();
+
+        limiter.check("a".to_string(), "/", start).unwrap();
+        limiter.prune(start);
+        assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), 1);
+
+        limiter.prune(start + Duration::from_secs(1));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.by_key.is_empty());
+        assert!(buckets.recent.is_empty());
+    }
+
+    fn credentials(tokens: &[&str]) -> auth::Shared {
+        Arc::new(auth::Credentials {
+            tokens: tokens
+                .iter()
+                .map(|token| (token.to_string(), auth::Role::Reader))
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn exhausted_clients_are_told_when_to_retry() {
+        let limiter = RateLimiter::start(policy("1/minute", &[]));
+        let api = limit(limiter, credentials(&["alice", "bob"]))
+            .map(warp::reply)
+            .recover(error::recover);
+        let request = |key: &str| warp::test::request().header(API_KEY, key).reply(&api);
+
+        assert_eq!(request("alice").await.status(), StatusCode::OK);
+        assert_eq!(request("bob").await.status(), StatusCode::OK);
+
+        let response = request("alice").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "60");
+        assert_eq!(response.headers()["ratelimit-limit"], "1");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+    }
+
+    #[tokio::test]
+    async fn unknown_keys_share_their_address_limit() {
+        let limiter = RateLimiter::start(policy("1/minute", &[]));
+        let api = limit(limiter, credentials(&["alice"]))
+            .map(warp::reply)
+            .recover(error::recover);
+        let request = |key: &str| warp::test::request().header(API_KEY, key).reply(&api);
+
+        assert_eq!(request("mallory-1").await.status(), StatusCode::OK);
+        assert_eq!(
+            request("mallory-2").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(request("alice").await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,147 @@
+// REQUEST LOG
+//
+// A filter that wraps the whole API to log every request once it has been answered: its method,
+// path, status, latency and request ID, and to record it in the server's metrics. The request ID
+// is taken from the `X-Request-Id` header when the client sends a sensible one, and generated
+// otherwise. Either way, it is echoed back in the response headers and, for errors, in the
+// problem+json body.
+
+use std::convert::Infallible;
+use std::time::Instant;
+
+use warp::http::header::HeaderValue;
+use warp::http::Method;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use super::error;
+use super::metrics;
+
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// The longest request ID accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Extracts the request's ID, generating one if the client did not send a usable one.
+pub fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::header::optional::<String>(REQUEST_ID)
+        .or(warp::any().map(|| None))
+        .unify()
+        .map(|id: Option<String>| match id {
+            Some(id) if is_valid(&id) => id,
+            _ => uuid::Uuid::new_v4().to_string(),
+        })
+}
+
+/// Client-supplied IDs end up in logs and headers, so only short, printable ones are trusted.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Wraps `routes` so that every request is logged, tagged with its ID and recorded in `metrics`,
+/// and every rejection is rendered as a problem+json response.
+pub fn logged<F, R>(
+    routes: F,
+    metrics: metrics::Shared,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let outcome = routes
+        .map(|reply: R| Ok(reply.into_response()))
+        .recover(|rejection| async move { Ok::<_, Infallible>(Err(rejection)) })
+        .unify();
+
+    warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(request_id())
+        .and(outcome)
+        .map(
+            move |start: Instant,
+                  method: Method,
+                  path: FullPath,
+                  id: String,
+                  outcome: Result<Response, Rejection>| {
+                let mut response = match outcome {
+                    Ok(response) => response,
+                    Err(rejection) => error::render(&rejection, Some(&id)),
+                };
+
+                let status = response.status().as_u16();
+                let latency = start.elapsed();
+
+                metrics.record(method.as_str(), path.as_str(), status, latency);
+                tracing::info!(
+                    request_id = %id,
+                    method = %method,
+                    path = path.as_str(),
+                    status,
+                    latency_ms = latency.as_secs_f64() * 1000.0,
+                    "request"
+                );
+
+                if let Ok(value) = HeaderValue::from_str(&id) {
+                    response.headers_mut().insert(REQUEST_ID, value);
+                }
+                response
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use super::*;
+
+    fn api() -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
+        logged(
+            warp::path!("hello").map(|| "Hello!"),
+            metrics::Metrics::new(&["/hello"]),
+        )
+    }
+
+    #[tokio::test]
+    async fn propagates_request_id() {
+        let response = warp::test::request()
+            .path("/hello")
+            .header(REQUEST_ID, "abc-123")
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[REQUEST_ID], "abc-123");
+    }
+
+    #[tokio::test]
+    async fn generates_request_id() {
+        let response = warp::test::request()
+            .path("/hello")
+            .header(REQUEST_ID, "not a valid id")
+            .reply(&api())
+            .await;
+
+        let id = response.headers()[REQUEST_ID].to_str().unwrap();
+
+        assert!(uuid::Uuid::parse_str(id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn errors_carry_request_id() {
+        let response = warp::test::request()
+            .path("/goodbye")
+            .header(REQUEST_ID, "abc-123")
+            .reply(&api())
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[REQUEST_ID], "abc-123");
+        assert_eq!(body["request_id"], "abc-123");
+    }
+}
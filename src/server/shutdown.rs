@@ -0,0 +1,207 @@
+// SHUTDOWN
+//
+// Serving with graceful shutdown. Once the server is told to stop, it closes its listener and
+// lets the requests that are already in flight run to completion, but only for so long: a
+// request that is still running when the drain timeout expires is abandoned.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use warp::{Filter, Reply};
+
+use super::tls::Identity;
+
+/// A server that is accepting connections in the background.
+pub struct Running {
+    pub addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    server: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+pub enum BindError {
+    Http(warp::Error),
+    Https(std::io::Error),
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindError::Http(error) => error.fmt(f),
+            BindError::Https(error) => write!(f, "error binding to address: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
+
+/// Binds `routes` to `addr` and starts serving them on a background task, over HTTPS if `tls`
+/// is given and plain HTTP otherwise.
+pub fn start<F>(routes: F, addr: SocketAddr, tls: Option<&Identity>) -> Result<Running, BindError>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let (stop, stopped) = oneshot::channel::<()>();
+    let stopped = async {
+        stopped.await.ok();
+    };
+
+    let (addr, server) = match tls {
+        None => {
+            let (addr, server) = warp::serve(routes)
+                .try_bind_with_graceful_shutdown(addr, stopped)
+                .map_err(BindError::Http)?;
+            (addr, tokio::spawn(server))
+        }
+        Some(identity) => {
+            // Warp's TLS server panics if it cannot bind, so find out first whether it can.
+            drop(std::net::TcpListener::bind(addr).map_err(BindError::Https)?);
+
+            let (addr, server) = warp::serve(routes)
+                .tls()
+                .cert(&identity.cert)
+                .key(&identity.key)
+                .bind_with_graceful_shutdown(addr, stopped);
+            (addr, tokio::spawn(server))
+        }
+    };
+
+    Ok(Running { addr, stop, server })
+}
+
+impl Running {
+    /// Stops accepting connections and waits up to `drain` for in-flight requests to finish.
+    /// Returns whether every request finished in time.
+    pub async fn shutdown(self, drain: Duration) -> bool {
+        let _ = self.stop.send(());
+
+        tokio::time::timeout(drain, self.server).await.is_ok()
+    }
+}
+
+/// Completes when the process is asked to stop, with Ctrl-C (SIGINT) or SIGTERM.
+pub async fn signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("cannot listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::server::tls;
+
+    /// A route that takes `millis` milliseconds to answer.
+    fn slow(millis: u64) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("slow").then(move || async move {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            "done"
+        })
+    }
+
+    /// Sends a request to the server and returns the raw response.
+    async fn request(addr: SocketAddr) -> tokio::task::JoinHandle<String> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        })
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_are_drained() {
+        let running = start(slow(200), ([127, 0, 0, 1], 0).into(), None).unwrap();
+
+        let response = request(running.addr).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(running.shutdown(Duration::from_secs(5)).await);
+
+        let response = response.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("done"));
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_timeout() {
+        let running = start(slow(5_000), ([127, 0, 0, 1], 0).into(), None).unwrap();
+
+        let _response = request(running.addr).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(!running.shutdown(Duration::from_millis(100)).await);
+    }
+
+    #[tokio::test]
+    async fn https_is_served_with_the_given_identity() {
+        use std::sync::Arc;
+        use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerName};
+
+        let identity = tls::generate(&["localhost".to_string()]).unwrap();
+        let running = start(slow(0), ([127, 0, 0, 1], 0).into(), Some(&identity)).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut identity.cert.as_slice()).unwrap() {
+            roots.add(&rustls::Certificate(cert)).unwrap();
+        }
+        let client = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(running.addr).await.unwrap();
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(running.shutdown(Duration::from_secs(5)).await);
+    }
+}
@@ -0,0 +1,925 @@
+// USERS
+//
+// The `UserRepo` trait from `async_await::futures::async_trait_example`, grown into a complete
+// repository. Handlers only ever see an `Arc<dyn UserRepo>`, so the same routes can be served
+// from memory, from a file on disk, or from a test double. Every user has a version, sent as an
+// `ETag`, so that clients can make conditional requests (see `conditional`). Versions come from
+// one counter for the whole repository, so a deleted user's tag never matches a user created
+// later under its identifier. Every change is published as an event, for clients following
+// `/events`, while the repository is still locked, so that the events come in the same order as
+// the changes. Reading needs the `Reader` role, changing needs `Editor`, and deleting needs
+// `Admin`.
+
+mod file;
+mod memory;
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+pub use file::FileUserRepo;
+pub use memory::InMemoryUserRepo;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::{self, Role};
+use super::conditional::{self, Tags};
+use super::error::AppError;
+use super::events::{self, Change};
+use super::idempotency::{self, Attempt};
+use super::json::{self, FieldError, Validate, Validator};
+use super::negotiate::{self, Format, Tabular};
+use super::openapi::{schema, Document, Operation};
+use super::page::{self, SortKey};
+use super::wal::WalError;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub id: i32,
+    pub name: String,
+    /// Assigned by the repository, starting at 1 and going up with every change. Clients never
+    /// need to send it.
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl Tabular for User {
+    const COLUMNS: &'static [&'static str] = &["id", "version", "name"];
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.version.to_string(),
+            self.name.clone(),
+        ]
+    }
+}
+
+/// The body accepted by `PUT /users/{id}`: everything about a user except their identifier.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct UserChanges {
+    pub name: String,
+}
+
+impl Validate for User {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        Validator::default()
+            .check(self.id > 0, "id", "must be positive")
+            .not_blank(&self.name, "name")
+            .finish()
+    }
+}
+
+impl Validate for UserChanges {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        Validator::default().not_blank(&self.name, "name").finish()
+    }
+}
+
+/// The query accepted by `GET /users`, such as `?name=holmes&sort=name&limit=10`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    /// Either `id` or `name`, prefixed with `-` to sort in descending order.
+    pub sort: Option<String>,
+    /// Only users whose name contains this, ignoring case.
+    pub name: Option<String>,
+}
+
+impl UserQuery {
+    fn matches(&self, user: &User) -> bool {
+        self.name
+            .as_ref()
+            .is_none_or(|name| user.name.to_lowercase().contains(&name.to_lowercase()))
+    }
+
+    fn page(&self) -> page::Request {
+        page::Request {
+            limit: self.limit,
+            cursor: self.cursor.clone(),
+            sort: self.sort.clone(),
+        }
+    }
+}
+
+const SORTABLE: &[&str] = &["id", "name"];
+
+fn sort_key(user: &User, field: &str) -> SortKey {
+    match field {
+        "name" => SortKey::Text(user.name.clone()),
+        _ => SortKey::Int(user.id.into()),
+    }
+}
+
+#[derive(Debug)]
+pub enum RepoError {
+    /// A user with this identifier already exists.
+    Conflict(i32),
+    /// The user has changed since the version the caller expected.
+    Stale { id: i32, version: u64 },
+    /// The backing storage could not be read or written.
+    Io(std::io::Error),
+    /// The backing storage holds data that is damaged or is not a valid list of users.
+    Corrupt(String),
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoError::Conflict(id) => write!(f, "a user with id {} already exists", id),
+            RepoError::Stale { id, version } => write!(
+                f,
+                "user {} has changed; its current ETag is {}",
+                id,
+                conditional::etag(*version)
+            ),
+            RepoError::Io(e) => write!(f, "user storage is unavailable: {}", e),
+            RepoError::Corrupt(e) => write!(f, "user storage is corrupt: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+impl From<std::io::Error> for RepoError {
+    fn from(e: std::io::Error) -> Self {
+        RepoError::Io(e)
+    }
+}
+
+impl From<WalError> for RepoError {
+    fn from(e: WalError) -> Self {
+        match e {
+            WalError::Io(e) => RepoError::Io(e),
+            WalError::Corrupt { .. } => RepoError::Corrupt(e.to_string()),
+        }
+    }
+}
+
+impl From<RepoError> for AppError {
+    fn from(e: RepoError) -> Self {
+        match e {
+            RepoError::Conflict(_) => AppError::Conflict(e.to_string()),
+            RepoError::Stale { .. } => AppError::PreconditionFailed(e.to_string()),
+            RepoError::Io(_) | RepoError::Corrupt(_) => AppError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<RepoError> for Rejection {
+    fn from(e: RepoError) -> Self {
+        AppError::from(e).into()
+    }
+}
+
+/// A repository of users. Lookups and removals of unknown users succeed with `None`; only
+/// storage failures, identifier clashes and stale versions are errors. The repository assigns
+/// every user's version, ignoring the one it is given. Every change calls its `then` with the
+/// user it stored or removed, still under the lock, once the change is stored.
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, RepoError>;
+
+    /// All users, ordered by identifier.
+    async fn list(&self) -> Result<Vec<User>, RepoError>;
+
+    async fn insert(&self, user: User, then: Then<'_>) -> Result<User, RepoError>;
+
+    /// Replaces the user with the same identifier, returning `None` if there is no such user.
+    /// With an `expected` version, fails unless the user is still at that version.
+    async fn update(
+        &self,
+        user: User,
+        expected: Option<u64>,
+        then: Then<'_>,
+    ) -> Result<Option<User>, RepoError>;
+
+    /// Removes a user, returning the user that was removed. With an `expected` version, fails
+    /// unless the user is still at that version.
+    async fn delete(
+        &self,
+        id: i32,
+        expected: Option<u64>,
+        then: Then<'_>,
+    ) -> Result<Option<User>, RepoError>;
+
+    /// Hands `change` a copy of every user, by identifier, while the repository is locked, and
+    /// then stores whatever it did to them as a single change, unless it returns `None`. This is
+    /// how several changes are made at once, all or none of them. `change` must assign versions
+    /// itself, as `edit` does. What it returns is called, still under the lock, with whether the
+    /// changes were stored, so that changes to other stores can be made or undone with them.
+    async fn transaction(&self, change: Transaction<'_>) -> Result<bool, RepoError>;
+
+    /// Makes sure every change made so far is durably stored. Called before the server exits.
+    async fn flush(&self) -> Result<(), RepoError> {
+        Ok(())
+    }
+}
+
+pub type SharedRepo = Arc<dyn UserRepo>;
+
+/// What to do with a user once a change to it has been stored, such as publishing it.
+pub type Then<'a> = Box<dyn FnOnce(&User) + Send + 'a>;
+
+/// A `Then` that does nothing.
+pub fn ignore<'a>() -> Then<'a> {
+    Box::new(|_| ())
+}
+
+/// Every user, by identifier, and the last version handed out to any of them. Versions are
+/// counted across the whole repository rather than per user, so that a user who is deleted and
+/// created again under the same identifier never gets a version, and so an `ETag`, that the old
+/// one had.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Users {
+    pub by_id: BTreeMap<i32, User>,
+    pub last_version: u64,
+}
+
+impl Users {
+    pub fn new(users: Vec<User>) -> Self {
+        let mut all = Users::default();
+        for user in users {
+            all.put(user);
+        }
+        all
+    }
+
+    /// The version for the next change.
+    pub fn next_version(&mut self) -> u64 {
+        self.last_version += 1;
+        self.last_version
+    }
+
+    /// Puts `user` in, at whatever version it already has.
+    fn put(&mut self, user: User) {
+        self.last_version = self.last_version.max(user.version);
+        self.by_id.insert(user.id, user);
+    }
+}
+
+/// The changes made by `UserRepo::transaction`.
+pub type Transaction<'a> = Box<dyn FnOnce(&mut Users) -> Option<Finish<'a>> + Send + 'a>;
+
+/// What to do once a transaction's changes have been stored (`true`) or could not be (`false`).
+pub type Finish<'a> = Box<dyn FnOnce(bool) + 'a>;
+
+/// A change to a single user, checked the way the routes check it.
+#[derive(Debug, Clone)]
+pub enum Edit {
+    Insert(User),
+    Update {
+        id: i32,
+        changes: UserChanges,
+        if_match: Option<Tags>,
+    },
+    Delete {
+        id: i32,
+        if_match: Option<Tags>,
+    },
+}
+
+/// Makes `edit` to `users`, returning the user it added or changed, or the one it removed. For
+/// use inside a `UserRepo::transaction`.
+pub fn edit(users: &mut Users, edit: Edit) -> Result<User, AppError> {
+    match edit {
+        Edit::Insert(user) => {
+            if users.by_id.contains_key(&user.id) {
+                return Err(RepoError::Conflict(user.id).into());
+            }
+            let user = User {
+                version: users.next_version(),
+                ..user
+            };
+            users.by_id.insert(user.id, user.clone());
+            Ok(user)
+        }
+        Edit::Update {
+            id,
+            changes,
+            if_match,
+        } => {
+            let existing = users.by_id.get(&id).ok_or_else(|| not_found(id))?;
+            conditional::check(if_match.as_ref(), existing.version)?;
+            let user = User {
+                id,
+                name: changes.name,
+                version: users.next_version(),
+            };
+            users.by_id.insert(id, user.clone());
+            Ok(user)
+        }
+        Edit::Delete { id, if_match } => {
+            let existing = users.by_id.get(&id).ok_or_else(|| not_found(id))?;
+            conditional::check(if_match.as_ref(), existing.version)?;
+            Ok(users.by_id.remove(&id).expect("the user was just found"))
+        }
+    }
+}
+
+/// Makes `edit` through `repo`, on its own, returning the user it added or changed, or the one it
+/// removed, which is also handed to `then` under the repository's lock. This is what the routes
+/// do.
+pub async fn apply(repo: &SharedRepo, edit: Edit, then: Then<'_>) -> Result<User, AppError> {
+    match edit {
+        Edit::Insert(user) => Ok(repo.insert(user, then).await?),
+        Edit::Update {
+            id,
+            changes,
+            if_match,
+        } => {
+            let expected = expected_version(repo, id, if_match.as_ref()).await?;
+            let user = User {
+                id,
+                name: changes.name,
+                version: 0,
+            };
+            repo.update(user, expected, then)
+                .await?
+                .ok_or_else(|| not_found(id))
+        }
+        Edit::Delete { id, if_match } => {
+            let expected = expected_version(repo, id, if_match.as_ref()).await?;
+            repo.delete(id, expected, then)
+                .await?
+                .ok_or_else(|| not_found(id))
+        }
+    }
+}
+
+/// Fails unless `current` is at the `expected` version, if there is one. Shared by the
+/// repositories, which call it while holding their lock.
+fn check_version(current: &User, expected: Option<u64>) -> Result<(), RepoError> {
+    match expected {
+        Some(version) if version != current.version => Err(RepoError::Stale {
+            id: current.id,
+            version: current.version,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// The resource named in events about users.
+pub const RESOURCE: &str = "user";
+
+/// The users used throughout the course exercises.
+pub fn seed() -> Vec<User> {
+    vec![
+        User {
+            id: 1,
+            name: "Sherlock Holmes".to_string(),
+            version: 1,
+        },
+        User {
+            id: 2,
+            name: "John Watson".to_string(),
+            version: 1,
+        },
+        User {
+            id: 3,
+            name: "Mycroft Holmes".to_string(),
+            version: 1,
+        },
+    ]
+}
+
+/// All the `/users` routes, composed into a single filter.
+pub fn routes(
+    repo: SharedRepo,
+    events: events::Shared,
+    auth: auth::Shared,
+    idempotency: idempotency::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    list(repo.clone(), auth.clone())
+        .or(get(repo.clone(), auth.clone()))
+        .or(create(
+            repo.clone(),
+            events.clone(),
+            auth.clone(),
+            idempotency,
+        ))
+        .or(update(repo.clone(), events.clone(), auth.clone()))
+        .or(delete(repo, events, auth))
+}
+
+/// Describes the `/users` routes.
+pub fn openapi(document: &mut Document) {
+    let id = json!({ "type": "integer", "minimum": 1 });
+    let name = json!({ "type": "string" });
+
+    document.schema(
+        "User",
+        json!({
+            "type": "object",
+            "required": ["id", "name"],
+            "properties": {
+                "id": id,
+                "name": name,
+                "version": { "type": "integer", "minimum": 1, "readOnly": true },
+            },
+        }),
+    );
+    document.schema(
+        "UserChanges",
+        json!({ "type": "object", "required": ["name"], "properties": { "name": name } }),
+    );
+    document.schema(
+        "UserPage",
+        json!({
+            "type": "object",
+            "properties": {
+                "items": { "type": "array", "items": schema("User") },
+                "next_cursor": { "type": "string" },
+            },
+        }),
+    );
+
+    let list = page::parameters(Operation::new("users", "List users"), SORTABLE)
+        .query(
+            "name",
+            name.clone(),
+            "Only names containing this, ignoring case",
+        )
+        .json(200, "A page of users", "UserPage")
+        .role(Role::Reader);
+    document.operation("get", "/users", negotiate::formats(list, 200));
+    document.operation(
+        "post",
+        "/users",
+        idempotency::parameters(Operation::new("users", "Add a user"))
+            .body("User")
+            .json(201, "The added user", "User")
+            .problem(409, "A user with this identifier already exists")
+            .role(Role::Editor),
+    );
+
+    let path = "/users/{id}";
+    let by_id =
+        |summary| Operation::new("users", summary).path("id", id.clone(), "The user's identifier");
+    let get = conditional::read_parameters(by_id("Get a user"))
+        .json(200, "The user", "User")
+        .problem(404, "There is no such user")
+        .role(Role::Reader);
+    document.operation("get", path, negotiate::formats(get, 200));
+    document.operation(
+        "put",
+        path,
+        conditional::write_parameters(by_id("Change a user"))
+            .body("UserChanges")
+            .json(200, "The updated user", "User")
+            .problem(404, "There is no such user")
+            .role(Role::Editor),
+    );
+    document.operation(
+        "delete",
+        path,
+        conditional::write_parameters(by_id("Remove a user"))
+            .response(204, "The user was removed", None)
+            .problem(404, "There is no such user")
+            .role(Role::Admin),
+    );
+}
+
+/// GET /users with an optional `UserQuery`
+fn list(
+    repo: SharedRepo,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("users")
+        .and(warp::get())
+        .and(auth::require(auth, Role::Reader))
+        .and(warp::query())
+        .and(negotiate::format())
+        .and(with_repo(repo))
+        .and_then(list_users)
+}
+
+/// GET /users/{id}
+fn get(
+    repo: SharedRepo,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("users" / i32)
+        .and(warp::get())
+        .and(auth::require(auth, Role::Reader))
+        .and(conditional::if_none_match())
+        .and(negotiate::format())
+        .and(with_repo(repo))
+        .and_then(get_user)
+}
+
+/// POST /users with a `User` body
+fn create(
+    repo: SharedRepo,
+    events: events::Shared,
+    auth: auth::Shared,
+    idempotency: idempotency::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("users")
+        .and(warp::post())
+        .and(auth::require(auth, Role::Editor))
+        .and(idempotency::body(idempotency))
+        .and(with_repo(repo))
+        .and(events::with_bus(events))
+        .and_then(|user, attempt: Attempt, repo, events| {
+            attempt.run(create_user(user, repo, events))
+        })
+}
+
+/// PUT /users/{id} with a `UserChanges` body
+fn update(
+    repo: SharedRepo,
+    events: events::Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("users" / i32)
+        .and(warp::put())
+        .and(auth::require(auth, Role::Editor))
+        .and(json::body())
+        .and(conditional::if_match())
+        .and(with_repo(repo))
+        .and(events::with_bus(events))
+        .and_then(update_user)
+}
+
+/// DELETE /users/{id}
+fn delete(
+    repo: SharedRepo,
+    events: events::Shared,
+    auth: auth::Shared,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("users" / i32)
+        .and(warp::delete())
+        .and(auth::require(auth, Role::Admin))
+        .and(conditional::if_match())
+        .and(with_repo(repo))
+        .and(events::with_bus(events))
+        .and_then(delete_user)
+}
+
+fn with_repo(repo: SharedRepo) -> impl Filter<Extract = (SharedRepo,), Error = Infallible> + Clone {
+    warp::any().map(move || repo.clone())
+}
+
+fn not_found(id: i32) -> AppError {
+    AppError::NotFound(format!("there is no user with id {}", id))
+}
+
+async fn list_users(
+    query: UserQuery,
+    format: Format,
+    repo: SharedRepo,
+) -> Result<impl Reply, Rejection> {
+    let users = repo.list().await?;
+    let page = page::paginate(
+        users.into_iter().filter(|user| query.matches(user)),
+        &query.page(),
+        SORTABLE,
+        sort_key,
+        |user| user.id.into(),
+    )?;
+
+    Ok(negotiate::page(format, &page))
+}
+
+async fn get_user(
+    id: i32,
+    if_none_match: Option<Tags>,
+    format: Format,
+    repo: SharedRepo,
+) -> Result<impl Reply, Rejection> {
+    let user = repo.find_by_id(id).await?.ok_or_else(|| not_found(id))?;
+
+    Ok(conditional::respond(
+        if_none_match.as_ref(),
+        user.version,
+        format,
+        negotiate::one(format, &user),
+    ))
+}
+
+/// The version user `id` must still be at for a change that `if_match` allows. The repository
+/// checks it again as it makes the change, in case another request got there first.
+async fn expected_version(
+    repo: &SharedRepo,
+    id: i32,
+    if_match: Option<&Tags>,
+) -> Result<Option<u64>, AppError> {
+    let Some(if_match) = if_match else {
+        return Ok(None);
+    };
+    let current = repo.find_by_id(id).await?.ok_or_else(|| not_found(id))?;
+    conditional::check(Some(if_match), current.version)?;
+
+    Ok(Some(current.version))
+}
+
+async fn create_user(
+    user: User,
+    repo: SharedRepo,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let publish = Box::new(|user: &User| events.publish(RESOURCE, Change::Created, user));
+    let user = apply(&repo, Edit::Insert(user), publish).await?;
+    let location = format!("/users/{}", user.id);
+
+    Ok(conditional::tagged(
+        warp::reply::with_header(
+            warp::reply::with_status(warp::reply::json(&user), StatusCode::CREATED),
+            "location",
+            location,
+        ),
+        user.version,
+    ))
+}
+
+async fn update_user(
+    id: i32,
+    changes: UserChanges,
+    if_match: Option<Tags>,
+    repo: SharedRepo,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let edit = Edit::Update {
+        id,
+        changes,
+        if_match,
+    };
+    let publish = Box::new(|user: &User| events.publish(RESOURCE, Change::Updated, user));
+    let user = apply(&repo, edit, publish).await?;
+
+    Ok(conditional::tagged(warp::reply::json(&user), user.version))
+}
+
+async fn delete_user(
+    id: i32,
+    if_match: Option<Tags>,
+    repo: SharedRepo,
+    events: events::Shared,
+) -> Result<impl Reply, Rejection> {
+    let publish = Box::new(|user: &User| {
+        events.publish(RESOURCE, Change::Deleted, &json!({ "id": user.id }))
+    });
+    apply(&repo, Edit::Delete { id, if_match }, publish).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::error;
+
+    fn api() -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone {
+        routes(
+            Arc::new(InMemoryUserRepo::new(seed())),
+            events::EventBus::new(),
+            Arc::new(auth::Credentials::default()),
+            idempotency::IdempotencyStore::start(),
+        )
+        .recover(error::recover)
+    }
+
+    #[tokio::test]
+    async fn get_user_by_id() {
+        let response = warp::test::request().path("/users/2").reply(&api()).await;
+
+        let user: User = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(user.name, "John Watson");
+    }
+
+    #[tokio::test]
+    async fn list_filters_by_name() {
+        let response = warp::test::request()
+            .path("/users?name=holmes&sort=-name")
+            .reply(&api())
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(body["items"][0]["name"], "Sherlock Holmes");
+        assert_eq!(body["items"][1]["name"], "Mycroft Holmes");
+        assert_eq!(body["items"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_query_parameters_are_rejected() {
+        let response = warp::test::request()
+            .path("/users?nmae=holmes")
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn get_missing_user() {
+        let response = warp::test::request().path("/users/42").reply(&api()).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn create_with_taken_id_conflicts() {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/users")
+            .json(&serde_json::json!({ "id": 1, "name": "Irene Adler" }))
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn create_with_blank_name_is_rejected() {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/users")
+            .json(&serde_json::json!({ "id": 4, "name": "" }))
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn update_renames_user() {
+        let api = api();
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/users/3")
+            .json(&serde_json::json!({ "name": "Mycroft" }))
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request().path("/users/3").reply(&api).await;
+        let user: User = serde_json::from_slice(response.body()).unwrap();
+
+        assert_eq!(user.name, "Mycroft");
+    }
+
+    #[tokio::test]
+    async fn updates_with_a_stale_etag_fail() {
+        let api = api();
+
+        let fetched = warp::test::request().path("/users/3").reply(&api).await;
+        let etag = fetched.headers()["etag"].to_str().unwrap().to_string();
+
+        let first = warp::test::request()
+            .method("PUT")
+            .path("/users/3")
+            .header("if-match", &etag)
+            .json(&serde_json::json!({ "name": "Mycroft" }))
+            .reply(&api)
+            .await;
+        let second = warp::test::request()
+            .method("PUT")
+            .path("/users/3")
+            .header("if-match", &etag)
+            .json(&serde_json::json!({ "name": "M" }))
+            .reply(&api)
+            .await;
+
+        assert_eq!(etag, "\"1\"");
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()["etag"], "\"2\"");
+        assert_eq!(second.status(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn re_created_users_never_reuse_an_etag() {
+        let api = api();
+
+        let fetched = warp::test::request().path("/users/3").reply(&api).await;
+        let etag = fetched.headers()["etag"].to_str().unwrap().to_string();
+
+        let deleted = warp::test::request()
+            .method("DELETE")
+            .path("/users/3")
+            .reply(&api)
+            .await;
+        let created = warp::test::request()
+            .method("POST")
+            .path("/users")
+            .json(&serde_json::json!({ "id": 3, "name": "Irene Adler" }))
+            .reply(&api)
+            .await;
+        let stale = warp::test::request()
+            .method("PUT")
+            .path("/users/3")
+            .header("if-match", &etag)
+            .json(&serde_json::json!({ "name": "Irene Norton" }))
+            .reply(&api)
+            .await;
+        let revalidated = warp::test::request()
+            .path("/users/3")
+            .header("if-none-match", &etag)
+            .reply(&api)
+            .await;
+
+        assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+        assert_eq!(created.status(), StatusCode::CREATED);
+        assert_ne!(created.headers()["etag"], etag.as_str());
+        assert_eq!(stale.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(revalidated.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unchanged_users_are_not_modified() {
+        let response = warp::test::request()
+            .path("/users/1")
+            .header("if-none-match", "\"1\"")
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn each_format_has_its_own_etag() {
+        let api = api();
+
+        let json = warp::test::request()
+            .path("/users/1")
+            .header("accept", "text/csv")
+            .header("if-none-match", "\"1\"")
+            .reply(&api)
+            .await;
+        let csv = warp::test::request()
+            .path("/users/1")
+            .header("accept", "text/csv")
+            .header("if-none-match", "\"1-csv\"")
+            .reply(&api)
+            .await;
+
+        assert_eq!(json.status(), StatusCode::OK);
+        assert_eq!(json.headers()["etag"], "\"1-csv\"");
+        assert_eq!(csv.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn users_can_be_listed_as_csv() {
+        let response = warp::test::request()
+            .path("/users?limit=2")
+            .header("accept", "text/csv")
+            .reply(&api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/csv; charset=utf-8"
+        );
+        assert!(response.headers().contains_key(negotiate::NEXT_CURSOR));
+        assert_eq!(
+            response.body(),
+            "id,version,name\r\n1,1,Sherlock Holmes\r\n2,1,John Watson\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn events_come_in_the_order_of_the_changes() {
+        let events = events::EventBus::new();
+        let api = routes(
+            Arc::new(InMemoryUserRepo::new(seed())),
+            events.clone(),
+            Arc::new(auth::Credentials::default()),
+            idempotency::IdempotencyStore::start(),
+        );
+        let (_, mut published) = events.subscribe(None);
+        let renames: Vec<_> = (0..20)
+            .map(|n| {
+                let api = api.clone();
+                tokio::spawn(async move {
+                    warp::test::request()
+                        .method("PUT")
+                        .path("/users/2")
+                        .json(&serde_json::json!({ "name": format!("Watson {}", n) }))
+                        .reply(&api)
+                        .await
+                })
+            })
+            .collect();
+        for rename in renames {
+            rename.await.unwrap();
+        }
+        let versions: Vec<u64> = std::iter::from_fn(|| published.try_recv().ok())
+            .map(|event| event.data["version"].as_u64().unwrap())
+            .collect();
+
+        assert_eq!(versions, (2..22).collect::<Vec<_>>());
+    }
+}
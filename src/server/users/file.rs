@@ -0,0 +1,349 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::{check_version, RepoError, Then, Transaction, User, UserRepo, Users};
+use crate::server::wal::{Wal, WalError};
+
+/// A `UserRepo` that persists users to a write-ahead log in a data directory, `users.log`, which
+/// is compacted into `users.snapshot` from time to time (see `server::wal`). Every change is
+/// logged before it is made, so a crash loses at most the change that was being written.
+#[derive(Debug)]
+pub struct FileUserRepo {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    users: Users,
+    journal: Wal<Mutation>,
+}
+
+/// A change to the repository, as written to its log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Mutation {
+    Put {
+        user: User,
+    },
+    Remove {
+        id: i32,
+    },
+    /// Changes made together, which are logged as one record so that they are replayed all
+    /// together or not at all.
+    Batch {
+        mutations: Vec<Mutation>,
+    },
+}
+
+/// The whole repository, as written to its snapshot. The last version handed out is kept too,
+/// since the user that had it may be gone.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    last_version: u64,
+    users: Vec<User>,
+}
+
+impl FileUserRepo {
+    /// Opens the repository stored in `dir`. A directory without one starts out holding `seed`.
+    pub async fn open(dir: impl AsRef<Path>, seed: Vec<User>) -> Result<Self, RepoError> {
+        let (journal, replay) = Wal::open::<Snapshot>(dir.as_ref(), "users")?;
+        let fresh = replay.is_empty();
+
+        let users = match replay.snapshot {
+            Some(Snapshot {
+                last_version,
+                users,
+            }) => Users {
+                last_version,
+                ..Users::new(users)
+            },
+            None if fresh => Users::new(seed),
+            None => Users::default(),
+        };
+        let mut state = State { users, journal };
+        for mutation in replay.entries {
+            state.apply(mutation);
+        }
+
+        // Fresh repositories are compacted straight away, so that the seed is stored too.
+        if fresh || state.journal.should_compact() {
+            state.compact()?;
+        }
+
+        Ok(FileUserRepo {
+            state: Mutex::new(state),
+        })
+    }
+}
+
+impl State {
+    /// Logs `mutation`, and then makes it.
+    fn commit(&mut self, mutation: Mutation) -> Result<(), WalError> {
+        self.journal.append(&mutation)?;
+        self.apply(mutation);
+
+        // The change is stored already, so a compaction that fails can wait for the next one.
+        if self.journal.should_compact() {
+            if let Err(e) = self.compact() {
+                tracing::error!("cannot compact the users: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::Put { user } => {
+                self.users.put(user);
+            }
+            Mutation::Remove { id } => {
+                self.users.by_id.remove(&id);
+            }
+            Mutation::Batch { mutations } => {
+                for mutation in mutations {
+                    self.apply(mutation);
+                }
+            }
+        }
+    }
+
+    /// Durably logs every difference between the users and `changed` as a single record, and
+    /// then swaps them in.
+    fn replace(&mut self, changed: Users) -> Result<(), WalError> {
+        let mut mutations: Vec<Mutation> = self
+            .users
+            .by_id
+            .keys()
+            .filter(|id| !changed.by_id.contains_key(id))
+            .map(|&id| Mutation::Remove { id })
+            .collect();
+        mutations.extend(
+            changed
+                .by_id
+                .values()
+                .filter(|user| self.users.by_id.get(&user.id) != Some(user))
+                .map(|user| Mutation::Put { user: user.clone() }),
+        );
+
+        if !mutations.is_empty() {
+            self.journal
+                .append_durably(&Mutation::Batch { mutations })?;
+        }
+        self.users = changed;
+
+        // The changes are stored already, so a compaction that fails can wait for the next one.
+        if self.journal.should_compact() {
+            if let Err(e) = self.compact() {
+                tracing::error!("cannot compact the users: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), WalError> {
+        self.journal.compact(&Snapshot {
+            last_version: self.users.last_version,
+            users: self.users.by_id.values().cloned().collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl UserRepo for FileUserRepo {
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, RepoError> {
+        Ok(self.state.lock().await.users.by_id.get(&id).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<User>, RepoError> {
+        Ok(self
+            .state
+            .lock()
+            .await
+            .users
+            .by_id
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn insert(&self, user: User, then: Then<'_>) -> Result<User, RepoError> {
+        let mut state = self.state.lock().await;
+
+        if state.users.by_id.contains_key(&user.id) {
+            return Err(RepoError::Conflict(user.id));
+        }
+        let user = User {
+            version: state.users.next_version(),
+            ..user
+        };
+        state.commit(Mutation::Put { user: user.clone() })?;
+        then(&user);
+
+        Ok(user)
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected: Option<u64>,
+        then: Then<'_>,
+    ) -> Result<Option<User>, RepoError> {
+        let mut state = self.state.lock().await;
+
+        let Some(existing) = state.users.by_id.get(&user.id) else {
+            return Ok(None);
+        };
+        check_version(existing, expected)?;
+        let user = User {
+            version: state.users.next_version(),
+            ..user
+        };
+        state.commit(Mutation::Put { user: user.clone() })?;
+        then(&user);
+
+        Ok(Some(user))
+    }
+
+    async fn delete(
+        &self,
+        id: i32,
+        expected: Option<u64>,
+        then: Then<'_>,
+    ) -> Result<Option<User>, RepoError> {
+        let mut state = self.state.lock().await;
+
+        let removed = state.users.by_id.get(&id).cloned();
+        if let Some(existing) = &removed {
+            check_version(existing, expected)?;
+            state.commit(Mutation::Remove { id })?;
+            then(existing);
+        }
+
+        Ok(removed)
+    }
+
+    async fn transaction(&self, change: Transaction<'_>) -> Result<bool, RepoError> {
+        let mut state = self.state.lock().await;
+
+        let mut changed = state.users.clone();
+        let Some(finish) = change(&mut changed) else {
+            return Ok(false);
+        };
+
+        let stored = state.replace(changed);
+        finish(stored.is_ok());
+        stored?;
+
+        Ok(true)
+    }
+
+    async fn flush(&self) -> Result<(), RepoError> {
+        // Holding the lock waits out any write that is still in progress.
+        self.state.lock().await.journal.sync()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::users::{self, seed};
+    use crate::server::wal::COMPACT_AFTER;
+
+    #[tokio::test]
+    async fn changes_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let repo = FileUserRepo::open(dir.path(), seed()).await.unwrap();
+        repo.insert(
+            User {
+                id: 4,
+                name: "Irene Adler".to_string(),
+                version: 0,
+            },
+            users::ignore(),
+        )
+        .await
+        .unwrap();
+        repo.delete(1, None, users::ignore()).await.unwrap();
+        drop(repo);
+
+        let reopened = FileUserRepo::open(dir.path(), Vec::new()).await.unwrap();
+        let names: Vec<String> = reopened
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.name)
+            .collect();
+
+        assert_eq!(names, vec!["John Watson", "Mycroft Holmes", "Irene Adler"]);
+    }
+
+    #[tokio::test]
+    async fn transactions_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let repo = FileUserRepo::open(dir.path(), seed()).await.unwrap();
+        let declined = repo
+            .transaction(Box::new(|users| {
+                users.by_id.clear();
+                None
+            }))
+            .await
+            .unwrap();
+        let kept = repo
+            .transaction(Box::new(|users| {
+                users.by_id.remove(&3);
+                users.by_id.get_mut(&2).unwrap().version = 2;
+                Some(Box::new(|stored| assert!(stored)))
+            }))
+            .await
+            .unwrap();
+        drop(repo);
+
+        let reopened = FileUserRepo::open(dir.path(), Vec::new()).await.unwrap();
+        let users = reopened.list().await.unwrap();
+
+        assert!(!declined && kept);
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[1].version, 2);
+    }
+
+    #[tokio::test]
+    async fn changes_are_kept_when_compaction_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = FileUserRepo::open(dir.path(), seed()).await.unwrap();
+        // A directory where the new snapshot should be written makes every compaction fail.
+        std::fs::create_dir(dir.path().join("users.snapshot.tmp")).unwrap();
+
+        for n in 0..COMPACT_AFTER + 1 {
+            let renamed = User {
+                id: 2,
+                name: format!("Watson {}", n),
+                version: 0,
+            };
+            repo.update(renamed, None, users::ignore()).await.unwrap();
+        }
+        drop(repo);
+        std::fs::remove_dir(dir.path().join("users.snapshot.tmp")).unwrap();
+
+        let reopened = FileUserRepo::open(dir.path(), Vec::new()).await.unwrap();
+        let watson = reopened.find_by_id(2).await.unwrap().unwrap();
+        assert_eq!(watson.version, 1 + COMPACT_AFTER as u64 + 1);
+    }
+
+    #[tokio::test]
+    async fn corrupt_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("users.snapshot"), "not json\n").unwrap();
+
+        let result = FileUserRepo::open(dir.path(), seed()).await;
+
+        assert!(matches!(result, Err(RepoError::Corrupt(_))));
+    }
+}
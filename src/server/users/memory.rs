@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::{check_version, RepoError, Then, Transaction, User, UserRepo, Users};
+
+/// A `UserRepo` that keeps users in memory, like `TestUserRepo` in the async exercises.
+#[derive(Debug, Default)]
+pub struct InMemoryUserRepo {
+    users: RwLock<Users>,
+}
+
+impl InMemoryUserRepo {
+    pub fn new(users: Vec<User>) -> Self {
+        InMemoryUserRepo {
+            users: RwLock::new(Users::new(users)),
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepo for InMemoryUserRepo {
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, RepoError> {
+        Ok(self.users.read().await.by_id.get(&id).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<User>, RepoError> {
+        Ok(self.users.read().await.by_id.values().cloned().collect())
+    }
+
+    async fn insert(&self, user: User, then: Then<'_>) -> Result<User, RepoError> {
+        let mut users = self.users.write().await;
+
+        if users.by_id.contains_key(&user.id) {
+            return Err(RepoError::Conflict(user.id));
+        }
+        let user = User {
+            version: users.next_version(),
+            ..user
+        };
+        users.by_id.insert(user.id, user.clone());
+        then(&user);
+
+        Ok(user)
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected: Option<u64>,
+        then: Then<'_>,
+    ) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.write().await;
+
+        let Some(existing) = users.by_id.get(&user.id) else {
+            return Ok(None);
+        };
+        check_version(existing, expected)?;
+        let user = User {
+            version: users.next_version(),
+            ..user
+        };
+        users.by_id.insert(user.id, user.clone());
+        then(&user);
+
+        Ok(Some(user))
+    }
+
+    async fn delete(
+        &self,
+        id: i32,
+        expected: Option<u64>,
+        then: Then<'_>,
+    ) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.write().await;
+
+        if let Some(existing) = users.by_id.get(&id) {
+            check_version(existing, expected)?;
+        }
+        let removed = users.by_id.remove(&id);
+        if let Some(removed) = &removed {
+            then(removed);
+        }
+        Ok(removed)
+    }
+
+    async fn transaction(&self, change: Transaction<'_>) -> Result<bool, RepoError> {
+        let mut users = self.users.write().await;
+
+        let mut changed = users.clone();
+        let Some(finish) = change(&mut changed) else {
+            return Ok(false);
+        };
+        *users = changed;
+        finish(true);
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::users::{ignore, seed};
+
+    #[tokio::test]
+    async fn find_by_id() {
+        let repo = InMemoryUserRepo::new(seed());
+
+        let user = repo.find_by_id(2).await.unwrap().unwrap();
+
+        assert_eq!(user.name, "John Watson");
+    }
+
+    #[tokio::test]
+    async fn delete_removes_user() {
+        let repo = InMemoryUserRepo::new(seed());
+
+        assert!(repo.delete(1, None, ignore()).await.unwrap().is_some());
+        assert_eq!(repo.find_by_id(1).await.unwrap(), None);
+        assert_eq!(repo.list().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_versions_are_refused() {
+        let repo = InMemoryUserRepo::new(seed());
+        let renamed = User {
+            id: 2,
+            name: "Dr. Watson".to_string(),
+            version: 0,
+        };
+
+        let updated = repo
+            .update(renamed.clone(), Some(1), ignore())
+            .await
+            .unwrap()
+            .unwrap();
+        let stale = repo.update(renamed, Some(1), ignore()).await;
+
+        assert_eq!(updated.version, 2);
+        assert!(matches!(stale, Err(RepoError::Stale { id: 2, version: 2 })));
+        assert!(matches!(
+            repo.delete(2, Some(1), ignore()).await,
+            Err(RepoError::Stale { .. })
+        ));
+        assert!(repo.delete(2, Some(2), ignore()).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,516 @@
+// WRITE-AHEAD LOG
+//
+// Persistence for the server's stores. Every change is appended to `<name>.log` as one JSON line
+// before it is applied in memory, and on startup the store is rebuilt by replaying the log on top
+// of the latest snapshot in `<name>.snapshot`. Every line carries a CRC-32 of its contents, so a
+// line that was only partly written when the server crashed is detected, and cut off the end of
+// the log. Once enough changes have piled up, the store is compacted: its whole state is written
+// to a new snapshot, and the log starts over.
+//
+// Appends are small, so they are written synchronously, which lets a store keep its log behind
+// the same lock as its data. They are left to the operating system to sync to disk until `sync`
+// is called, which the server does before it exits. The price is that a write blocks the async
+// worker thread it runs on, and holds up every request waiting for the store's lock, for as
+// long as it takes; compaction, which writes the whole store, takes longest. For stores of the
+// size this server keeps, that is far less than moving the data to a blocking thread and back on
+// every change would cost.
+//
+// An append that fails part way leaves a fragment of a line behind, which the next append would
+// be joined onto, making a record that cannot be read back. So a failed append is cut back off
+// the log, and if even that fails, the log refuses any more appends rather than corrupt itself.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use warp::Rejection;
+
+use super::error::AppError;
+
+/// How many changes are logged before the store is compacted into a snapshot.
+pub const COMPACT_AFTER: usize = 1000;
+
+#[derive(Debug)]
+pub enum WalError {
+    /// The log or snapshot could not be read or written.
+    Io(io::Error),
+    /// A line other than the last is damaged, so the log cannot be trusted.
+    Corrupt {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalError::Io(e) => write!(f, "storage is unavailable: {}", e),
+            WalError::Corrupt { path, line, reason } => {
+                write!(
+                    f,
+                    "{} is corrupt at line {}: {}",
+                    path.display(),
+                    line,
+                    reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for WalError {}
+
+impl From<io::Error> for WalError {
+    fn from(e: io::Error) -> Self {
+        WalError::Io(e)
+    }
+}
+
+impl From<WalError> for AppError {
+    fn from(e: WalError) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<WalError> for Rejection {
+    fn from(e: WalError) -> Self {
+        AppError::from(e).into()
+    }
+}
+
+/// A logged change, numbered so that replay can skip the changes a snapshot already holds.
+#[derive(Serialize, Deserialize)]
+struct Record<T> {
+    seq: u64,
+    #[serde(rename = "data")]
+    value: T,
+}
+
+/// What was found on disk when the log was opened, for the store to rebuild itself from.
+#[derive(Debug)]
+pub struct Replay<S, E> {
+    /// The state as of the latest compaction, if the store was ever compacted.
+    pub snapshot: Option<S>,
+    /// The changes made since the snapshot, oldest first.
+    pub entries: Vec<E>,
+}
+
+impl<S, E> Replay<S, E> {
+    /// Whether nothing at all was stored, so the store is starting out fresh.
+    pub fn is_empty(&self) -> bool {
+        self.snapshot.is_none() && self.entries.is_empty()
+    }
+}
+
+/// An append-only log of changes of type `E`.
+#[derive(Debug)]
+pub struct Wal<E> {
+    log_path: PathBuf,
+    snapshot_path: PathBuf,
+    log: File,
+    /// How many bytes the log holds, all of them whole records.
+    len: u64,
+    /// Why the log can no longer be appended to, if a failed append could not be undone.
+    broken: Option<String>,
+    /// Where the last record starts, while `retract` can still take it back.
+    last: Option<u64>,
+    /// The sequence number of the last change logged.
+    seq: u64,
+    /// How many changes have been logged since the last snapshot.
+    pending: usize,
+    entries: PhantomData<fn(E)>,
+}
+
+impl<E: Serialize + DeserializeOwned> Wal<E> {
+    /// Opens the log called `name` in `dir`, creating it if need be, and reads back everything
+    /// stored so far. A damaged last line is truncated; damage anywhere else is an error.
+    pub fn open<S: DeserializeOwned>(
+        dir: &Path,
+        name: &str,
+    ) -> Result<(Self, Replay<S, E>), WalError> {
+        let log_path = dir.join(format!("{}.log", name));
+        let snapshot_path = dir.join(format!("{}.snapshot", name));
+
+        let snapshot = match fs::read(&snapshot_path) {
+            Ok(bytes) => {
+                let line = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+                Some(decode::<S>(line).map_err(|reason| WalError::Corrupt {
+                    path: snapshot_path.clone(),
+                    line: 1,
+                    reason,
+                })?)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot_seq = snapshot.as_ref().map_or(0, |record| record.seq);
+
+        let bytes = match fs::read(&log_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (records, valid_len) = read_lines::<E>(&bytes, &log_path)?;
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        if valid_len < bytes.len() {
+            tracing::warn!(
+                log = %log_path.display(),
+                bytes = bytes.len() - valid_len,
+                "truncating a torn write at the end of the log"
+            );
+            log.set_len(valid_len as u64)?;
+        }
+
+        let mut seq = snapshot_seq;
+        let mut entries = Vec::new();
+        for record in records {
+            // Changes the snapshot already holds were logged just before a compaction that
+            // crashed before it could empty the log.
+            if record.seq > snapshot_seq {
+                seq = record.seq;
+                entries.push(record.value);
+            }
+        }
+
+        let wal = Wal {
+            log_path,
+            snapshot_path,
+            log,
+            len: valid_len as u64,
+            broken: None,
+            last: None,
+            seq,
+            pending: entries.len(),
+            entries: PhantomData,
+        };
+        let replay = Replay {
+            snapshot: snapshot.map(|record| record.value),
+            entries,
+        };
+
+        Ok((wal, replay))
+    }
+
+    /// Appends a change to the log. It must be applied in memory only once this succeeds.
+    pub fn append(&mut self, entry: &E) -> Result<(), WalError> {
+        let line = encode(&Record {
+            seq: self.seq + 1,
+            value: entry,
+        })?;
+
+        if let Some(reason) = &self.broken {
+            return Err(WalError::Io(io::Error::other(format!(
+                "the log cannot be appended to, since {}",
+                reason
+            ))));
+        }
+
+        if let Err(e) = self.log.write_all(&line) {
+            self.truncate(self.len);
+            return Err(e.into());
+        }
+        self.last = Some(self.len);
+        self.len += line.len() as u64;
+        self.seq += 1;
+        self.pending += 1;
+
+        Ok(())
+    }
+
+    /// Appends a change and syncs it to disk, for changes that must not be made until they are
+    /// sure to survive a crash, such as one half of a batch.
+    pub fn append_durably(&mut self, entry: &E) -> Result<(), WalError> {
+        self.append(entry)?;
+
+        if let Err(e) = self.log.sync_all() {
+            self.retract();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Takes the last change back out of the log, for a change that was logged but could not be
+    /// made after all.
+    pub fn retract(&mut self) {
+        let Some(start) = self.last.take() else {
+            tracing::error!(log = %self.log_path.display(), "no change to take back");
+            return;
+        };
+
+        self.truncate(start);
+        self.len = start;
+        self.seq -= 1;
+        self.pending -= 1;
+    }
+
+    /// Cuts the log back to `len` bytes, or marks it broken if it cannot be.
+    fn truncate(&mut self, len: u64) {
+        if let Err(e) = self.log.set_len(len) {
+            tracing::error!(log = %self.log_path.display(), "cannot cut the log back: {}", e);
+            self.broken = Some(format!("it could not be cut back after a failure: {}", e));
+        }
+    }
+
+    /// Whether enough changes have been logged that the store should be compacted.
+    pub fn should_compact(&self) -> bool {
+        self.pending >= COMPACT_AFTER
+    }
+
+    /// Replaces the snapshot with `state`, which must include every change logged so far, and
+    /// empties the log.
+    pub fn compact<S: Serialize>(&mut self, state: &S) -> Result<(), WalError> {
+        let tmp = self.snapshot_path.with_extension("snapshot.tmp");
+        let line = encode(&Record {
+            seq: self.seq,
+            value: state,
+        })?;
+
+        // The snapshot is complete on disk before the log is emptied, so a crash in between
+        // leaves changes that are in both, which replay skips by their sequence numbers.
+        let mut file = File::create(&tmp)?;
+        file.write_all(&line)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.snapshot_path)?;
+
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.len = 0;
+        self.last = None;
+        self.pending = 0;
+
+        tracing::debug!(log = %self.log_path.display(), seq = self.seq, "compacted the log");
+        Ok(())
+    }
+
+    /// Makes sure every change logged so far is durably stored.
+    pub fn sync(&self) -> Result<(), WalError> {
+        self.log.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Frames `record` as a line: the CRC-32 of its JSON, in hex, a space, then the JSON itself.
+fn encode<T: Serialize>(record: &Record<T>) -> Result<Vec<u8>, WalError> {
+    let json = serde_json::to_vec(record).map_err(io::Error::from)?;
+    let mut line = format!("{:08x} ", crc32(&json)).into_bytes();
+
+    line.extend_from_slice(&json);
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn decode<T: DeserializeOwned>(line: &[u8]) -> Result<Record<T>, String> {
+    let (checksum, json) = match line.iter().position(|b| *b == b' ') {
+        Some(space) => (&line[..space], &line[space + 1..]),
+        None => return Err("the line has no checksum".to_string()),
+    };
+    let checksum = std::str::from_utf8(checksum)
+        .ok()
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| "the checksum is not valid hex".to_string())?;
+
+    if checksum != crc32(json) {
+        return Err("the checksum does not match".to_string());
+    }
+    serde_json::from_slice(json).map_err(|e| e.to_string())
+}
+
+/// Decodes every line of a log, returning the records and how many bytes they take up. Anything
+/// after that is a torn write: either a last line with no newline, or one that does not decode.
+fn read_lines<E: DeserializeOwned>(
+    bytes: &[u8],
+    path: &Path,
+) -> Result<(Vec<Record<E>>, usize), WalError> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while let Some(len) = bytes[offset..].iter().position(|b| *b == b'\n') {
+        let end = offset + len + 1;
+
+        match decode(&bytes[offset..offset + len]) {
+            Ok(record) => records.push(record),
+            Err(_) if end == bytes.len() => break,
+            Err(reason) => {
+                return Err(WalError::Corrupt {
+                    path: path.to_path_buf(),
+                    line: records.len() + 1,
+                    reason,
+                })
+            }
+        }
+        offset = end;
+    }
+
+    Ok((records, offset))
+}
+
+/// The CRC-32 used by zip and PNG (the IEEE polynomial, reflected).
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Log = Wal<String>;
+
+    fn open(dir: &Path) -> (Log, Replay<Vec<String>, String>) {
+        Wal::open(dir, "words").unwrap()
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn entries_are_replayed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut log, replay) = open(dir.path());
+        assert!(replay.is_empty());
+        log.append(&"one".to_string()).unwrap();
+        log.append(&"two".to_string()).unwrap();
+        drop(log);
+
+        let (_, replay) = open(dir.path());
+
+        assert_eq!(replay.snapshot, None);
+        assert_eq!(replay.entries, vec!["one", "two"]);
+    }
+
+    #[test]
+    fn compaction_replaces_the_log_with_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut log, _) = open(dir.path());
+        log.append(&"one".to_string()).unwrap();
+        log.compact(&vec!["one".to_string()]).unwrap();
+        log.append(&"two".to_string()).unwrap();
+        drop(log);
+
+        let (_, replay) = open(dir.path());
+
+        assert_eq!(replay.snapshot, Some(vec!["one".to_string()]));
+        assert_eq!(replay.entries, vec!["two"]);
+    }
+
+    #[test]
+    fn entries_already_in_the_snapshot_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut log, _) = open(dir.path());
+        log.append(&"one".to_string()).unwrap();
+        log.append(&"two".to_string()).unwrap();
+        let before = fs::read(dir.path().join("words.log")).unwrap();
+        log.compact(&vec!["one".to_string(), "two".to_string()])
+            .unwrap();
+        drop(log);
+
+        // As if the server crashed after writing the snapshot, but before emptying the log.
+        fs::write(dir.path().join("words.log"), before).unwrap();
+        let (_, replay) = open(dir.path());
+
+        assert_eq!(replay.snapshot.unwrap().len(), 2);
+        assert!(replay.entries.is_empty());
+    }
+
+    #[test]
+    fn torn_last_line_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("words.log");
+
+        let (mut log, _) = open(dir.path());
+        log.append(&"one".to_string()).unwrap();
+        drop(log);
+        let intact = fs::read(&path).unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"1234abcd {\"seq\":2,\"da")
+            .unwrap();
+
+        let (mut log, replay) = open(dir.path());
+
+        assert_eq!(replay.entries, vec!["one"]);
+        assert_eq!(fs::read(&path).unwrap().len(), intact);
+
+        log.append(&"two".to_string()).unwrap();
+        drop(log);
+        let (_, replay) = open(dir.path());
+
+        assert_eq!(replay.entries, vec!["one", "two"]);
+    }
+
+    #[test]
+    fn retracted_entries_are_not_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut log, _) = open(dir.path());
+        log.append(&"one".to_string()).unwrap();
+        log.append_durably(&"two".to_string()).unwrap();
+        log.retract();
+        log.append(&"three".to_string()).unwrap();
+        drop(log);
+
+        let (_, replay) = open(dir.path());
+
+        assert_eq!(replay.entries, vec!["one", "three"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_log_that_cannot_undo_a_failed_append_refuses_more() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut log, _) = open(dir.path());
+        // Writes to /dev/full fail for want of space, and it cannot be truncated either.
+        log.log = OpenOptions::new().write(true).open("/dev/full").unwrap();
+
+        assert!(log.append(&"one".to_string()).is_err());
+        assert!(log.broken.is_some());
+        assert!(log.append(&"two".to_string()).is_err());
+    }
+
+    #[test]
+    fn damage_before_the_last_line_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("words.log");
+
+        let (mut log, _) = open(dir.path());
+        log.append(&"one".to_string()).unwrap();
+        log.append(&"two".to_string()).unwrap();
+        drop(log);
+        let damaged = String::from_utf8(fs::read(&path).unwrap())
+            .unwrap()
+            .replacen("one", "uno", 1);
+        fs::write(&path, damaged).unwrap();
+
+        let result = Wal::<String>::open::<Vec<String>>(dir.path(), "words");
+
+        assert!(matches!(result, Err(WalError::Corrupt { line: 1, .. })));
+    }
+}
@@ -5,6 +5,9 @@ mod welcome {
     //
     // CHAT ROOM: https://discord.gg/VYvFKC8
     //
+    // No internet in the classroom? Run `cargo run` and connect to the graduation server's own
+    // chat room at ws://localhost:3030/chat/lobby instead (see `server::chat`).
+    //
     // Please git clone and build:
     //
     // REPOSITORY: https://github.com/jdegoes/intro-rust
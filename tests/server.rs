@@ -0,0 +1,486 @@
+// GRADUATION SERVER -- INTEGRATION TESTS
+//
+// The whole server, driven through `warp::test` exactly as `main` assembles it: every route
+// behind compression, request logging, rate limiting, body limits and authentication. Each
+// module tests its own routes in isolation; these tests check that they still behave once they
+// are put together.
+
+use std::io::Read;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::test::RequestBuilder;
+use warp::{Filter, Reply};
+
+use intro_rust::server::auth::{Credentials, Role};
+use intro_rust::server::idempotency::IdempotencyStore;
+use intro_rust::server::limits::Limits;
+use intro_rust::server::metrics::Metrics;
+use intro_rust::server::rate_limit::{KeyBy, Policy, RateLimiter};
+use intro_rust::server::users::{self, InMemoryUserRepo};
+use intro_rust::server::{self, chat, detectives, events, health, jobs};
+
+fn credentials() -> Credentials {
+    Credentials {
+        tokens: [
+            ("reader-token", Role::Reader),
+            ("editor-token", Role::Editor),
+            ("admin-token", Role::Admin),
+        ]
+        .into_iter()
+        .map(|(token, role)| (token.to_string(), role))
+        .collect(),
+        ..Default::default()
+    }
+}
+
+/// The server as `main` builds it, without persistence, under `policy`.
+fn app(
+    policy: Policy,
+) -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone {
+    let readiness = health::Readiness::default();
+    readiness.set_ready(true);
+
+    server::routes(server::State {
+        detectives: detectives::store(detectives::Registry::seeded()),
+        users: Arc::new(InMemoryUserRepo::new(users::seed())),
+        readiness,
+        metrics: Metrics::new(server::ROUTES),
+        chat: chat::Chat::new(),
+        events: events::EventBus::new(),
+        limiter: RateLimiter::start(policy),
+        limits: Arc::new(Limits::default()),
+        auth: Arc::new(credentials()),
+        jobs: jobs::JobQueue::start(2),
+        idempotency: IdempotencyStore::start(),
+    })
+}
+
+fn as_reader() -> RequestBuilder {
+    warp::test::request().header("authorization", "Bearer reader-token")
+}
+
+fn as_editor() -> RequestBuilder {
+    warp::test::request().header("authorization", "Bearer editor-token")
+}
+
+fn as_admin() -> RequestBuilder {
+    warp::test::request().header("authorization", "Bearer admin-token")
+}
+
+fn json(body: &Bytes) -> Value {
+    serde_json::from_slice(body).unwrap()
+}
+
+fn watson() -> Value {
+    json!({
+        "name": "John Watson",
+        "age": 58,
+        "address": { "street": "221B Baker Street", "city": "London" },
+    })
+}
+
+#[tokio::test]
+async fn hello_needs_no_credentials() {
+    let response = warp::test::request()
+        .path("/hello/warp")
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response.body())["message"], "Hello, warp!");
+}
+
+#[tokio::test]
+async fn detectives_can_be_created_read_and_deleted() {
+    let app = app(Policy::default());
+
+    let created = as_editor()
+        .method("POST")
+        .path("/detectives")
+        .json(&watson())
+        .reply(&app)
+        .await;
+    let location = created.headers()["location"].to_str().unwrap().to_string();
+
+    assert_eq!(created.status(), StatusCode::CREATED);
+    assert_eq!(created.headers()["etag"], "\"1\"");
+
+    let fetched = as_reader().path(&location).reply(&app).await;
+
+    assert_eq!(fetched.status(), StatusCode::OK);
+    assert_eq!(json(fetched.body())["name"], "John Watson");
+
+    let deleted = as_admin()
+        .method("DELETE")
+        .path(&location)
+        .reply(&app)
+        .await;
+    let gone = as_reader().path(&location).reply(&app).await;
+
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    assert_eq!(gone.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn users_are_listed_a_page_at_a_time() {
+    let app = app(Policy::default());
+
+    let first = as_reader().path("/users?limit=2").reply(&app).await;
+    let first = json(first.body());
+    let second = as_reader()
+        .path(&format!(
+            "/users?limit=2&cursor={}",
+            first["next_cursor"].as_str().unwrap()
+        ))
+        .reply(&app)
+        .await;
+    let second = json(second.body());
+
+    assert_eq!(first["items"].as_array().unwrap().len(), 2);
+    assert_eq!(second["items"][0]["name"], "Mycroft Holmes");
+    assert_eq!(second["next_cursor"], Value::Null);
+}
+
+#[tokio::test]
+async fn detectives_can_be_pulled_into_a_spreadsheet() {
+    let response = as_reader()
+        .path("/detectives?sort=-age")
+        .header("accept", "text/csv, application/json;q=0.5")
+        .reply(&app(Policy::default()))
+        .await;
+    let body = String::from_utf8_lossy(response.body());
+    let lines: Vec<&str> = body.lines().collect();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(lines[0], "id,version,name,age,street,city");
+    assert_eq!(lines[1], "1,1,Sherlock Holmes,64,221B Baker Street,London");
+    assert_eq!(lines.len(), 3);
+}
+
+#[tokio::test]
+async fn retried_posts_create_only_once() {
+    let app = app(Policy::default());
+    let create = || {
+        as_editor()
+            .method("POST")
+            .path("/detectives")
+            .header("idempotency-key", "register-watson")
+            .json(&watson())
+    };
+
+    let first = create().reply(&app).await;
+    let retry = create().reply(&app).await;
+    let reused = as_editor()
+        .method("POST")
+        .path("/users")
+        .header("idempotency-key", "register-watson")
+        .json(&json!({ "id": 7, "name": "John Watson" }))
+        .reply(&app)
+        .await;
+
+    assert_eq!(first.status(), StatusCode::CREATED);
+    assert_eq!(retry.status(), StatusCode::CREATED);
+    assert_eq!(retry.headers()["idempotent-replayed"], "true");
+    assert_eq!(retry.headers()["location"], first.headers()["location"]);
+    assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn imports_can_be_sent_as_one_batch() {
+    let app = app(Policy::default());
+    // Far more than a single detective or user may take up.
+    let operations: Vec<Value> = (10..610)
+        .map(|id| {
+            json!({
+                "op": "create",
+                "resource": "users",
+                "body": { "id": id, "name": format!("Baker Street Irregular {}", id) },
+            })
+        })
+        .collect();
+
+    let response = as_editor()
+        .method("POST")
+        .path("/batch")
+        .json(&json!({ "atomic": true, "operations": operations }))
+        .reply(&app)
+        .await;
+    let imported = as_reader().path("/users/609").reply(&app).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        json(response.body())["results"].as_array().unwrap().len(),
+        600
+    );
+    assert_eq!(imported.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn invalid_bodies_list_every_field_at_fault() {
+    let response = as_editor()
+        .method("POST")
+        .path("/detectives")
+        .json(&json!({
+            "name": "",
+            "age": 200,
+            "address": { "street": "221B Baker Street", "city": "London" },
+        }))
+        .reply(&app(Policy::default()))
+        .await;
+    let body = json(response.body());
+    let fields: Vec<&str> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["field"].as_str().unwrap())
+        .collect();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    assert_eq!(fields, vec!["name", "age"]);
+}
+
+#[tokio::test]
+async fn bodies_that_are_not_json_are_unsupported() {
+    let response = as_editor()
+        .method("POST")
+        .path("/users")
+        .header("content-type", "text/plain")
+        .body("Irene Adler")
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn missing_credentials_are_challenged() {
+    let response = warp::test::request()
+        .path("/detectives")
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers().contains_key("www-authenticate"));
+}
+
+#[tokio::test]
+async fn roles_limit_what_credentials_can_do() {
+    let app = app(Policy::default());
+
+    let created = as_reader()
+        .method("POST")
+        .path("/detectives")
+        .json(&watson())
+        .reply(&app)
+        .await;
+    let deleted = as_editor()
+        .method("DELETE")
+        .path("/users/1")
+        .reply(&app)
+        .await;
+
+    assert_eq!(created.status(), StatusCode::FORBIDDEN);
+    assert_eq!(deleted.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn stale_changes_are_refused() {
+    let app = app(Policy::default());
+    let rename = |name: &str| {
+        as_editor()
+            .method("PUT")
+            .path("/users/2")
+            .header("if-match", "\"1\"")
+            .json(&json!({ "name": name }))
+    };
+
+    let first = rename("Dr. Watson").reply(&app).await;
+    let second = rename("Johnny").reply(&app).await;
+
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(second.status(), StatusCode::PRECONDITION_FAILED);
+}
+
+#[tokio::test]
+async fn jobs_are_worked_off_in_the_background() {
+    let app = app(Policy::default());
+
+    let created = as_editor()
+        .method("POST")
+        .path("/jobs")
+        .json(&json!({ "kind": "divide", "numerator": "42", "denominator": "6" }))
+        .reply(&app)
+        .await;
+    let location = created.headers()["location"].to_str().unwrap().to_string();
+
+    assert_eq!(created.status(), StatusCode::ACCEPTED);
+
+    let job = loop {
+        let job = json(as_reader().path(&location).reply(&app).await.body());
+        if job["status"] == "succeeded" {
+            break job;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    };
+
+    assert_eq!(job["result"]["result"], 7.0);
+}
+
+#[tokio::test]
+async fn taken_ids_conflict() {
+    let response = as_editor()
+        .method("POST")
+        .path("/users")
+        .json(&json!({ "id": 1, "name": "Irene Adler" }))
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    assert_eq!(json(response.body())["type"], "/problems/conflict");
+}
+
+#[tokio::test]
+async fn calculation_errors_are_problems() {
+    let response = warp::test::request()
+        .path("/divide/1/0")
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(json(response.body())["type"], "/problems/division-by-zero");
+}
+
+#[tokio::test]
+async fn unknown_routes_are_not_found_and_carry_the_request_id() {
+    let response = warp::test::request()
+        .path("/villains")
+        .header("x-request-id", "case-of-the-missing-route")
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        response.headers()["x-request-id"],
+        "case-of-the-missing-route"
+    );
+    assert_eq!(
+        json(response.body())["request_id"],
+        "case-of-the-missing-route"
+    );
+}
+
+#[tokio::test]
+async fn wrong_methods_are_not_allowed() {
+    let response = as_admin()
+        .method("PATCH")
+        .path("/users/1")
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn clients_over_their_limit_are_told_to_wait() {
+    let app = app(Policy {
+        default: Some("2/minute".parse().unwrap()),
+        key_by: KeyBy::ApiKey,
+        ..Default::default()
+    });
+    let hello = |key: &str| {
+        warp::test::request()
+            .path("/hello/warp")
+            .header("x-api-key", key)
+            .reply(&app)
+    };
+
+    assert_eq!(hello("reader-token").await.status(), StatusCode::OK);
+    assert_eq!(hello("reader-token").await.status(), StatusCode::OK);
+
+    let limited = hello("reader-token").await;
+
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(limited.headers().contains_key("retry-after"));
+    assert_eq!(hello("editor-token").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn monitoring_is_never_rate_limited_or_authenticated() {
+    let app = app(Policy {
+        default: Some("1/minute".parse().unwrap()),
+        ..Default::default()
+    });
+
+    for _ in 0..3 {
+        let ready = warp::test::request().path("/readyz").reply(&app).await;
+
+        assert_eq!(ready.status(), StatusCode::OK);
+    }
+
+    let metrics = warp::test::request().path("/metrics").reply(&app).await;
+    let metrics = String::from_utf8_lossy(metrics.body());
+
+    assert!(metrics.contains(r#"route="/readyz""#));
+}
+
+#[tokio::test]
+async fn every_route_is_documented() {
+    let response = warp::test::request()
+        .path("/openapi.json")
+        .reply(&app(Policy::default()))
+        .await;
+    let document = json(response.body());
+
+    assert_eq!(response.status(), StatusCode::OK);
+    for route in server::ROUTES {
+        assert!(
+            document["paths"].get(*route).is_some(),
+            "{} is not documented",
+            route
+        );
+    }
+}
+
+#[tokio::test]
+async fn large_responses_are_compressed() {
+    let response = warp::test::request()
+        .path("/openapi.json")
+        .header("accept-encoding", "gzip")
+        .reply(&app(Policy::default()))
+        .await;
+    let mut document = Vec::new();
+    flate2::read::GzDecoder::new(&response.body()[..])
+        .read_to_end(&mut document)
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert!(json(&Bytes::from(document))["paths"].is_object());
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_refused_before_they_are_read() {
+    let response = warp::test::request()
+        .method("POST")
+        .path("/hello/warp")
+        .body(vec![b'x'; Limits::default().max_body_bytes as usize + 1])
+        .reply(&app(Policy::default()))
+        .await;
+
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+}